@@ -1,21 +1,153 @@
 use std::path::Path;
 use std::process::Command;
+use std::time::SystemTime;
 
 const FRONTEND_DIR: &str = "../ui";
+// Inputs of the trunk build, relative to FRONTEND_DIR
+const FRONTEND_SOURCES: [&str; 6] = [
+    "src",
+    "index.html",
+    "hecate.css",
+    "vendor",
+    "Cargo.toml",
+    "Trunk.toml",
+];
+// Set to use `ui/dist` as it is, e.g. when it was built separately
+const SKIP_ENV: &str = "HECATE_SKIP_UI_BUILD";
 
 fn main() {
-    println!("cargo:rerun-if-changed={}/src", FRONTEND_DIR);
-    println!("cargo:rerun-if-changed={}/index.html", FRONTEND_DIR);
-    build_frontend(FRONTEND_DIR);
+    for source in FRONTEND_SOURCES {
+        println!("cargo:rerun-if-changed={FRONTEND_DIR}/{source}");
+    }
+    println!("cargo:rerun-if-env-changed={SKIP_ENV}");
+
+    let frontend = Path::new(FRONTEND_DIR);
+    if std::env::var_os(SKIP_ENV).is_some() {
+        // Embed a separately built UI again whenever it changes
+        println!("cargo:rerun-if-changed={FRONTEND_DIR}/dist");
+        println!("cargo:warning={SKIP_ENV} is set, using ui/dist as it is");
+    } else if up_to_date(frontend) {
+        // Nothing to do, ui/dist is newer than all of its sources
+    } else if let Err(e) = build_frontend(frontend) {
+        // Without the UI embedded the server can still serve a separately
+        // built one from `ui_dir`
+        if cfg!(feature = "embed-ui") {
+            panic!("Failed to build the frontend: {e}");
+        }
+        println!(
+            "cargo:warning=Failed to build the frontend: {}",
+            first_line(&e)
+        );
+    }
+
+    #[cfg(feature = "embed-ui")]
+    {
+        let dist = frontend.join("dist");
+        if !dist.join("index.html").is_file() {
+            panic!("{} has no index.html to embed", dist.display());
+        }
+        let out_dir = std::env::var("OUT_DIR").expect("OUT_DIR not set");
+        let target = Path::new(&out_dir).join("ui");
+        _ = std::fs::remove_dir_all(&target);
+        embed::prepare(&dist, &target).expect("Failed to prepare embedded frontend");
+    }
+}
+
+// Latest modification time of a file or of anything below a directory
+fn newest(path: &Path) -> Option<SystemTime> {
+    let metadata = std::fs::metadata(path).ok()?;
+    if !metadata.is_dir() {
+        return metadata.modified().ok();
+    }
+    std::fs::read_dir(path)
+        .ok()?
+        .filter_map(|entry| newest(&entry.ok()?.path()))
+        .max()
 }
 
-fn build_frontend<P>(source: P)
-where
-    P: AsRef<Path>,
-{
-    Command::new("trunk")
+fn up_to_date(frontend: &Path) -> bool {
+    let Some(built) = newest(&frontend.join("dist/index.html")) else {
+        return false;
+    };
+    FRONTEND_SOURCES
+        .iter()
+        .filter_map(|source| newest(&frontend.join(source)))
+        .all(|modified| modified <= built)
+}
+
+fn build_frontend(source: &Path) -> Result<(), String> {
+    let output = Command::new("trunk")
         .args(["build", "--release"])
-        .current_dir(source.as_ref())
-        .status()
-        .expect("Failed to build frontend");
+        .current_dir(source)
+        .output()
+        .map_err(|e| format!("could not run trunk: {e}"))?;
+    if output.status.success() {
+        return Ok(());
+    }
+    Err(format!(
+        "trunk {}\n{}{}",
+        output.status,
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    ))
+}
+
+fn first_line(text: &str) -> &str {
+    text.lines().next().unwrap_or_default()
+}
+
+#[cfg(feature = "embed-ui")]
+mod embed {
+    use std::fs;
+    use std::io::{self, Write};
+    use std::path::Path;
+
+    // Files not worth compressing are served as they are
+    const COMPRESSIBLE: [&str; 7] = ["css", "html", "js", "json", "svg", "txt", "wasm"];
+    const MIN_SIZE: usize = 1024;
+
+    // Copy the built frontend to `target`, adding a gzip and a brotli
+    // compressed variant next to each compressible file
+    pub fn prepare(source: &Path, target: &Path) -> io::Result<()> {
+        fs::create_dir_all(target)?;
+        for entry in fs::read_dir(source)? {
+            let entry = entry?;
+            let path = entry.path();
+            let destination = target.join(entry.file_name());
+            if entry.file_type()?.is_dir() {
+                prepare(&path, &destination)?;
+                continue;
+            }
+
+            let data = fs::read(&path)?;
+            fs::write(&destination, &data)?;
+            let compressible = path
+                .extension()
+                .and_then(|e| e.to_str())
+                .is_some_and(|e| COMPRESSIBLE.contains(&e));
+            if !compressible || data.len() < MIN_SIZE {
+                continue;
+            }
+
+            let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+            gzip.write_all(&data)?;
+            fs::write(with_suffix(&destination, "gz"), gzip.finish()?)?;
+
+            let mut brotli = Vec::new();
+            {
+                // Finishes the stream when dropped
+                let mut writer = brotli::CompressorWriter::new(&mut brotli, 4096, 11, 22);
+                writer.write_all(&data)?;
+            }
+            fs::write(with_suffix(&destination, "br"), brotli)?;
+        }
+        Ok(())
+    }
+
+    fn with_suffix(path: &Path, suffix: &str) -> std::path::PathBuf {
+        let mut name = path.as_os_str().to_os_string();
+        name.push(".");
+        name.push(suffix);
+        name.into()
+    }
 }
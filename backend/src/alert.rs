@@ -0,0 +1,552 @@
+use crate::hello::{self, ChannelSpec};
+
+use lettre::{
+    message::Mailbox, transport::smtp::authentication::Credentials, AsyncSmtpTransport,
+    AsyncTransport, Message, Tokio1Executor,
+};
+use polars::prelude::*;
+use rocket::{
+    futures::lock::Mutex,
+    serde::{Deserialize, Serialize},
+    tokio::{fs::OpenOptions, io::AsyncWriteExt},
+};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::path::PathBuf;
+use std::time::{Duration as StdDuration, Instant};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", tag = "type", rename_all = "snake_case")]
+pub enum Condition {
+    // A single channel, e.g. "acc_x", exceeds or falls below a limit
+    Above { channel: String, limit: f64 },
+    Below { channel: String, limit: f64 },
+    // The vector magnitude of the x/y/z channels of a sensor declared by the
+    // device, e.g. "acc", exceeds a limit
+    MagnitudeAbove { sensor: String, limit: f64 },
+    // No data has been received from the device for the given time
+    Inactive { seconds: u64 },
+}
+
+fn enabled() -> bool {
+    true
+}
+
+fn cooldown() -> u64 {
+    60
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Rule {
+    #[serde(default)]
+    pub id: u64,
+    pub name: String,
+    // Restrict the rule to a single device, otherwise it applies to all
+    #[serde(default)]
+    pub device: Option<String>,
+    pub condition: Condition,
+    #[serde(default = "enabled")]
+    pub enabled: bool,
+    // Minimum time between two alerts of this rule for the same device
+    #[serde(default = "cooldown")]
+    pub cooldown_secs: u64,
+}
+
+impl Rule {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.name.trim().is_empty() {
+            return Err(String::from("rule name must not be empty"));
+        }
+        let (name, limit) = match &self.condition {
+            Condition::Above { channel, limit } | Condition::Below { channel, limit } => {
+                (channel, *limit)
+            }
+            Condition::MagnitudeAbove { sensor, limit } => (sensor, *limit),
+            Condition::Inactive { seconds: 0 } => {
+                return Err(String::from("inactive seconds must be positive"))
+            }
+            Condition::Inactive { .. } => return Ok(()),
+        };
+        if name.is_empty() {
+            return Err(String::from("rule must name a channel or sensor"));
+        }
+        if !limit.is_finite() {
+            return Err(format!("invalid limit {limit}"));
+        }
+        Ok(())
+    }
+
+    fn applies_to(&self, device: &str) -> bool {
+        self.enabled && self.device.as_deref().map_or(true, |d| d == device)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Alert {
+    pub rule_id: u64,
+    pub rule_name: String,
+    pub device: String,
+    pub message: String,
+    pub value: Option<f64>,
+    pub time: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde", tag = "type", rename_all = "snake_case")]
+pub enum Sink {
+    Webhook {
+        url: String,
+    },
+    Log {
+        path: PathBuf,
+    },
+    Smtp {
+        server: String,
+        #[serde(default)]
+        port: Option<u16>,
+        from: String,
+        to: Vec<String>,
+        #[serde(default)]
+        username: Option<String>,
+        #[serde(default)]
+        password: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct AlertConfig {
+    pub history_size: usize,
+    pub sinks: Vec<Sink>,
+}
+
+impl Default for AlertConfig {
+    fn default() -> Self {
+        Self {
+            history_size: 1000,
+            sinks: Vec::new(),
+        }
+    }
+}
+
+struct AlertEngine {
+    next_id: u64,
+    rules: BTreeMap<u64, Rule>,
+    history: VecDeque<Alert>,
+    last_fired: HashMap<(u64, String), Instant>,
+    // Inactivity alerts are only raised once until the device sends data again
+    inactive: HashSet<(u64, String)>,
+}
+
+#[derive(Clone)]
+pub struct Alerts {
+    engine: Arc<Mutex<AlertEngine>>,
+    config: Arc<AlertConfig>,
+}
+
+fn channel(frame: &DataFrame, name: &str) -> Option<Vec<f64>> {
+    let values = frame.column(name).ok()?.cast(&DataType::Float64).ok()?;
+    let values = values.f64().ok()?;
+    Some(
+        values
+            .into_iter()
+            .flatten()
+            .filter(|v| !v.is_nan())
+            .collect(),
+    )
+}
+
+fn max(values: impl Iterator<Item = f64>) -> Option<f64> {
+    values.max_by(|a, b| a.total_cmp(b))
+}
+
+fn min(values: impl Iterator<Item = f64>) -> Option<f64> {
+    values.min_by(|a, b| a.total_cmp(b))
+}
+
+fn magnitude(frame: &DataFrame, schema: &[ChannelSpec], sensor: &str) -> Option<Vec<f64>> {
+    let [x, y, z] = hello::axes(schema, sensor)?;
+    let (x, y, z) = (channel(frame, x)?, channel(frame, y)?, channel(frame, z)?);
+    Some(
+        x.into_iter()
+            .zip(y)
+            .zip(z)
+            .map(|((x, y), z)| (x * x + y * y + z * z).sqrt())
+            .collect(),
+    )
+}
+
+// Check a frame of a device with the given schema against a data condition,
+// returning the offending value and a description if the condition is met
+fn check(
+    condition: &Condition,
+    frame: &DataFrame,
+    schema: &[ChannelSpec],
+) -> Option<(f64, String)> {
+    match condition {
+        Condition::Above {
+            channel: name,
+            limit,
+        } => max(channel(frame, name)?.into_iter())
+            .filter(|v| v > limit)
+            .map(|v| (v, format!("{name} = {v:.3} above {limit}"))),
+        Condition::Below {
+            channel: name,
+            limit,
+        } => min(channel(frame, name)?.into_iter())
+            .filter(|v| v < limit)
+            .map(|v| (v, format!("{name} = {v:.3} below {limit}"))),
+        Condition::MagnitudeAbove { sensor, limit } => {
+            max(magnitude(frame, schema, sensor)?.into_iter())
+                .filter(|v| v > limit)
+                .map(|v| (v, format!("|{sensor}| = {v:.3} above {limit}")))
+        }
+        Condition::Inactive { .. } => None,
+    }
+}
+
+impl Alerts {
+    pub fn new(config: AlertConfig) -> Self {
+        Self {
+            engine: Arc::new(Mutex::new(AlertEngine {
+                next_id: 1,
+                rules: BTreeMap::new(),
+                history: VecDeque::new(),
+                last_fired: HashMap::new(),
+                inactive: HashSet::new(),
+            })),
+            config: Arc::new(config),
+        }
+    }
+
+    pub async fn rules(&self) -> Vec<Rule> {
+        self.engine.lock().await.rules.values().cloned().collect()
+    }
+
+    pub async fn rule(&self, id: u64) -> Option<Rule> {
+        self.engine.lock().await.rules.get(&id).cloned()
+    }
+
+    pub async fn add_rule(&self, mut rule: Rule) -> Rule {
+        let mut engine = self.engine.lock().await;
+        rule.id = engine.next_id;
+        engine.next_id += 1;
+        engine.rules.insert(rule.id, rule.clone());
+        rule
+    }
+
+    pub async fn update_rule(&self, id: u64, mut rule: Rule) -> Option<Rule> {
+        let mut engine = self.engine.lock().await;
+        let existing = engine.rules.get_mut(&id)?;
+        rule.id = id;
+        *existing = rule.clone();
+        Some(rule)
+    }
+
+    pub async fn remove_rule(&self, id: u64) -> Option<Rule> {
+        let mut engine = self.engine.lock().await;
+        engine.last_fired.retain(|(rule, _), _| *rule != id);
+        engine.inactive.retain(|(rule, _)| *rule != id);
+        engine.rules.remove(&id)
+    }
+
+    pub async fn history(&self, limit: usize) -> Vec<Alert> {
+        let engine = self.engine.lock().await;
+        engine.history.iter().rev().take(limit).cloned().collect()
+    }
+
+    // Evaluate all data rules applying to `device` against a newly received frame
+    pub async fn evaluate(
+        &self,
+        device: &str,
+        frame: &DataFrame,
+        schema: &[ChannelSpec],
+    ) -> Vec<Alert> {
+        let mut engine = self.engine.lock().await;
+        engine.inactive.retain(|(_, d)| d != device);
+
+        let hits = engine
+            .rules
+            .values()
+            .filter(|rule| rule.applies_to(device))
+            .filter_map(|rule| check(&rule.condition, frame, schema).map(|hit| (rule.clone(), hit)))
+            .collect::<Vec<_>>();
+
+        hits.into_iter()
+            .filter_map(|(rule, (value, message))| {
+                engine.fire(&rule, device, message, Some(value), &self.config)
+            })
+            .collect()
+    }
+
+    // Evaluate inactivity rules given the time since each device last sent data
+    pub async fn evaluate_inactive(&self, idle: &[(String, StdDuration)]) -> Vec<Alert> {
+        let mut engine = self.engine.lock().await;
+        let rules = engine.rules.values().cloned().collect::<Vec<_>>();
+
+        let mut alerts = Vec::new();
+        for rule in rules {
+            let Condition::Inactive { seconds } = rule.condition else {
+                continue;
+            };
+            for (device, idle) in idle {
+                let key = (rule.id, device.clone());
+                if !rule.applies_to(device)
+                    || idle.as_secs() < seconds
+                    || engine.inactive.contains(&key)
+                {
+                    continue;
+                }
+                let message = format!("no data for {}s", idle.as_secs());
+                // Checked again on the next tick while the cooldown holds it back
+                if let Some(alert) = engine.fire(
+                    &rule,
+                    device,
+                    message,
+                    Some(idle.as_secs_f64()),
+                    &self.config,
+                ) {
+                    engine.inactive.insert(key);
+                    alerts.push(alert);
+                }
+            }
+        }
+        alerts
+    }
+
+    // Send alerts to all configured sinks without blocking the caller
+    pub fn dispatch(&self, alerts: Vec<Alert>) {
+        for alert in alerts {
+            for sink in self.config.sinks.iter().cloned() {
+                let alert = alert.clone();
+                rocket::tokio::spawn(async move {
+                    if let Err(e) = sink.send(&alert).await {
+                        log::warn!("Failed to deliver alert '{}': {e}", alert.rule_name);
+                    }
+                });
+            }
+        }
+    }
+}
+
+impl AlertEngine {
+    fn fire(
+        &mut self,
+        rule: &Rule,
+        device: &str,
+        message: String,
+        value: Option<f64>,
+        config: &AlertConfig,
+    ) -> Option<Alert> {
+        let key = (rule.id, device.to_string());
+        let cooldown = StdDuration::from_secs(rule.cooldown_secs);
+        if self
+            .last_fired
+            .get(&key)
+            .is_some_and(|t| t.elapsed() < cooldown)
+        {
+            return None;
+        }
+        self.last_fired.insert(key, Instant::now());
+
+        let alert = Alert {
+            rule_id: rule.id,
+            rule_name: rule.name.clone(),
+            device: device.to_string(),
+            message,
+            value,
+            time: chrono::Utc::now().to_rfc3339(),
+        };
+        self.history.push_back(alert.clone());
+        while self.history.len() > config.history_size {
+            self.history.pop_front();
+        }
+        Some(alert)
+    }
+}
+
+impl Sink {
+    async fn send(&self, alert: &Alert) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let text = format!(
+            "[{}] {} on {}: {}",
+            alert.time, alert.rule_name, alert.device, alert.message
+        );
+
+        match self {
+            Sink::Webhook { url } => {
+                reqwest::Client::new()
+                    .post(url)
+                    .json(alert)
+                    .send()
+                    .await?
+                    .error_for_status()?;
+            }
+            Sink::Log { path } => {
+                let mut file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .await?;
+                file.write_all(format!("{text}\n").as_bytes()).await?;
+            }
+            Sink::Smtp {
+                server,
+                port,
+                from,
+                to,
+                username,
+                password,
+            } => {
+                let mut message = Message::builder()
+                    .from(from.parse::<Mailbox>()?)
+                    .subject(format!("Hecate alert: {}", alert.rule_name));
+                for to in to {
+                    message = message.to(to.parse::<Mailbox>()?);
+                }
+                let message = message.body(text)?;
+
+                let mut transport = AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(server)?;
+                if let Some(port) = port {
+                    transport = transport.port(*port);
+                }
+                if let (Some(username), Some(password)) = (username, password) {
+                    transport =
+                        transport.credentials(Credentials::new(username.clone(), password.clone()));
+                }
+                transport.build().send(message).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hello::Hello;
+
+    fn imu() -> Vec<ChannelSpec> {
+        Hello::legacy("a").schema()
+    }
+
+    fn rule(condition: Condition, cooldown_secs: u64) -> Rule {
+        Rule {
+            id: 0,
+            name: String::from("test"),
+            device: None,
+            condition,
+            enabled: true,
+            cooldown_secs,
+        }
+    }
+
+    fn frame() -> DataFrame {
+        df!(
+            "acc_x" => [0.5, 3.0, f64::NAN],
+            "acc_y" => [0.0, 4.0, 0.0],
+            "acc_z" => [-2.0, 0.0, 0.0],
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn checks_thresholds() {
+        let above = |limit| Condition::Above {
+            channel: String::from("acc_x"),
+            limit,
+        };
+        let below = |limit| Condition::Below {
+            channel: String::from("acc_z"),
+            limit,
+        };
+        let magnitude = |limit| Condition::MagnitudeAbove {
+            sensor: String::from("acc"),
+            limit,
+        };
+        assert_eq!(check(&above(2.0), &frame(), &imu()).unwrap().0, 3.0);
+        assert!(check(&above(3.0), &frame(), &imu()).is_none());
+        assert_eq!(check(&below(-1.0), &frame(), &imu()).unwrap().0, -2.0);
+        assert!(check(&below(-2.0), &frame(), &imu()).is_none());
+        assert_eq!(check(&magnitude(4.9), &frame(), &imu()).unwrap().0, 5.0);
+        assert!(check(&magnitude(5.0), &frame(), &imu()).is_none());
+        // Only for sensors the device declared
+        assert!(check(&magnitude(0.0), &frame(), &[]).is_none());
+
+        // Channels the frame does not have never match
+        let missing = Condition::Above {
+            channel: String::from("temperature"),
+            limit: 0.0,
+        };
+        assert!(check(&missing, &frame(), &imu()).is_none());
+    }
+
+    #[rocket::async_test]
+    async fn holds_back_alerts_during_the_cooldown() {
+        let alerts = Alerts::new(AlertConfig::default());
+        let condition = Condition::Above {
+            channel: String::from("acc_x"),
+            limit: 1.0,
+        };
+        alerts.add_rule(rule(condition.clone(), 60)).await;
+        assert_eq!(alerts.evaluate("a", &frame(), &imu()).await.len(), 1);
+        assert!(alerts.evaluate("a", &frame(), &imu()).await.is_empty());
+        // The cooldown is per device
+        assert_eq!(alerts.evaluate("b", &frame(), &imu()).await.len(), 1);
+
+        let alerts = Alerts::new(AlertConfig::default());
+        alerts.add_rule(rule(condition, 0)).await;
+        assert_eq!(alerts.evaluate("a", &frame(), &imu()).await.len(), 1);
+        assert_eq!(alerts.evaluate("a", &frame(), &imu()).await.len(), 1);
+        assert_eq!(alerts.history(10).await.len(), 2);
+    }
+
+    #[rocket::async_test]
+    async fn raises_inactivity_once_until_data_arrives() {
+        let alerts = Alerts::new(AlertConfig::default());
+        let rule = alerts
+            .add_rule(rule(Condition::Inactive { seconds: 10 }, 60))
+            .await;
+        let idle = |secs| [(String::from("a"), StdDuration::from_secs(secs))];
+
+        assert!(alerts.evaluate_inactive(&idle(5)).await.is_empty());
+        assert_eq!(alerts.evaluate_inactive(&idle(11)).await.len(), 1);
+        assert!(alerts.evaluate_inactive(&idle(12)).await.is_empty());
+
+        // Silent again right after sending data, the cooldown holds the alert
+        // back but it is raised once the cooldown is over
+        alerts.evaluate("a", &frame(), &imu()).await;
+        assert!(alerts.evaluate_inactive(&idle(11)).await.is_empty());
+        alerts
+            .engine
+            .lock()
+            .await
+            .last_fired
+            .remove(&(rule.id, String::from("a")));
+        assert_eq!(alerts.evaluate_inactive(&idle(12)).await.len(), 1);
+    }
+
+    #[test]
+    fn validates_rules() {
+        let above = |channel: &str, limit| {
+            rule(
+                Condition::Above {
+                    channel: channel.to_string(),
+                    limit,
+                },
+                60,
+            )
+        };
+        assert!(above("acc_x", 1.0).validate().is_ok());
+        assert!(above("", 1.0).validate().is_err());
+        assert!(above("acc_x", f64::NAN).validate().is_err());
+        assert!(above("acc_x", f64::INFINITY).validate().is_err());
+        assert!(rule(Condition::Inactive { seconds: 0 }, 60)
+            .validate()
+            .is_err());
+
+        let mut unnamed = above("acc_x", 1.0);
+        unnamed.name = String::new();
+        assert!(unnamed.validate().is_err());
+    }
+}
@@ -0,0 +1,361 @@
+use rocket::{
+    http::{ContentType, Header, Status},
+    request::{self, FromRequest, Request},
+    response::{self, Responder, Response},
+};
+use std::path::{Component, Path, PathBuf};
+
+// Where `trunk build` puts the UI, independent of the working directory
+pub fn default_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("../ui/dist")
+}
+
+// What the client accepts, from the request headers
+#[cfg_attr(not(feature = "embed-ui"), allow(dead_code))]
+pub struct Negotiation {
+    gzip: bool,
+    brotli: bool,
+    // A browser navigating rather than a script fetching
+    html: bool,
+    if_none_match: Option<String>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Negotiation {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, ()> {
+        let accepts = |header: &str, value: &str| {
+            request
+                .headers()
+                .get(header)
+                .flat_map(|values| values.split(','))
+                .any(|v| v.split(';').next().unwrap_or("").trim() == value)
+        };
+        request::Outcome::Success(Self {
+            gzip: accepts("Accept-Encoding", "gzip"),
+            brotli: accepts("Accept-Encoding", "br"),
+            html: accepts("Accept", "text/html"),
+            if_none_match: request.headers().get_one("If-None-Match").map(String::from),
+        })
+    }
+}
+
+impl Negotiation {
+    fn matches(&self, etag: &str) -> bool {
+        self.if_none_match.as_deref().is_some_and(|value| {
+            value
+                .split(',')
+                .map(|tag| tag.trim().trim_start_matches("W/"))
+                .any(|tag| tag == "*" || tag == etag)
+        })
+    }
+}
+
+enum Body {
+    #[cfg(not(feature = "embed-ui"))]
+    File(rocket::tokio::fs::File, u64),
+    #[cfg(feature = "embed-ui")]
+    Bytes(std::borrow::Cow<'static, [u8]>),
+}
+
+pub struct Asset {
+    content_type: ContentType,
+    etag: String,
+    cache_control: &'static str,
+    encoding: Option<&'static str>,
+    // None if the client already has this version
+    body: Option<Body>,
+}
+
+impl<'r> Responder<'r, 'static> for Asset {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = Response::build();
+        response
+            .header(Header::new("ETag", self.etag))
+            .header(Header::new("Cache-Control", self.cache_control));
+        if cfg!(feature = "embed-ui") {
+            response.header(Header::new("Vary", "Accept-Encoding"));
+        }
+        let Some(body) = self.body else {
+            return response.status(Status::NotModified).ok();
+        };
+        if let Some(encoding) = self.encoding {
+            response.header(Header::new("Content-Encoding", encoding));
+        }
+        response.header(self.content_type);
+        match body {
+            #[cfg(not(feature = "embed-ui"))]
+            Body::File(file, size) => response.sized_body(size as usize, file),
+            #[cfg(feature = "embed-ui")]
+            Body::Bytes(data) => response.sized_body(data.len(), std::io::Cursor::new(data)),
+        };
+        response.ok()
+    }
+}
+
+// The request path as a name relative to the root of the UI, None if it could
+// leave that root
+fn relative(path: &Path) -> Option<String> {
+    let mut names = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => names.push(name.to_str()?),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    (!names.is_empty()).then(|| names.join("/"))
+}
+
+fn content_type(name: &str) -> ContentType {
+    Path::new(name)
+        .extension()
+        .and_then(|e| e.to_str())
+        .and_then(ContentType::from_extension)
+        .unwrap_or(ContentType::Binary)
+}
+
+// Whether the name carries a content hash, as trunk adds to the files it
+// generates, e.g. "hecate-ui-2f9d0c5b8a1e4d3f_bg.wasm"
+fn hashed(name: &str) -> bool {
+    let file = name.rsplit('/').next().unwrap_or(name);
+    let stem = file.split('.').next().unwrap_or(file);
+    stem.split(['-', '_'])
+        .any(|part| part.len() >= 16 && part.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+// Files with a content hash never change under their name. Everything else,
+// index.html as well as copied files like the vendored scripts, is
+// revalidated with its ETag on every load.
+fn cache_control(name: &str) -> &'static str {
+    if hashed(name) {
+        "public, max-age=31536000, immutable"
+    } else {
+        "no-cache"
+    }
+}
+
+// The built UI, read from `ui_dir` or, with the `embed-ui` feature, from the
+// binary itself
+pub struct Assets {
+    #[cfg_attr(feature = "embed-ui", allow(dead_code))]
+    dir: PathBuf,
+}
+
+impl Assets {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    // Unknown paths without an extension are routes of the single page app
+    // when a browser navigates to them, and get index.html
+    pub async fn get(&self, path: &Path, negotiation: &Negotiation) -> Result<Asset, Status> {
+        let name = relative(path).ok_or(Status::NotFound)?;
+        if let Some(asset) = self.find(&name, negotiation).await {
+            return Ok(asset);
+        }
+        if negotiation.html && Path::new(&name).extension().is_none() {
+            return self
+                .find("index.html", negotiation)
+                .await
+                .ok_or(Status::NotFound);
+        }
+        Err(Status::NotFound)
+    }
+
+    #[cfg(not(feature = "embed-ui"))]
+    async fn find(&self, name: &str, negotiation: &Negotiation) -> Option<Asset> {
+        use rocket::tokio::fs;
+        use std::time::UNIX_EPOCH;
+
+        // Resolve symlinks before checking the file is below the root
+        let root = fs::canonicalize(&self.dir).await.ok()?;
+        let path = fs::canonicalize(root.join(name)).await.ok()?;
+        if !path.starts_with(&root) {
+            return None;
+        }
+        let file = fs::File::open(&path).await.ok()?;
+        let metadata = file.metadata().await.ok()?;
+        if !metadata.is_file() {
+            return None;
+        }
+
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |t| t.as_nanos());
+        let etag = format!("\"{:x}-{:x}\"", metadata.len(), modified);
+        Some(Asset {
+            content_type: content_type(name),
+            cache_control: cache_control(name),
+            encoding: None,
+            body: (!negotiation.matches(&etag)).then_some(Body::File(file, metadata.len())),
+            etag,
+        })
+    }
+
+    #[cfg(feature = "embed-ui")]
+    async fn find(&self, name: &str, negotiation: &Negotiation) -> Option<Asset> {
+        let file = embedded::Files::get(name)?;
+        let variant = [
+            (negotiation.brotli, "br", "br"),
+            (negotiation.gzip, "gz", "gzip"),
+        ]
+        .into_iter()
+        .filter(|(accepted, ..)| *accepted)
+        .find_map(|(_, extension, encoding)| {
+            embedded::Files::get(&format!("{name}.{extension}")).map(|file| (file, encoding))
+        });
+
+        // The hash of the uncompressed content, qualified by the encoding as
+        // the bytes sent differ
+        let hash = file
+            .metadata
+            .sha256_hash()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect::<String>();
+        let (data, encoding) = match variant {
+            Some((compressed, encoding)) => (compressed.data, Some(encoding)),
+            None => (file.data, None),
+        };
+        let etag = match encoding {
+            Some(encoding) => format!("\"{hash}-{encoding}\""),
+            None => format!("\"{hash}\""),
+        };
+
+        Some(Asset {
+            content_type: content_type(name),
+            cache_control: cache_control(name),
+            encoding,
+            body: (!negotiation.matches(&etag)).then_some(Body::Bytes(data)),
+            etag,
+        })
+    }
+}
+
+#[cfg(feature = "embed-ui")]
+mod embedded {
+    use rust_embed::RustEmbed;
+
+    // Copied from `ui/dist` by build.rs, which also adds `.gz` and `.br`
+    // variants of the compressible files
+    #[derive(RustEmbed)]
+    #[folder = "$OUT_DIR/ui"]
+    pub struct Files;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn negotiation(html: bool, if_none_match: Option<&str>) -> Negotiation {
+        Negotiation {
+            gzip: false,
+            brotli: false,
+            html,
+            if_none_match: if_none_match.map(String::from),
+        }
+    }
+
+    #[test]
+    fn keeps_paths_below_the_root() {
+        assert_eq!(
+            relative(Path::new("index.html")).as_deref(),
+            Some("index.html")
+        );
+        assert_eq!(relative(Path::new("./a/b.js")).as_deref(), Some("a/b.js"));
+        for path in ["", "..", "../secret", "a/../../secret", "/etc/passwd"] {
+            assert_eq!(relative(Path::new(path)), None, "{path}");
+        }
+    }
+
+    #[test]
+    fn caches_only_hashed_files_for_long() {
+        let immutable = "public, max-age=31536000, immutable";
+        assert_eq!(
+            cache_control("hecate-ui-2f9d0c5b8a1e4d3f_bg.wasm"),
+            immutable
+        );
+        assert_eq!(cache_control("hecate-5c1e0a9d7b3f2e41.css"), immutable);
+        for name in [
+            "index.html",
+            "echarts.min.js",
+            "echarts-gl.min.js",
+            "favicon.ico",
+        ] {
+            assert_eq!(cache_control(name), "no-cache", "{name}");
+        }
+    }
+
+    #[test]
+    fn matches_etags() {
+        let etag = "\"abc\"";
+        assert!(negotiation(false, Some("\"abc\"")).matches(etag));
+        assert!(negotiation(false, Some("W/\"xyz\", W/\"abc\"")).matches(etag));
+        assert!(negotiation(false, Some("*")).matches(etag));
+        assert!(!negotiation(false, Some("\"xyz\"")).matches(etag));
+        assert!(!negotiation(false, None).matches(etag));
+    }
+
+    #[cfg(not(feature = "embed-ui"))]
+    #[rocket::async_test]
+    async fn serves_files_and_falls_back_to_the_app() {
+        let root = std::env::temp_dir().join(format!("hecate-assets-{}", std::process::id()));
+        let dir = root.join("dist");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("index.html"), "<html></html>").unwrap();
+        std::fs::write(dir.join("app.js"), "main()").unwrap();
+        std::fs::write(root.join("secret.txt"), "secret").unwrap();
+        let assets = Assets::new(dir.clone());
+        let get = |path: &'static str, negotiation: Negotiation| {
+            let assets = &assets;
+            async move { assets.get(Path::new(path), &negotiation).await }
+        };
+
+        let script = get("app.js", negotiation(false, None)).await.unwrap();
+        assert_eq!(script.content_type, ContentType::JavaScript);
+        assert_eq!(script.cache_control, "no-cache");
+        assert!(script.body.is_some());
+
+        // Revalidation answers 304 without a body
+        let etag = script.etag.clone();
+        let cached = get("app.js", negotiation(false, Some(&etag)))
+            .await
+            .unwrap();
+        assert_eq!(cached.etag, etag);
+        assert!(cached.body.is_none());
+
+        // Routes of the app get index.html, but only for a browser navigating
+        let route = get("devices/bench-1", negotiation(true, None))
+            .await
+            .unwrap();
+        assert_eq!(route.content_type, ContentType::HTML);
+        assert_eq!(
+            get("devices/bench-1", negotiation(false, None)).await.err(),
+            Some(Status::NotFound)
+        );
+        assert_eq!(
+            get("missing.js", negotiation(true, None)).await.err(),
+            Some(Status::NotFound)
+        );
+
+        // Nothing outside the root, not even through a symlink
+        assert_eq!(
+            get("../secret.txt", negotiation(true, None)).await.err(),
+            Some(Status::NotFound)
+        );
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(root.join("secret.txt"), dir.join("link.txt")).unwrap();
+            assert_eq!(
+                get("link.txt", negotiation(false, None)).await.err(),
+                Some(Status::NotFound)
+            );
+        }
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,219 @@
+use crate::hello::Hello;
+
+use bytes::Buf;
+use polars::prelude::*;
+use rocket::{
+    http::Status,
+    request::{FromRequest, Outcome, Request},
+    serde::Serialize,
+};
+use std::io::Read;
+
+// Compression of an uploaded batch, from its Content-Encoding header
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Identity,
+    Gzip,
+    Zstd,
+}
+
+impl Encoding {
+    // None for encodings that can't be decompressed, including a chain of them
+    fn parse(header: Option<&str>) -> Option<Self> {
+        match header.map(|h| h.trim().to_ascii_lowercase()).as_deref() {
+            None | Some("") | Some("identity") => Some(Self::Identity),
+            Some("gzip") | Some("x-gzip") => Some(Self::Gzip),
+            Some("zstd") => Some(Self::Zstd),
+            Some(_) => None,
+        }
+    }
+}
+
+// Bodies in any other encoding are refused with 415
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Encoding {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, ()> {
+        match Self::parse(request.headers().get_one("Content-Encoding")) {
+            Some(encoding) => Outcome::Success(encoding),
+            None => Outcome::Error((Status::UnsupportedMediaType, ())),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct BatchSummary {
+    pub messages: usize,
+    pub samples: usize,
+    // Time range covered by the batch in seconds
+    pub start: Option<f64>,
+    pub end: Option<f64>,
+}
+
+// The decompressed body, None if it exceeds `limit` bytes. Reading stops
+// there, so a small body can't expand into more memory than that.
+pub fn decompress(
+    bytes: Vec<u8>,
+    encoding: Encoding,
+    limit: usize,
+) -> std::io::Result<Option<Vec<u8>>> {
+    let decoder: Box<dyn Read + '_> = match encoding {
+        Encoding::Identity => return Ok((bytes.len() <= limit).then_some(bytes)),
+        Encoding::Gzip => Box::new(flate2::read::GzDecoder::new(bytes.as_slice())),
+        Encoding::Zstd => Box::new(zstd::stream::read::Decoder::new(bytes.as_slice())?),
+    };
+    let mut decoded = Vec::new();
+    decoder.take(limit as u64 + 1).read_to_end(&mut decoded)?;
+    Ok((decoded.len() <= limit).then_some(decoded))
+}
+
+// Decode a body of length-delimited messages into one frame, sorted by time.
+// Each message is decoded like a binary message on the WebSocket, in the
+// protocol of the device's `hello`.
+pub fn decode(bytes: &[u8], hello: &Hello) -> Result<(DataFrame, BatchSummary), String> {
+    let mut buffer = bytes;
+    let mut frame: Option<DataFrame> = None;
+    let mut messages = 0;
+
+    while buffer.has_remaining() {
+        let length = prost::decode_length_delimiter(&mut buffer)
+            .ok()
+            .filter(|length| *length <= buffer.len())
+            .ok_or_else(|| format!("message {messages}: invalid length"))?;
+        let (message, rest) = buffer.split_at(length);
+        buffer = rest;
+        let next = hello.decode(message).ok_or_else(|| {
+            format!(
+                "message {messages}: invalid protocol {} data",
+                hello.protocol
+            )
+        })?;
+        match &mut frame {
+            Some(frame) => {
+                frame.vstack_mut(&next).map_err(|e| e.to_string())?;
+            }
+            None => frame = Some(next),
+        }
+        messages += 1;
+    }
+
+    let frame = frame
+        .ok_or_else(|| String::from("no messages in body"))?
+        .lazy()
+        .sort(["time"], Default::default())
+        .collect()
+        .map_err(|e| e.to_string())?;
+
+    let times = frame
+        .column("time")
+        .and_then(|s| s.cast(&DataType::Int64))
+        .map_err(|e| e.to_string())?;
+    let times = times.i64().map_err(|e| e.to_string())?;
+    let summary = BatchSummary {
+        messages,
+        samples: frame.height(),
+        start: times.min().map(|t| t as f64 * 1.0e-9),
+        end: times.max().map(|t| t as f64 * 1.0e-9),
+    };
+    Ok((frame, summary))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn gzip(bytes: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(bytes).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn decompresses_within_the_limit() {
+        let body = vec![7u8; 1000];
+        for (encoded, encoding) in [
+            (body.clone(), Encoding::Identity),
+            (gzip(&body), Encoding::Gzip),
+            (
+                zstd::encode_all(body.as_slice(), 0).unwrap(),
+                Encoding::Zstd,
+            ),
+        ] {
+            assert_eq!(
+                decompress(encoded, encoding, 1000).unwrap(),
+                Some(body.clone())
+            );
+        }
+    }
+
+    #[test]
+    fn stops_decompressing_at_the_limit() {
+        // Compresses to about a kilobyte
+        let bomb = vec![0u8; 1 << 20];
+        for (encoded, encoding) in [
+            (gzip(&bomb), Encoding::Gzip),
+            (
+                zstd::encode_all(bomb.as_slice(), 0).unwrap(),
+                Encoding::Zstd,
+            ),
+        ] {
+            assert!(encoded.len() < 4096);
+            assert_eq!(decompress(encoded, encoding, 4096).unwrap(), None);
+        }
+        assert_eq!(decompress(bomb, Encoding::Identity, 4096).unwrap(), None);
+    }
+
+    #[test]
+    fn accepts_only_known_encodings() {
+        assert_eq!(Encoding::parse(None), Some(Encoding::Identity));
+        assert_eq!(Encoding::parse(Some("identity")), Some(Encoding::Identity));
+        assert_eq!(Encoding::parse(Some(" GZIP ")), Some(Encoding::Gzip));
+        assert_eq!(Encoding::parse(Some("zstd")), Some(Encoding::Zstd));
+        for header in ["br", "deflate", "gzip, zstd", "compress"] {
+            assert_eq!(Encoding::parse(Some(header)), None, "{header}");
+        }
+    }
+
+    #[test]
+    fn rejects_an_empty_body() {
+        assert!(decode(&[], &Hello::legacy("a")).is_err());
+    }
+
+    #[test]
+    fn decodes_messages_in_the_protocol_of_the_hello() {
+        use crate::frame::sensor_data;
+        use crate::hello::wire;
+        use prost::Message;
+
+        let mut body = Vec::new();
+        for times in [[0.2, 0.3], [0.0, 0.1]] {
+            sensor_data(&times)
+                .encode_length_delimited(&mut body)
+                .unwrap();
+        }
+        let (frame, summary) = decode(&body, &Hello::legacy("a")).unwrap();
+        assert_eq!((summary.messages, summary.samples), (2, 4));
+        assert_eq!(summary.start, Some(0.0));
+        assert_eq!(frame.width(), 10);
+
+        let hello = Hello::announced(
+            "a",
+            r#"{ "protocol": 2, "channels": [{ "name": "level", "type": "i64" }] }"#,
+        )
+        .unwrap();
+        let data = wire::ChannelData {
+            time: vec![0.0, 0.5],
+            channels: vec![wire::Values {
+                values: vec![3.0, 4.0],
+            }],
+        };
+        let body = data.encode_length_delimited_to_vec();
+        let (frame, _) = decode(&body, &hello).unwrap();
+        assert_eq!(frame.get_column_names(), ["time", "level"]);
+        // A message cut short
+        assert!(decode(&body[..body.len() - 1], &hello).is_err());
+    }
+}
@@ -0,0 +1,339 @@
+use crate::duration;
+use crate::persist::Snapshot;
+use crate::recording::{self, Recordings};
+use crate::registry::{Orientation, Registry};
+
+use clap::{Args, Parser, Subcommand};
+use polars::prelude::*;
+use rocket::figment::Figment;
+use rocket::serde::json;
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+#[derive(Parser)]
+#[command(about = "Hecate sensor server")]
+pub struct Cli {
+    /// Directory with recordings and the device registry
+    #[arg(long, global = true)]
+    pub data_dir: Option<PathBuf>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run the server (the default)
+    Serve(Serve),
+    /// Write a recording to a CSV, Parquet or Arrow IPC file
+    Export {
+        /// Recording name, or device id with --device
+        name: String,
+        /// Output file, its extension selects the format
+        output: PathBuf,
+        /// Export the data a device uploaded in batches
+        #[arg(long)]
+        device: bool,
+    },
+    /// Store a CSV, Parquet or Arrow IPC file as a recording
+    Import {
+        file: PathBuf,
+        /// Recording name, defaults to the file name without extension
+        #[arg(long)]
+        name: Option<String>,
+    },
+    /// Manage the device registry
+    #[command(subcommand)]
+    Devices(Devices),
+}
+
+#[derive(Args)]
+pub struct Serve {
+    #[arg(long)]
+    pub address: Option<IpAddr>,
+    #[arg(long)]
+    pub port: Option<u16>,
+    /// How long live data is kept in memory, e.g. "5m" or "1h"
+    #[arg(long, value_parser = retention_arg)]
+    pub retention: Option<String>,
+    /// Directory of the built UI
+    #[arg(long)]
+    pub ui_dir: Option<PathBuf>,
+    /// PEM certificate chain, serves HTTPS and WSS together with --tls-key
+    #[arg(long, requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+    /// PEM private key
+    #[arg(long, requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+    /// PEM CA certificates to verify optional client certificates with
+    #[arg(long, requires = "tls_cert")]
+    pub tls_client_ca: Option<PathBuf>,
+}
+
+#[derive(Subcommand)]
+pub enum Devices {
+    /// List known devices
+    List,
+    /// Print the metadata of a device as JSON
+    Show { id: String },
+    /// Create or update a device
+    Set {
+        id: String,
+        #[arg(long)]
+        name: Option<String>,
+        #[arg(long)]
+        description: Option<String>,
+        #[arg(long)]
+        location: Option<String>,
+        /// May be given several times, replaces the existing tags
+        #[arg(long = "tag")]
+        tags: Vec<String>,
+        #[arg(long)]
+        model: Option<String>,
+        /// Mounting orientation in degrees as "roll,pitch,yaw"
+        #[arg(long, value_parser = orientation_arg)]
+        orientation: Option<Orientation>,
+    },
+    /// Remove a device from the registry
+    Remove { id: String },
+}
+
+impl Cli {
+    // Rocket's configuration with the command line options applied
+    pub fn figment(&self) -> Figment {
+        let mut figment = rocket::Config::figment();
+        if let Some(data_dir) = &self.data_dir {
+            figment = figment.merge(("data_dir", data_dir));
+        }
+        if let Some(Command::Serve(serve)) = &self.command {
+            if let Some(address) = serve.address {
+                figment = figment.merge(("address", address));
+            }
+            if let Some(port) = serve.port {
+                figment = figment.merge(("port", port));
+            }
+            if let Some(retention) = &serve.retention {
+                figment = figment.merge(("retention", retention));
+            }
+            if let Some(ui_dir) = &serve.ui_dir {
+                figment = figment.merge(("ui_dir", ui_dir));
+            }
+            if let (Some(cert), Some(key)) = (&serve.tls_cert, &serve.tls_key) {
+                figment = figment.merge(("tls.certs", cert)).merge(("tls.key", key));
+            }
+            if let Some(ca) = &serve.tls_client_ca {
+                figment = figment.merge(("tls.mutual.ca_certs", ca));
+            }
+        }
+        figment
+    }
+}
+
+// How long live data is kept, see `duration::parse`
+pub fn parse_retention(text: &str) -> Result<chrono::Duration, String> {
+    duration::parse(text).map_err(|e| format!("Invalid retention: {e}"))
+}
+
+fn retention_arg(text: &str) -> Result<String, String> {
+    parse_retention(text).map(|_| text.to_string())
+}
+
+fn orientation_arg(text: &str) -> Result<Orientation, String> {
+    let angles = text
+        .split(',')
+        .map(|angle| angle.trim().parse::<f64>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Invalid orientation: {e}"))?;
+    match angles[..] {
+        [roll, pitch, yaw] if angles.iter().all(|a| a.is_finite()) => {
+            Ok(Orientation { roll, pitch, yaw })
+        }
+        _ => Err("Invalid orientation: expected \"roll,pitch,yaw\" in degrees".into()),
+    }
+}
+
+pub fn data_dir(figment: &Figment) -> PathBuf {
+    figment
+        .extract_inner("data_dir")
+        .unwrap_or_else(|_| PathBuf::from("data"))
+}
+
+// Everything a device sent that is still stored: the batches in its backlog
+// recording and the live data of the last snapshot, without samples both have
+fn device_data(
+    recordings: &Recordings,
+    snapshot: &Snapshot,
+    id: &str,
+) -> PolarsResult<Option<DataFrame>> {
+    let name = recording::backlog_name(id);
+    let mut frames = Vec::new();
+    if recordings.exists(&name) {
+        frames.push(recording::raw_channels(&recordings.load(&name)?)?.lazy());
+    }
+    if let Some(live) = snapshot.device(id)? {
+        frames.push(recording::raw_channels(&live)?.lazy());
+    }
+    if frames.is_empty() {
+        return Ok(None);
+    }
+    concat_lf_diagonal(frames, Default::default())?
+        .unique_stable(Some(vec!["time".into()]), UniqueKeepStrategy::First)
+        .sort(["time"], Default::default())
+        .collect()
+        .map(Some)
+}
+
+// Run a command other than `serve` against the persisted store
+pub async fn run(command: Command, figment: &Figment) -> Result<(), String> {
+    let data_dir = data_dir(figment);
+    let recordings = Recordings::new(data_dir.join("recordings"));
+    let registry = Registry::load(data_dir.join("devices.json"))?;
+
+    match command {
+        Command::Serve(_) => unreachable!("serve is run by main"),
+        Command::Export {
+            name,
+            output,
+            device,
+        } => {
+            let mut frame = if device {
+                let snapshot = Snapshot::new(data_dir.join("state"));
+                device_data(&recordings, &snapshot, &name)
+                    .map_err(|e| e.to_string())?
+                    .ok_or_else(|| format!("no data of device '{name}'"))?
+            } else {
+                if !recordings.exists(&name) {
+                    return Err(format!("no recording '{name}'"));
+                }
+                recordings
+                    .load(&name)
+                    .and_then(|frame| recording::raw_channels(&frame))
+                    .map_err(|e| e.to_string())?
+            };
+            recording::write_file(&mut frame, &output).map_err(|e| e.to_string())?;
+            println!("Exported '{name}' to {}", output.display());
+        }
+        Command::Import { file, name } => {
+            let name = name
+                .or_else(|| file.file_stem().and_then(|s| s.to_str()).map(String::from))
+                .unwrap_or_default();
+            if !recording::valid_name(&name) {
+                return Err(format!("invalid recording name '{name}'"));
+            }
+            if recordings.exists(&name) {
+                return Err(format!("recording '{name}' already exists"));
+            }
+            let bytes = std::fs::read(&file).map_err(|e| e.to_string())?;
+            let mut frame = recording::read_file(bytes)
+                .and_then(|frame| recording::validate(&frame))
+                .map_err(|e| e.to_string())?;
+            recordings
+                .save(&name, &mut frame)
+                .map_err(|e| e.to_string())?;
+            println!("Imported {} samples as '{name}'", frame.height());
+        }
+        Command::Devices(Devices::List) => {
+            for (id, info) in registry.all().await {
+                let mut line = id.clone();
+                if let Some(name) = &info.name {
+                    line.push_str(&format!("  {name}"));
+                }
+                if let Some(location) = &info.location {
+                    line.push_str(&format!("  @ {location}"));
+                }
+                if !info.tags.is_empty() {
+                    line.push_str(&format!("  [{}]", info.tags.join(", ")));
+                }
+                println!("{line}");
+            }
+        }
+        Command::Devices(Devices::Show { id }) => {
+            let info = registry
+                .get(&id)
+                .await
+                .ok_or_else(|| format!("unknown device '{id}'"))?;
+            println!(
+                "{}",
+                json::to_pretty_string(&info).map_err(|e| e.to_string())?
+            );
+        }
+        Command::Devices(Devices::Set {
+            id,
+            name,
+            description,
+            location,
+            tags,
+            model,
+            orientation,
+        }) => {
+            let mut info = registry.get(&id).await.unwrap_or_default();
+            info.name = name.or(info.name);
+            info.description = description.or(info.description);
+            info.location = location.or(info.location);
+            info.model = model.or(info.model);
+            info.orientation = orientation.or(info.orientation);
+            if !tags.is_empty() {
+                info.tags = tags;
+            }
+            registry.set(&id, info).await;
+        }
+        Command::Devices(Devices::Remove { id }) => {
+            registry
+                .remove(&id)
+                .await
+                .ok_or_else(|| format!("unknown device '{id}'"))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_retentions() {
+        assert_eq!(parse_retention("1h"), Ok(chrono::Duration::hours(1)));
+        assert!(parse_retention("soon").is_err());
+        assert!(Cli::try_parse_from(["hecate", "serve", "--retention", "soon"]).is_err());
+        assert!(Cli::try_parse_from(["hecate", "serve", "--retention", "1h"]).is_ok());
+    }
+
+    #[test]
+    fn parses_orientations() {
+        assert_eq!(
+            orientation_arg("0, 90.5,-45"),
+            Ok(Orientation {
+                roll: 0.0,
+                pitch: 90.5,
+                yaw: -45.0
+            })
+        );
+        assert!(orientation_arg("0,90").is_err());
+        assert!(orientation_arg("0,90,inf").is_err());
+        assert!(orientation_arg("up").is_err());
+    }
+
+    #[rocket::async_test]
+    async fn exports_the_backlog_and_the_snapshot_of_a_device() {
+        let dir = std::env::temp_dir().join(format!("hecate-cli-{}", std::process::id()));
+        let recordings = Recordings::new(dir.join("recordings"));
+        let snapshot = Snapshot::new(dir.join("state"));
+        assert!(device_data(&recordings, &snapshot, "a").unwrap().is_none());
+
+        let mut backlog = crate::frame::sensor_data(&[0.0, 1.0]).frame().unwrap();
+        recordings
+            .save(&recording::backlog_name("a"), &mut backlog)
+            .unwrap();
+        let pipeline = crate::ingest::Pipeline::for_test();
+        pipeline.connections.open("a", "test").await.unwrap();
+        pipeline
+            .ingest("a", crate::frame::sensor_data(&[1.0, 2.0]).frame().unwrap())
+            .await;
+        snapshot.save(&pipeline.connections).await.unwrap();
+
+        let data = device_data(&recordings, &snapshot, "a").unwrap().unwrap();
+        assert_eq!(data.height(), 3);
+        assert!(data.column("device").is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
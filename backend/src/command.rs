@@ -0,0 +1,330 @@
+use prost::Message;
+use rocket::{
+    futures::lock::Mutex,
+    serde::{json, Deserialize, Serialize},
+    tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration as StdDuration, Instant};
+
+// Commands not acknowledged within this time are reported as timed out
+const ACK_TIMEOUT: StdDuration = StdDuration::from_secs(30);
+const HISTORY_SIZE: usize = 100;
+
+// Messages sent to a device as binary WebSocket frames
+pub mod wire {
+    use prost::{Message, Oneof};
+
+    #[derive(Clone, PartialEq, Message)]
+    pub struct Command {
+        #[prost(uint32, tag = "1")]
+        pub id: u32,
+        #[prost(oneof = "Action", tags = "2, 3, 4, 5, 6")]
+        pub action: Option<Action>,
+    }
+
+    #[derive(Clone, PartialEq, Oneof)]
+    pub enum Action {
+        #[prost(message, tag = "2")]
+        SetSampleRate(SetSampleRate),
+        #[prost(message, tag = "3")]
+        SetSensors(SetSensors),
+        #[prost(message, tag = "4")]
+        Calibrate(Empty),
+        #[prost(message, tag = "5")]
+        Reboot(Empty),
+        #[prost(message, tag = "6")]
+        Identify(Identify),
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    pub struct SetSampleRate {
+        #[prost(float, tag = "1")]
+        pub hz: f32,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    pub struct SetSensors {
+        #[prost(bool, tag = "1")]
+        pub acceleration: bool,
+        #[prost(bool, tag = "2")]
+        pub magnetometer: bool,
+        #[prost(bool, tag = "3")]
+        pub gyroscope: bool,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    pub struct Empty {}
+
+    #[derive(Clone, PartialEq, Message)]
+    pub struct Identify {
+        #[prost(uint32, tag = "1")]
+        pub seconds: u32,
+    }
+}
+
+// Sent back by the device as a JSON text message. Binary messages only ever
+// carry sensor data, so the two can't be mistaken for each other.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct CommandAck {
+    pub command_id: u32,
+    pub ok: bool,
+    #[serde(default)]
+    pub message: String,
+}
+
+fn identify_seconds() -> u32 {
+    5
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", tag = "type", rename_all = "snake_case")]
+pub enum CommandRequest {
+    SetSampleRate {
+        hz: f32,
+    },
+    SetSensors {
+        acceleration: bool,
+        magnetometer: bool,
+        gyroscope: bool,
+    },
+    Calibrate,
+    Reboot,
+    // Blink the identify LED
+    Identify {
+        #[serde(default = "identify_seconds")]
+        seconds: u32,
+    },
+}
+
+impl CommandRequest {
+    fn wire(&self, id: u32) -> wire::Command {
+        let action = match *self {
+            Self::SetSampleRate { hz } => wire::Action::SetSampleRate(wire::SetSampleRate { hz }),
+            Self::SetSensors {
+                acceleration,
+                magnetometer,
+                gyroscope,
+            } => wire::Action::SetSensors(wire::SetSensors {
+                acceleration,
+                magnetometer,
+                gyroscope,
+            }),
+            Self::Calibrate => wire::Action::Calibrate(wire::Empty {}),
+            Self::Reboot => wire::Action::Reboot(wire::Empty {}),
+            Self::Identify { seconds } => wire::Action::Identify(wire::Identify { seconds }),
+        };
+        wire::Command {
+            id,
+            action: Some(action),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum CommandStatus {
+    Pending,
+    Acknowledged,
+    Failed,
+    TimedOut,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct CommandRecord {
+    pub id: u32,
+    pub command: CommandRequest,
+    pub status: CommandStatus,
+    // Message the device sent with its acknowledgement
+    pub message: String,
+    // RFC3339
+    pub sent: String,
+    #[serde(skip)]
+    sent_at: Option<Instant>,
+}
+
+#[derive(Default)]
+struct Device {
+    // Command channels of the open sessions of the device, oldest first
+    senders: Vec<(u64, UnboundedSender<Vec<u8>>)>,
+    history: VecDeque<CommandRecord>,
+}
+
+#[derive(Clone)]
+pub struct Commands {
+    next_id: Arc<Mutex<u32>>,
+    devices: Arc<Mutex<HashMap<String, Device>>>,
+}
+
+impl Commands {
+    pub fn new() -> Self {
+        Self {
+            next_id: Arc::new(Mutex::new(1)),
+            devices: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // Called by the WebSocket handler of a device session; encoded commands
+    // for the device arrive on the returned receiver
+    pub async fn register(&self, device: &str, session: u64) -> UnboundedReceiver<Vec<u8>> {
+        let (sender, receiver) = unbounded_channel();
+        let mut devices = self.devices.lock().await;
+        let device = devices.entry(device.to_string()).or_default();
+        device.senders.retain(|(_, s)| !s.is_closed());
+        device.senders.push((session, sender));
+        receiver
+    }
+
+    // Called when the session ends. Other sessions of the device keep theirs.
+    pub async fn unregister(&self, device: &str, session: u64) {
+        let mut devices = self.devices.lock().await;
+        if let Some(device) = devices.get_mut(device) {
+            device
+                .senders
+                .retain(|(id, s)| *id != session && !s.is_closed());
+        }
+    }
+
+    // Send a command to a connected device through its newest session, None if
+    // it has no command channel
+    pub async fn send(&self, device: &str, command: CommandRequest) -> Option<CommandRecord> {
+        let id = {
+            let mut next_id = self.next_id.lock().await;
+            let id = *next_id;
+            *next_id = next_id.wrapping_add(1).max(1);
+            id
+        };
+
+        let mut devices = self.devices.lock().await;
+        let device = devices.get_mut(device)?;
+        let encoded = command.wire(id).encode_to_vec();
+        device
+            .senders
+            .iter()
+            .rev()
+            .find_map(|(_, s)| s.send(encoded.clone()).ok())?;
+
+        let record = CommandRecord {
+            id,
+            command,
+            status: CommandStatus::Pending,
+            message: String::new(),
+            sent: chrono::Utc::now().to_rfc3339(),
+            sent_at: Some(Instant::now()),
+        };
+        device.history.push_front(record.clone());
+        device.history.truncate(HISTORY_SIZE);
+        Some(record)
+    }
+
+    // Read a text message from a device as an acknowledgement, false if it
+    // is not one
+    pub async fn acknowledge(&self, device: &str, text: &str) -> bool {
+        let Ok(ack) = json::from_str::<CommandAck>(text) else {
+            return false;
+        };
+
+        let mut devices = self.devices.lock().await;
+        if let Some(record) = devices
+            .get_mut(device)
+            .and_then(|d| d.history.iter_mut().find(|r| r.id == ack.command_id))
+        {
+            record.status = if ack.ok {
+                CommandStatus::Acknowledged
+            } else {
+                CommandStatus::Failed
+            };
+            record.message = ack.message;
+        }
+        true
+    }
+
+    // Newest first
+    pub async fn history(&self, device: &str) -> Vec<CommandRecord> {
+        let mut devices = self.devices.lock().await;
+        let Some(device) = devices.get_mut(device) else {
+            return Vec::new();
+        };
+        for record in device.history.iter_mut() {
+            if record.status == CommandStatus::Pending
+                && record.sent_at.is_some_and(|t| t.elapsed() > ACK_TIMEOUT)
+            {
+                record.status = CommandStatus::TimedOut;
+            }
+        }
+        device.history.iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn status(commands: &Commands, device: &str, id: u32) -> (CommandStatus, String) {
+        let history = commands.history(device).await;
+        let record = history.iter().find(|r| r.id == id).unwrap();
+        (record.status, record.message.clone())
+    }
+
+    #[rocket::async_test]
+    async fn sends_commands_and_records_acknowledgements() {
+        let commands = Commands::new();
+        assert!(commands.send("a", CommandRequest::Reboot).await.is_none());
+
+        let mut receiver = commands.register("a", 1).await;
+        let sent = commands
+            .send("a", CommandRequest::SetSampleRate { hz: 100.0 })
+            .await
+            .unwrap();
+        let received = wire::Command::decode(receiver.recv().await.unwrap().as_slice()).unwrap();
+        assert_eq!(
+            received,
+            CommandRequest::SetSampleRate { hz: 100.0 }.wire(sent.id)
+        );
+        assert_eq!(
+            status(&commands, "a", sent.id).await.0,
+            CommandStatus::Pending
+        );
+
+        let ack = format!(
+            r#"{{ "command_id": {}, "ok": false, "message": "busy" }}"#,
+            sent.id
+        );
+        assert!(commands.acknowledge("a", &ack).await);
+        assert_eq!(
+            status(&commands, "a", sent.id).await,
+            (CommandStatus::Failed, String::from("busy"))
+        );
+        // Anything else a device sends as text is not an acknowledgement
+        assert!(!commands.acknowledge("a", "bench-1").await);
+        assert!(!commands.acknowledge("a", r#"{ "device_id": "a" }"#).await);
+    }
+
+    #[rocket::async_test]
+    async fn keeps_a_command_channel_per_session() {
+        let commands = Commands::new();
+        let mut first = commands.register("a", 1).await;
+        let mut second = commands.register("a", 2).await;
+
+        // The newest session gets the commands
+        commands.send("a", CommandRequest::Calibrate).await.unwrap();
+        assert!(second.try_recv().is_ok());
+        assert!(first.try_recv().is_err());
+
+        // Until it ends, then the other one takes over
+        drop(second);
+        commands.unregister("a", 2).await;
+        commands.send("a", CommandRequest::Calibrate).await.unwrap();
+        assert!(first.try_recv().is_ok());
+
+        drop(first);
+        commands.unregister("a", 1).await;
+        assert!(commands
+            .send("a", CommandRequest::Calibrate)
+            .await
+            .is_none());
+    }
+}
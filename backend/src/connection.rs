@@ -1,24 +1,299 @@
+use crate::events::{Detector, Event};
+use crate::filter::FilterState;
+use crate::hello::{ChannelSpec, Hello};
+use crate::memory::Reduction;
+use crate::quality::PeriodEstimate;
+
 use polars::prelude::*;
+use rocket::{
+    futures::lock::{Mutex, MutexGuard},
+    serde::{Deserialize, Serialize},
+    tokio::sync::oneshot,
+};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration as StdDuration, Instant};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum DuplicatePolicy {
+    // Refuse a second session for a device id that is already connected
+    Reject,
+    // Close the existing session in favour of the new one
+    #[default]
+    KickOld,
+    // Accept data from several sessions for the same device id
+    Multiple,
+}
+
+static NEXT_SESSION: AtomicU64 = AtomicU64::new(1);
+
+// Data over its budget is reduced to this share of it, so that it is not
+// reduced again on every message that follows
+const SHRINK_TARGET: f64 = 0.75;
+
+struct Session {
+    id: u64,
+    source: String,
+    started: chrono::DateTime<chrono::Utc>,
+    // Tells the session's transport that it was replaced by a newer session
+    kick: Option<oneshot::Sender<()>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct SessionInfo {
+    pub id: u64,
+    pub source: String,
+    // RFC3339
+    pub started: String,
+}
+
+// An open session of a device, held by the transport that opened it
+pub struct SessionHandle {
+    pub id: u64,
+    // Resolves when the session was kicked by a newer one
+    pub kicked: oneshot::Receiver<()>,
+}
+
+#[derive(Clone)]
+pub struct Connections {
+    pub connections: Arc<Mutex<HashMap<String, Connection>>>,
+    pub policy: DuplicatePolicy,
+}
+
+impl Connections {
+    pub fn new(policy: DuplicatePolicy) -> Self {
+        Self {
+            connections: Arc::new(Mutex::new(HashMap::new())),
+            policy,
+        }
+    }
+
+    pub fn get<'a>(
+        lock: &'a mut MutexGuard<'_, HashMap<String, Connection>>,
+        id: &str,
+    ) -> Option<&'a mut Connection> {
+        (*lock).get_mut(id)
+    }
+
+    // Open a session for a device, creating its connection on first contact.
+    // None if the duplicate policy refuses it.
+    pub async fn open(&self, id: &str, source: &str) -> Option<SessionHandle> {
+        let mut lock = self.connections.lock().await;
+        lock.entry(id.to_string())
+            .or_insert_with(Connection::new)
+            .open_session(source, self.policy)
+    }
+
+    // The hello a device last announced, that of a legacy device sending
+    // `SensorData` if it never did. Binary messages are decoded with it.
+    pub async fn hello(&self, id: &str) -> Hello {
+        let lock = self.connections.lock().await;
+        lock.get(id)
+            .and_then(|c| c.hello.clone())
+            .unwrap_or_else(|| Hello::legacy(id))
+    }
+
+    pub async fn set_hello(&self, id: &str, hello: Hello) {
+        let mut lock = self.connections.lock().await;
+        if let Some(connection) = Self::get(&mut lock, id) {
+            connection.hello = Some(hello);
+        }
+    }
+
+    pub async fn close(&self, id: &str, session: u64) {
+        let mut lock = self.connections.lock().await;
+        if let Some(connection) = Self::get(&mut lock, id) {
+            connection.close_session(session);
+        }
+    }
+}
+
+impl SessionHandle {
+    // Whether the session was kicked or otherwise closed by someone else
+    pub fn is_closed(&mut self) -> bool {
+        !matches!(
+            self.kicked.try_recv(),
+            Err(oneshot::error::TryRecvError::Empty)
+        )
+    }
+}
+
+struct Seen {
+    last: Instant,
+    // None once the duplicate policy refused or kicked the session, until the
+    // device has been silent for the timeout
+    session: Option<SessionHandle>,
+}
+
+// Connection state of devices on transports without a notion of a session,
+// which are considered disconnected once they stop sending
+pub struct Presence {
+    connections: Connections,
+    source: String,
+    timeout: StdDuration,
+    seen: HashMap<String, Seen>,
+}
+
+impl Presence {
+    pub fn new(connections: Connections, source: &str, timeout: StdDuration) -> Self {
+        Self {
+            connections,
+            source: source.to_string(),
+            timeout,
+            seen: HashMap::new(),
+        }
+    }
+
+    // Whether data from the device should be accepted
+    pub async fn seen(&mut self, id: &str) -> bool {
+        if let Some(seen) = self.seen.get_mut(id) {
+            seen.last = Instant::now();
+            if seen.session.as_mut().is_some_and(|s| s.is_closed()) {
+                seen.session = None;
+            }
+            return seen.session.is_some();
+        }
+
+        let session = self.connections.open(id, &self.source).await;
+        let accepted = session.is_some();
+        self.seen.insert(
+            id.to_string(),
+            Seen {
+                last: Instant::now(),
+                session,
+            },
+        );
+        accepted
+    }
+
+    // Close the sessions of devices that have not been seen within the timeout
+    pub async fn expire(&mut self) {
+        let idle = self
+            .seen
+            .iter()
+            .filter(|(_, s)| s.last.elapsed() > self.timeout)
+            .map(|(id, _)| id.clone())
+            .collect::<Vec<_>>();
+        for id in idle {
+            if let Some(session) = self.seen.remove(&id).and_then(|s| s.session) {
+                self.connections.close(&id, session.id).await;
+            }
+        }
+    }
+}
 
 pub struct Connection {
-    pub active: bool,
+    sessions: Vec<Session>,
+    // What the device announced about itself when it last connected
+    pub hello: Option<Hello>,
+    pub last_seen: Option<Instant>,
+    pub detector: Detector,
+    pub filter_state: Option<FilterState>,
+    pub period: PeriodEstimate,
     recent_data: DataFrame,
+    events: Vec<Event>,
 }
 
 impl Connection {
     pub fn new() -> Self {
         Self {
-            active: false,
+            sessions: Vec::new(),
+            hello: None,
+            last_seen: None,
+            detector: Detector::default(),
+            filter_state: None,
+            period: PeriodEstimate::default(),
             recent_data: DataFrame::empty(),
+            events: Vec::new(),
+        }
+    }
+
+    // Channels the device sends, those of a legacy device if it sent no hello
+    pub fn schema(&self) -> Vec<ChannelSpec> {
+        self.hello
+            .as_ref()
+            .map_or_else(|| Hello::legacy("").schema(), Hello::schema)
+    }
+
+    pub fn active(&self) -> bool {
+        !self.sessions.is_empty()
+    }
+
+    pub fn sessions(&self) -> Vec<SessionInfo> {
+        self.sessions
+            .iter()
+            .map(|s| SessionInfo {
+                id: s.id,
+                source: s.source.clone(),
+                started: s.started.to_rfc3339(),
+            })
+            .collect()
+    }
+
+    pub fn open_session(&mut self, source: &str, policy: DuplicatePolicy) -> Option<SessionHandle> {
+        match policy {
+            DuplicatePolicy::Reject if self.active() => return None,
+            DuplicatePolicy::KickOld => {
+                for session in self.sessions.drain(..) {
+                    log::info!("Session from {} replaced by {source}", session.source);
+                    if let Some(kick) = session.kick {
+                        _ = kick.send(());
+                    }
+                }
+            }
+            _ => {}
         }
+
+        let (kick, kicked) = oneshot::channel();
+        let id = NEXT_SESSION.fetch_add(1, Ordering::Relaxed);
+        self.sessions.push(Session {
+            id,
+            source: source.to_string(),
+            started: chrono::Utc::now(),
+            kick: Some(kick),
+        });
+        Some(SessionHandle { id, kicked })
+    }
+
+    pub fn close_session(&mut self, session: u64) {
+        self.sessions.retain(|s| s.id != session);
     }
 
     pub fn recent_data(&self) -> &DataFrame {
         &self.recent_data
     }
 
+    // Latest sample time currently stored, in nanoseconds
+    pub fn last_time(&self) -> Option<i64> {
+        self.recent_data
+            .column("time")
+            .ok()
+            .and_then(|s| s.cast(&DataType::Int64).ok())
+            .and_then(|s| s.i64().ok().and_then(|t| t.max()))
+    }
+
+    pub fn events(&self) -> &[Event] {
+        &self.events
+    }
+
+    pub fn add_events(&mut self, events: Vec<Event>) {
+        self.events.extend(events);
+    }
+
+    // Add events that may be older than the ones already stored
+    pub fn merge_events(&mut self, events: Vec<Event>) {
+        self.events.extend(events);
+        self.events.sort_by(|a, b| a.time.total_cmp(&b.time));
+    }
+
     pub fn reset_recent_data(&mut self) {
         self.recent_data = DataFrame::empty();
+        self.events.clear();
+        self.detector = Detector::default();
+        self.filter_state = None;
+        self.period = PeriodEstimate::default();
     }
 
     pub fn append_data(&mut self, new_data: DataFrame) -> Result<(), PolarsError> {
@@ -30,6 +305,65 @@ impl Connection {
         Ok(())
     }
 
+    // Merge data that may overlap or predate the stored data. Samples already
+    // stored win over ones with the same time.
+    pub fn merge_data(&mut self, new_data: DataFrame) -> Result<(), PolarsError> {
+        self.recent_data = concat_lf_diagonal(
+            [self.recent_data.clone().lazy(), new_data.lazy()],
+            Default::default(),
+        )?
+        .unique_stable(Some(vec![String::from("time")]), UniqueKeepStrategy::First)
+        .sort(["time"], Default::default())
+        .collect()?;
+        Ok(())
+    }
+
+    // Approximate size of the buffered data in bytes
+    pub fn memory_usage(&self) -> usize {
+        self.recent_data.estimated_size()
+    }
+
+    // Reduce the buffered data to below `budget` bytes once it exceeds it. The
+    // kept samples are gathered into new buffers so the memory of the others
+    // is released.
+    pub fn shrink_to(&mut self, budget: usize, reduction: Reduction) -> Result<(), PolarsError> {
+        let size = self.memory_usage();
+        let height = self.recent_data.height();
+        if size <= budget || height == 0 {
+            return Ok(());
+        }
+        let allowed = (height as f64 * SHRINK_TARGET * budget as f64 / size as f64) as usize;
+
+        let indices: Vec<IdxSize> = match reduction {
+            Reduction::Evict => (height - allowed..height).map(|i| i as IdxSize).collect(),
+            Reduction::Downsample => {
+                // The newest half of what fits stays at full resolution, the
+                // rest is spread evenly over the older samples
+                let recent = allowed / 2;
+                let older = height - recent;
+                let kept = allowed - recent;
+                (0..kept)
+                    .map(|i| i * older / kept)
+                    .chain(older..height)
+                    .map(|i| i as IdxSize)
+                    .collect()
+            }
+        };
+        self.recent_data = self.recent_data.take(&IdxCa::from_vec("", indices))?;
+
+        let first = self
+            .recent_data
+            .column("time")
+            .and_then(|s| s.cast(&DataType::Int64))
+            .ok()
+            .and_then(|s| s.i64().ok().and_then(|t| t.min()));
+        if let Some(first) = first {
+            let cutoff = first as f64 * 1.0e-9;
+            self.events.retain(|e| e.time >= cutoff);
+        }
+        Ok(())
+    }
+
     pub fn discard_older_than(&mut self, duration: chrono::Duration) -> Result<(), PolarsError> {
         self.recent_data = self
             .recent_data
@@ -37,6 +371,80 @@ impl Connection {
             .lazy()
             .filter(col("time").gt(col("time").max() - lit(duration)))
             .collect()?;
+
+        if let (Some(last), Some(duration)) = (self.last_time(), duration.num_nanoseconds()) {
+            let cutoff = (last - duration) as f64 * 1.0e-9;
+            self.events.retain(|e| e.time > cutoff);
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A connection holding `height` samples, one per millisecond
+    fn connection(height: i64) -> Connection {
+        let time = Series::new(
+            "time",
+            (0..height).map(|i| i * 1_000_000).collect::<Vec<_>>(),
+        )
+        .cast(&DataType::Duration(TimeUnit::Nanoseconds))
+        .unwrap();
+        let value = Series::new("acc_x", (0..height).map(|i| i as f64).collect::<Vec<_>>());
+        let mut connection = Connection::new();
+        connection
+            .append_data(DataFrame::new(vec![time, value]).unwrap())
+            .unwrap();
+        connection
+    }
+
+    fn values(connection: &Connection) -> Vec<f64> {
+        let values = connection.recent_data().column("acc_x").unwrap();
+        values.f64().unwrap().into_no_null_iter().collect()
+    }
+
+    #[test]
+    fn evicts_the_oldest_samples() {
+        let mut connection = connection(1000);
+        let budget = connection.memory_usage() / 2;
+        connection.shrink_to(budget, Reduction::Evict).unwrap();
+        let values = values(&connection);
+        assert_eq!(values.len(), 375);
+        assert_eq!(values[0], 625.0);
+        assert_eq!(values[374], 999.0);
+    }
+
+    #[test]
+    fn downsamples_the_older_samples_evenly() {
+        let mut connection = connection(1000);
+        let budget = connection.memory_usage() / 2;
+        connection.shrink_to(budget, Reduction::Downsample).unwrap();
+        let values = values(&connection);
+        assert_eq!(values.len(), 375);
+
+        // 188 samples spread over the oldest 813, then the newest 187
+        let (older, recent) = values.split_at(188);
+        assert_eq!(older[0], 0.0);
+        assert!(older[187] > 800.0 && older[187] < 813.0);
+        assert!(older
+            .windows(2)
+            .all(|w| (4.0..=5.0).contains(&(w[1] - w[0]))));
+        assert_eq!(recent, (813..1000).map(f64::from).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn shrinks_below_the_budget() {
+        let mut connection = connection(1000);
+        let budget = connection.memory_usage() / 2;
+        connection.shrink_to(budget, Reduction::Downsample).unwrap();
+        assert!(connection.memory_usage() <= budget);
+
+        // Data appended afterwards fits without another reduction
+        let more = self::connection(1).recent_data().clone();
+        connection.append_data(more).unwrap();
+        connection.shrink_to(budget, Reduction::Downsample).unwrap();
+        assert_eq!(connection.recent_data().height(), 376);
+    }
+}
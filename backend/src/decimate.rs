@@ -0,0 +1,211 @@
+use polars::prelude::*;
+use rocket::FromFormField;
+use std::collections::BTreeSet;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, FromFormField)]
+pub enum Decimation {
+    // Largest-Triangle-Three-Buckets, keeps the visual shape of a curve
+    #[default]
+    Lttb,
+    // The minimum and maximum of each bucket, keeps every peak
+    #[field(value = "min_max")]
+    MinMax,
+}
+
+// Indices of at most `points` samples chosen by Largest-Triangle-Three-Buckets
+fn lttb(x: &[f64], y: &[f64], points: usize) -> Vec<usize> {
+    let n = x.len();
+    if points >= n || points < 3 {
+        return (0..n).collect();
+    }
+
+    let every = (n - 2) as f64 / (points - 2) as f64;
+    let mut selected = Vec::with_capacity(points);
+    let mut a = 0;
+    selected.push(a);
+    for i in 0..points - 2 {
+        // Average of the next bucket, the third point of the triangle
+        let next_start = ((i + 1) as f64 * every) as usize + 1;
+        let next_end = (((i + 2) as f64 * every) as usize + 1).min(n);
+        let (sum_x, sum_y, count) = (next_start..next_end)
+            .filter(|&j| y[j].is_finite())
+            .fold((0.0, 0.0, 0), |(sx, sy, c), j| {
+                (sx + x[j], sy + y[j], c + 1)
+            });
+        let (avg_x, avg_y) = if count > 0 {
+            (sum_x / count as f64, sum_y / count as f64)
+        } else {
+            (x[n - 1], y[n - 1])
+        };
+
+        let start = (i as f64 * every) as usize + 1;
+        let end = (((i + 1) as f64 * every) as usize + 1).min(n - 1);
+        let mut best = (start, -1.0);
+        for j in start..end {
+            let area = ((x[a] - avg_x) * (y[j] - y[a]) - (x[a] - x[j]) * (avg_y - y[a])).abs();
+            if area > best.1 {
+                best = (j, area);
+            }
+        }
+        a = best.0;
+        selected.push(a);
+    }
+    selected.push(n - 1);
+    selected
+}
+
+// Indices of the minimum and maximum of `points / 2` equally sized buckets
+fn min_max(y: &[f64], points: usize) -> Vec<usize> {
+    let n = y.len();
+    if points >= n || points < 2 {
+        return (0..n).collect();
+    }
+
+    let size = n.div_ceil(points / 2);
+    let mut selected = Vec::with_capacity(points);
+    for start in (0..n).step_by(size) {
+        let bucket = (start..(start + size).min(n)).filter(|&j| y[j].is_finite());
+        let (mut low, mut high) = (None::<usize>, None::<usize>);
+        for j in bucket {
+            if !low.is_some_and(|l| y[j] >= y[l]) {
+                low = Some(j);
+            }
+            if !high.is_some_and(|h| y[j] <= y[h]) {
+                high = Some(j);
+            }
+        }
+        let (Some(low), Some(high)) = (low, high) else {
+            continue;
+        };
+        selected.push(low.min(high));
+        if low != high {
+            selected.push(low.max(high));
+        }
+    }
+    selected
+}
+
+// Reduce every channel of a frame sorted by time to at most `points` samples.
+// The limit is per channel: channels keep different samples, so the result has
+// the rows any channel kept, up to `points` for each channel, with each channel
+// null in the rows it did not keep.
+pub fn apply(frame: &DataFrame, points: usize, method: Decimation) -> PolarsResult<DataFrame> {
+    if frame.height() <= points {
+        return Ok(frame.clone());
+    }
+
+    let x = frame
+        .column("time")?
+        .cast(&DataType::Int64)?
+        .cast(&DataType::Float64)?;
+    let x = x
+        .f64()?
+        .into_iter()
+        .map(|t| t.unwrap_or(0.0))
+        .collect::<Vec<_>>();
+
+    let mut channels = Vec::new();
+    for column in frame.get_columns() {
+        if column.name() == "time" || column.name() == "quality" || !column.dtype().is_numeric() {
+            continue;
+        }
+        let y = column.cast(&DataType::Float64)?;
+        let y = y
+            .f64()?
+            .into_iter()
+            .map(|v| v.unwrap_or(f64::NAN))
+            .collect::<Vec<_>>();
+        let indices = match method {
+            Decimation::Lttb => lttb(&x, &y, points),
+            Decimation::MinMax => min_max(&y, points),
+        };
+        channels.push((column.name().to_string(), indices));
+    }
+
+    let rows = channels
+        .iter()
+        .flat_map(|(_, indices)| indices.iter().copied())
+        .collect::<BTreeSet<_>>();
+    let mut decimated = frame.take(&IdxCa::from_vec(
+        "",
+        rows.iter().map(|&i| i as IdxSize).collect(),
+    ))?;
+    for (name, indices) in channels {
+        let indices = indices.into_iter().collect::<BTreeSet<_>>();
+        let keep = rows
+            .iter()
+            .map(|i| indices.contains(i))
+            .collect::<BooleanChunked>();
+        let column = decimated.column(&name)?;
+        let nulls = Series::full_null(&name, column.len(), column.dtype());
+        let column = column.zip_with(&keep, &nulls)?;
+        decimated.with_column(column)?;
+    }
+    Ok(decimated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A slow sine with a spike up at 300 and one down at 700
+    fn signal(n: usize) -> (Vec<f64>, Vec<f64>) {
+        let x = (0..n).map(|i| i as f64).collect::<Vec<_>>();
+        let mut y = x.iter().map(|x| (x / 100.0).sin()).collect::<Vec<_>>();
+        y[300] = 10.0;
+        y[700] = -10.0;
+        (x, y)
+    }
+
+    #[test]
+    fn lttb_keeps_the_ends_and_the_spikes() {
+        let (x, y) = signal(1000);
+        let selected = lttb(&x, &y, 50);
+        assert_eq!(selected.len(), 50);
+        assert_eq!((selected[0], selected[49]), (0, 999));
+        assert!(selected.windows(2).all(|w| w[0] < w[1]));
+        assert!(selected.contains(&300) && selected.contains(&700));
+
+        assert_eq!(lttb(&x[..10], &y[..10], 50), (0..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn min_max_keeps_the_extremes_of_each_bucket() {
+        let (_, mut y) = signal(1000);
+        y[10] = f64::NAN;
+        let selected = min_max(&y, 50);
+        assert!(selected.len() <= 50);
+        assert!(selected.windows(2).all(|w| w[0] < w[1]));
+        assert!(selected.contains(&300) && selected.contains(&700));
+        assert!(!selected.contains(&10));
+
+        // Buckets without a finite value contribute nothing
+        assert!(min_max(&[f64::NAN; 100], 10).is_empty());
+    }
+
+    #[test]
+    fn limits_every_channel_to_the_points() {
+        let (x, y) = signal(1000);
+        let time = Series::new("time", x.iter().map(|x| *x as i64).collect::<Vec<_>>())
+            .cast(&DataType::Duration(TimeUnit::Nanoseconds))
+            .unwrap();
+        let reversed = y.iter().rev().copied().collect::<Vec<_>>();
+        let frame = DataFrame::new(vec![
+            time,
+            Series::new("acc_x", y),
+            Series::new("acc_y", reversed),
+        ])
+        .unwrap();
+
+        for method in [Decimation::Lttb, Decimation::MinMax] {
+            let decimated = apply(&frame, 50, method).unwrap();
+            assert!(decimated.height() > 50 && decimated.height() <= 100);
+            for name in ["acc_x", "acc_y"] {
+                let column = decimated.column(name).unwrap();
+                assert!(column.len() - column.null_count() <= 50);
+            }
+            assert_eq!(decimated.column("time").unwrap().null_count(), 0);
+        }
+        assert_eq!(apply(&frame, 1000, Decimation::Lttb).unwrap(), frame);
+    }
+}
@@ -0,0 +1,71 @@
+// A duration like "90s" or "1h30m", in units from "ns" up to "w". Calendar
+// units such as months have no fixed length and are not accepted.
+pub fn parse(text: &str) -> Result<chrono::Duration, String> {
+    let invalid = || format!("invalid duration \"{text}\", expected e.g. \"5m\" or \"1h\"");
+    let mut total = chrono::Duration::zero();
+    let mut rest = text.trim();
+    if rest.is_empty() {
+        return Err(invalid());
+    }
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .ok_or_else(invalid)?;
+        let units = rest[digits..]
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .map_or(rest.len(), |i| digits + i);
+        let count: i64 = rest[..digits].parse().map_err(|_| invalid())?;
+        let part = match &rest[digits..units] {
+            "ns" => Some(chrono::Duration::nanoseconds(count)),
+            "us" => Some(chrono::Duration::microseconds(count)),
+            "ms" => chrono::Duration::try_milliseconds(count),
+            "s" => chrono::Duration::try_seconds(count),
+            "m" => chrono::Duration::try_minutes(count),
+            "h" => chrono::Duration::try_hours(count),
+            "d" => chrono::Duration::try_days(count),
+            "w" => chrono::Duration::try_weeks(count),
+            _ => None,
+        };
+        total = part
+            .and_then(|part| total.checked_add(&part))
+            .ok_or_else(invalid)?;
+        rest = &rest[units..];
+    }
+    // Also bounded to what fits in nanoseconds, as the data's time does
+    if total <= chrono::Duration::zero() || total.num_nanoseconds().is_none() {
+        return Err(invalid());
+    }
+    Ok(total)
+}
+
+// The same duration for polars, e.g. for `group_by_dynamic`
+pub fn polars(duration: chrono::Duration) -> polars::time::Duration {
+    let nanoseconds = duration.num_nanoseconds().unwrap_or(i64::MAX);
+    polars::time::Duration::parse(&format!("{nanoseconds}ns"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse("5m"), Ok(chrono::Duration::minutes(5)));
+        assert_eq!(parse("1h30m"), Ok(chrono::Duration::minutes(90)));
+        assert_eq!(parse("250ms"), Ok(chrono::Duration::milliseconds(250)));
+        assert_eq!(parse("2d"), Ok(chrono::Duration::days(2)));
+        assert_eq!(
+            polars(chrono::Duration::seconds(10)).duration_ns(),
+            10_000_000_000
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_durations() {
+        for text in [
+            "", "5", "m", "abc", "5x", "-5m", "0s", "1.5h", "5m 1s", "1mo", "9999999w",
+        ] {
+            assert!(parse(text).is_err(), "{text}");
+        }
+    }
+}
@@ -0,0 +1,376 @@
+use crate::hello::{self, ChannelSpec};
+
+use polars::prelude::*;
+use rocket::{
+    serde::{Deserialize, Serialize},
+    FromFormField,
+};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct EventConfig {
+    // Value of 1 g in the unit the sensor reports acceleration in
+    pub gravity: f64,
+    // Acceleration magnitude (in g) below which the device is considered falling
+    pub free_fall_threshold: f64,
+    pub free_fall_min_ms: u64,
+    // Deviation from 1 g (in g) starting a peak, and peak magnitude (in g)
+    // separating shocks from taps
+    pub tap_threshold: f64,
+    pub tap_max_ms: u64,
+    pub shock_threshold: f64,
+    // Maximum deviation from 1 g (in g) and rotation rate while stationary
+    pub stationary_acc_tolerance: f64,
+    pub stationary_gyro_limit: f64,
+    pub stationary_min_ms: u64,
+}
+
+impl Default for EventConfig {
+    fn default() -> Self {
+        Self {
+            gravity: 9.81,
+            free_fall_threshold: 0.3,
+            free_fall_min_ms: 80,
+            tap_threshold: 1.0,
+            tap_max_ms: 60,
+            shock_threshold: 4.0,
+            stationary_acc_tolerance: 0.05,
+            stationary_gyro_limit: 5.0,
+            stationary_min_ms: 500,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, FromFormField)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum EventKind {
+    #[field(value = "free_fall")]
+    FreeFall,
+    Shock,
+    Tap,
+    Stationary,
+    Moving,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Event {
+    // Sample time of the event start in seconds
+    pub time: f64,
+    pub kind: EventKind,
+    // Peak or minimum acceleration magnitude in g, where meaningful
+    pub value: Option<f64>,
+    pub duration: Option<f64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Motion {
+    Stationary,
+    Moving,
+}
+
+// Detection state carried over between frames of one connection
+#[derive(Debug, Default)]
+pub struct Detector {
+    free_fall: Option<(i64, f64)>,
+    peak: Option<(i64, f64)>,
+    still_since: Option<i64>,
+    motion: Option<Motion>,
+}
+
+fn ms(ms: u64) -> i64 {
+    ms as i64 * 1_000_000
+}
+
+fn secs(ns: i64) -> f64 {
+    ns as f64 * 1.0e-9
+}
+
+// Magnitudes of the x, y and z channels of a sensor declared in `schema`
+fn magnitudes(frame: &DataFrame, schema: &[ChannelSpec], sensor: &str) -> Option<Vec<Option<f64>>> {
+    let axes = hello::axes(schema, sensor)?
+        .iter()
+        .map(|name| {
+            let values = frame.column(name).ok()?.cast(&DataType::Float64).ok()?;
+            Some(values.f64().ok()?.into_iter().collect::<Vec<_>>())
+        })
+        .collect::<Option<Vec<_>>>()?;
+
+    Some(
+        (0..frame.height())
+            .map(|i| match (axes[0][i], axes[1][i], axes[2][i]) {
+                (Some(x), Some(y), Some(z)) => Some((x * x + y * y + z * z).sqrt()),
+                _ => None,
+            })
+            .collect(),
+    )
+}
+
+impl Detector {
+    // Run all detectors over a newly received frame and return the events that
+    // were completed by it. Acceleration and rotation are read from the "acc"
+    // and "gyro" sensors in `schema`.
+    pub fn process(
+        &mut self,
+        frame: &DataFrame,
+        schema: &[ChannelSpec],
+        config: &EventConfig,
+    ) -> Vec<Event> {
+        let Some(times) = frame
+            .column("time")
+            .ok()
+            .and_then(|s| s.cast(&DataType::Int64).ok())
+            .map(|s| s.i64().map(|t| t.into_iter().collect::<Vec<_>>()))
+            .and_then(|t| t.ok())
+        else {
+            return Vec::new();
+        };
+        let Some(acc) = magnitudes(frame, schema, "acc") else {
+            return Vec::new();
+        };
+        let gyro =
+            magnitudes(frame, schema, "gyro").unwrap_or_else(|| vec![Some(0.0); frame.height()]);
+
+        let mut events = Vec::new();
+        for ((time, acc), gyro) in times.into_iter().zip(acc).zip(gyro) {
+            if let (Some(time), Some(acc)) = (time, acc) {
+                self.sample(
+                    time,
+                    acc / config.gravity,
+                    gyro.unwrap_or(0.0),
+                    config,
+                    &mut events,
+                );
+            }
+        }
+        events
+    }
+
+    fn sample(
+        &mut self,
+        time: i64,
+        acc: f64,
+        gyro: f64,
+        config: &EventConfig,
+        events: &mut Vec<Event>,
+    ) {
+        // Free-fall: acceleration magnitude close to zero for long enough
+        if acc < config.free_fall_threshold {
+            let (_, min) = self.free_fall.get_or_insert((time, acc));
+            *min = min.min(acc);
+        } else if let Some((start, min)) = self.free_fall.take() {
+            if time - start >= ms(config.free_fall_min_ms) {
+                events.push(Event {
+                    time: secs(start),
+                    kind: EventKind::FreeFall,
+                    value: Some(min),
+                    duration: Some(secs(time - start)),
+                });
+            }
+        }
+
+        // Shocks and taps: a short excursion away from 1 g
+        let deviation = (acc - 1.0).abs();
+        if deviation > config.tap_threshold {
+            let (_, peak) = self.peak.get_or_insert((time, acc));
+            *peak = peak.max(acc);
+        } else if let Some((start, peak)) = self.peak.take() {
+            let kind = if peak >= config.shock_threshold {
+                Some(EventKind::Shock)
+            } else if time - start <= ms(config.tap_max_ms) {
+                Some(EventKind::Tap)
+            } else {
+                None
+            };
+            if let Some(kind) = kind {
+                events.push(Event {
+                    time: secs(start),
+                    kind,
+                    value: Some(peak),
+                    duration: Some(secs(time - start)),
+                });
+            }
+        }
+
+        // Stationary/moving state changes
+        let still =
+            deviation < config.stationary_acc_tolerance && gyro < config.stationary_gyro_limit;
+        let change = if still {
+            let since = *self.still_since.get_or_insert(time);
+            (time - since >= ms(config.stationary_min_ms)).then_some((Motion::Stationary, since))
+        } else {
+            self.still_since = None;
+            Some((Motion::Moving, time))
+        };
+        if let Some((motion, at)) = change.filter(|(m, _)| self.motion != Some(*m)) {
+            self.motion = Some(motion);
+            events.push(Event {
+                time: secs(at),
+                kind: match motion {
+                    Motion::Stationary => EventKind::Stationary,
+                    Motion::Moving => EventKind::Moving,
+                },
+                value: None,
+                duration: None,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hello::Hello;
+
+    fn imu() -> Vec<ChannelSpec> {
+        Hello::legacy("a").schema()
+    }
+
+    // A frame with one sample every 10 ms from `start`, of the given
+    // acceleration along z in g and no rotation
+    fn frame(start: i64, acc: &[f64]) -> DataFrame {
+        let n = acc.len() as i64;
+        let time = Series::new(
+            "time",
+            (start..start + n)
+                .map(|i| i * 10_000_000)
+                .collect::<Vec<_>>(),
+        )
+        .cast(&DataType::Duration(TimeUnit::Nanoseconds))
+        .unwrap();
+        let zeros = vec![0.0; acc.len()];
+        let acc_z = acc.iter().map(|a| a * 9.81).collect::<Vec<_>>();
+        let mut columns = vec![time];
+        for (name, values) in [
+            ("acc_x", &zeros),
+            ("acc_y", &zeros),
+            ("acc_z", &acc_z),
+            ("gyro_x", &zeros),
+            ("gyro_y", &zeros),
+            ("gyro_z", &zeros),
+        ] {
+            columns.push(Series::new(name, values));
+        }
+        DataFrame::new(columns).unwrap()
+    }
+
+    // `count` samples of `value` g
+    fn hold(value: f64, count: usize) -> Vec<f64> {
+        vec![value; count]
+    }
+
+    fn detect(acc: &[f64], kind: EventKind) -> Vec<Event> {
+        Detector::default()
+            .process(&frame(0, acc), &imu(), &EventConfig::default())
+            .into_iter()
+            .filter(|e| e.kind == kind)
+            .collect()
+    }
+
+    #[test]
+    fn detects_free_fall_lasting_long_enough() {
+        let fall = [hold(1.0, 5), hold(0.1, 10), hold(1.0, 5)].concat();
+        let events = detect(&fall, EventKind::FreeFall);
+        assert_eq!(events.len(), 1);
+        assert!((events[0].time - 0.05).abs() < 1e-9);
+        assert!((events[0].duration.unwrap() - 0.1).abs() < 1e-9);
+        assert!((events[0].value.unwrap() - 0.1).abs() < 1e-9);
+
+        // Shorter than free_fall_min_ms
+        let drop = [hold(1.0, 5), hold(0.1, 5), hold(1.0, 5)].concat();
+        assert!(detect(&drop, EventKind::FreeFall).is_empty());
+    }
+
+    #[test]
+    fn tells_shocks_from_taps() {
+        let shock = [hold(1.0, 5), hold(6.0, 1), hold(1.0, 5)].concat();
+        let events = detect(&shock, EventKind::Shock);
+        assert_eq!(events.len(), 1);
+        assert!((events[0].value.unwrap() - 6.0).abs() < 1e-9);
+        assert!(detect(&shock, EventKind::Tap).is_empty());
+
+        let tap = [hold(1.0, 5), hold(2.5, 2), hold(1.0, 5)].concat();
+        assert_eq!(detect(&tap, EventKind::Tap).len(), 1);
+        assert!(detect(&tap, EventKind::Shock).is_empty());
+
+        // Longer than tap_max_ms and below the shock threshold
+        let sway = [hold(1.0, 5), hold(2.5, 10), hold(1.0, 5)].concat();
+        assert!(detect(&sway, EventKind::Tap).is_empty());
+        assert!(detect(&sway, EventKind::Shock).is_empty());
+
+        // Within the tap threshold of 1 g
+        let bump = [hold(1.0, 5), hold(1.8, 2), hold(1.0, 5)].concat();
+        assert!(detect(&bump, EventKind::Tap).is_empty());
+    }
+
+    #[test]
+    fn reports_stationary_after_the_minimum_time() {
+        let still = [hold(1.5, 1), hold(1.0, 60), hold(1.5, 1)].concat();
+        let events =
+            Detector::default().process(&frame(0, &still), &imu(), &EventConfig::default());
+        let kinds = events.iter().map(|e| e.kind).collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            [EventKind::Moving, EventKind::Stationary, EventKind::Moving]
+        );
+        assert!((events[1].time - 0.01).abs() < 1e-9);
+
+        // Not for as long as stationary_min_ms
+        let brief = [hold(1.5, 1), hold(1.0, 30), hold(1.5, 1)].concat();
+        assert!(detect(&brief, EventKind::Stationary).is_empty());
+    }
+
+    #[test]
+    fn carries_events_over_frames() {
+        let config = EventConfig::default();
+        let mut detector = Detector::default();
+        let first = detector.process(
+            &frame(0, &[hold(1.0, 5), hold(0.1, 5)].concat()),
+            &imu(),
+            &config,
+        );
+        assert!(first.iter().all(|e| e.kind != EventKind::FreeFall));
+        let second = detector.process(
+            &frame(10, &[hold(0.1, 5), hold(1.0, 1)].concat()),
+            &imu(),
+            &config,
+        );
+        let falls = second
+            .iter()
+            .filter(|e| e.kind == EventKind::FreeFall)
+            .collect::<Vec<_>>();
+        assert_eq!(falls.len(), 1);
+        assert!((falls[0].duration.unwrap() - 0.1).abs() < 1e-9);
+    }
+
+    #[test]
+    fn needs_acceleration() {
+        let frame = frame(0, &hold(0.0, 20)).drop("acc_z").unwrap();
+        assert!(Detector::default()
+            .process(&frame, &imu(), &EventConfig::default())
+            .is_empty());
+    }
+
+    #[test]
+    fn reads_the_sensors_declared_in_the_schema() {
+        let mut frame = frame(0, &[hold(1.0, 5), hold(0.1, 10), hold(1.0, 1)].concat());
+        for (old, new) in [("acc_x", "ax"), ("acc_y", "ay"), ("acc_z", "az")] {
+            frame.rename(old, new).unwrap();
+        }
+        let (hello, _) = Hello::parse(
+            r#"{ "device_id": "a", "protocol": 2, "channels": [
+                { "name": "ax", "sensor": "acc", "axis": "x" },
+                { "name": "ay", "sensor": "acc", "axis": "y" },
+                { "name": "az", "sensor": "acc", "axis": "z" }
+            ] }"#,
+        );
+        let schema = hello.unwrap().schema();
+        let config = EventConfig::default();
+
+        let events = Detector::default().process(&frame, &schema, &config);
+        assert!(events.iter().any(|e| e.kind == EventKind::FreeFall));
+        assert!(Detector::default()
+            .process(&frame, &imu(), &config)
+            .is_empty());
+    }
+}
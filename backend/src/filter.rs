@@ -0,0 +1,539 @@
+use polars::prelude::*;
+use rocket::{
+    futures::lock::Mutex,
+    serde::{Deserialize, Serialize},
+};
+use std::collections::{HashMap, VecDeque};
+use std::f64::consts::PI;
+
+fn order() -> usize {
+    2
+}
+
+fn gravity_cutoff() -> f64 {
+    0.3
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", tag = "type", rename_all = "snake_case")]
+pub enum Filter {
+    // Butterworth filters, cutoff frequencies in Hz
+    LowPass {
+        cutoff: f64,
+        #[serde(default = "order")]
+        order: usize,
+    },
+    HighPass {
+        cutoff: f64,
+        #[serde(default = "order")]
+        order: usize,
+    },
+    BandPass {
+        low: f64,
+        high: f64,
+        #[serde(default = "order")]
+        order: usize,
+    },
+    // Window sizes in samples
+    MovingAverage {
+        window: usize,
+    },
+    Median {
+        window: usize,
+    },
+    // Subtract the slowly varying (gravity) component below the cutoff
+    GravityRemoval {
+        #[serde(default = "gravity_cutoff")]
+        cutoff: f64,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum Stage {
+    // Filter samples as they arrive and store the result
+    Ingest,
+    // Filter the stored raw samples whenever they are requested
+    #[default]
+    Query,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct FilterChain {
+    // Channels to filter, all floating point channels if empty
+    #[serde(default)]
+    pub channels: Vec<String>,
+    pub filters: Vec<Filter>,
+    #[serde(default)]
+    pub stage: Stage,
+}
+
+impl Filter {
+    // Cutoff frequencies in Hz
+    fn cutoffs(&self) -> Vec<f64> {
+        match *self {
+            Self::LowPass { cutoff, .. }
+            | Self::HighPass { cutoff, .. }
+            | Self::GravityRemoval { cutoff } => vec![cutoff],
+            Self::BandPass { low, high, .. } => vec![low, high],
+            Self::MovingAverage { .. } | Self::Median { .. } => Vec::new(),
+        }
+    }
+
+    fn validate(&self, sample_rate: Option<f64>) -> Result<(), String> {
+        match *self {
+            Self::LowPass { order, .. }
+            | Self::HighPass { order, .. }
+            | Self::BandPass { order, .. }
+                if !(1..=8).contains(&order) =>
+            {
+                return Err(format!("order {order} is not between 1 and 8"));
+            }
+            Self::BandPass { low, high, .. } if low >= high => {
+                return Err(format!(
+                    "band pass low {low} Hz is not below high {high} Hz"
+                ));
+            }
+            Self::MovingAverage { window: 0 } | Self::Median { window: 0 } => {
+                return Err(String::from("window must be at least one sample"));
+            }
+            _ => {}
+        }
+        for cutoff in self.cutoffs() {
+            if !(cutoff > 0.0 && cutoff.is_finite()) {
+                return Err(format!("cutoff {cutoff} Hz is not positive"));
+            }
+            if let Some(sample_rate) = sample_rate.filter(|r| cutoff >= r / 2.0) {
+                return Err(format!(
+                    "cutoff {cutoff} Hz is not below the Nyquist frequency of {} Hz",
+                    sample_rate / 2.0
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FilterChain {
+    // Check the parameters of every filter, and the cutoffs against the
+    // Nyquist frequency if the device's sample rate is known
+    pub fn validate(&self, sample_rate: Option<f64>) -> Result<(), String> {
+        self.filters
+            .iter()
+            .try_for_each(|filter| filter.validate(sample_rate))
+    }
+}
+
+#[derive(Clone)]
+pub struct Filters {
+    chains: Arc<Mutex<HashMap<String, FilterChain>>>,
+}
+
+impl Filters {
+    pub fn new() -> Self {
+        Self {
+            chains: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub async fn get(&self, id: &str) -> Option<FilterChain> {
+        self.chains.lock().await.get(id).cloned()
+    }
+
+    pub async fn set(&self, id: &str, chain: FilterChain) {
+        self.chains.lock().await.insert(id.to_string(), chain);
+    }
+
+    pub async fn remove(&self, id: &str) -> Option<FilterChain> {
+        self.chains.lock().await.remove(id)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 3]) -> Self {
+        Self {
+            b: [b[0] / a[0], b[1] / a[0], b[2] / a[0]],
+            a: [a[1] / a[0], a[2] / a[0]],
+            x: [0.0; 2],
+            y: [0.0; 2],
+        }
+    }
+
+    fn second_order(sample_rate: f64, cutoff: f64, q: f64, high_pass: bool) -> Self {
+        let w0 = 2.0 * PI * cutoff / sample_rate;
+        let alpha = w0.sin() / (2.0 * q);
+        let cos = w0.cos();
+        let b = if high_pass {
+            [(1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0]
+        } else {
+            [(1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0]
+        };
+        Self::new(b, [1.0 + alpha, -2.0 * cos, 1.0 - alpha])
+    }
+
+    fn first_order(sample_rate: f64, cutoff: f64, high_pass: bool) -> Self {
+        let k = (PI * cutoff / sample_rate).tan();
+        let b = if high_pass {
+            [1.0, -1.0, 0.0]
+        } else {
+            [k, k, 0.0]
+        };
+        Self::new(b, [1.0 + k, k - 1.0, 0.0])
+    }
+
+    // Cascade of sections forming a Butterworth filter of the given order
+    fn butterworth(sample_rate: f64, cutoff: f64, order: usize, high_pass: bool) -> Vec<Self> {
+        let order = order.clamp(1, 8);
+        let cutoff = cutoff.clamp(1.0e-6, 0.45 * sample_rate);
+        let mut sections = (0..order / 2)
+            .map(|k| {
+                let q = 1.0 / (2.0 * (PI * (2 * k + 1) as f64 / (2 * order) as f64).cos());
+                Self::second_order(sample_rate, cutoff, q, high_pass)
+            })
+            .collect::<Vec<_>>();
+        if order % 2 == 1 {
+            sections.push(Self::first_order(sample_rate, cutoff, high_pass));
+        }
+        sections
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[0] * self.y[0]
+            - self.a[1] * self.y[1];
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+}
+
+#[derive(Debug, Clone)]
+enum StageState {
+    Sections(Vec<Biquad>),
+    MovingAverage(usize, VecDeque<f64>),
+    Median(usize, VecDeque<f64>),
+    GravityRemoval(Vec<Biquad>),
+}
+
+impl StageState {
+    fn new(filter: &Filter, sample_rate: f64) -> Self {
+        match *filter {
+            Filter::LowPass { cutoff, order } => {
+                Self::Sections(Biquad::butterworth(sample_rate, cutoff, order, false))
+            }
+            Filter::HighPass { cutoff, order } => {
+                Self::Sections(Biquad::butterworth(sample_rate, cutoff, order, true))
+            }
+            Filter::BandPass { low, high, order } => Self::Sections(
+                Biquad::butterworth(sample_rate, low, order, true)
+                    .into_iter()
+                    .chain(Biquad::butterworth(sample_rate, high, order, false))
+                    .collect(),
+            ),
+            Filter::MovingAverage { window } => Self::MovingAverage(window.max(1), VecDeque::new()),
+            Filter::Median { window } => Self::Median(window.max(1), VecDeque::new()),
+            Filter::GravityRemoval { cutoff } => {
+                Self::GravityRemoval(Biquad::butterworth(sample_rate, cutoff, 2, false))
+            }
+        }
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        match self {
+            Self::Sections(sections) => sections.iter_mut().fold(x, |x, s| s.process(x)),
+            Self::MovingAverage(window, buffer) => {
+                buffer.push_back(x);
+                if buffer.len() > *window {
+                    buffer.pop_front();
+                }
+                buffer.iter().sum::<f64>() / buffer.len() as f64
+            }
+            Self::Median(window, buffer) => {
+                buffer.push_back(x);
+                if buffer.len() > *window {
+                    buffer.pop_front();
+                }
+                let mut sorted = buffer.iter().copied().collect::<Vec<_>>();
+                sorted.sort_by(|a, b| a.total_cmp(b));
+                sorted[sorted.len() / 2]
+            }
+            Self::GravityRemoval(sections) => x - sections.iter_mut().fold(x, |x, s| s.process(x)),
+        }
+    }
+}
+
+// Filter state of a single channel, kept between frames when filtering at ingest
+#[derive(Debug, Clone)]
+pub struct ChannelFilter {
+    stages: Vec<StageState>,
+}
+
+impl ChannelFilter {
+    fn new(filters: &[Filter], sample_rate: f64) -> Self {
+        Self {
+            stages: filters
+                .iter()
+                .map(|f| StageState::new(f, sample_rate))
+                .collect(),
+        }
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        // Missing samples pass through without disturbing the filter state
+        if x.is_nan() {
+            return x;
+        }
+        self.stages.iter_mut().fold(x, |x, s| s.process(x))
+    }
+}
+
+// Filter state of a connection filtering at ingest
+#[derive(Debug, Clone)]
+pub struct FilterState {
+    chain: FilterChain,
+    sample_rate: f64,
+    channels: HashMap<String, ChannelFilter>,
+}
+
+impl FilterState {
+    pub fn matches(&self, chain: &FilterChain) -> bool {
+        self.chain == *chain
+    }
+}
+
+pub fn sample_rate(frame: &DataFrame) -> Option<f64> {
+    let times = frame.column("time").ok()?.cast(&DataType::Int64).ok()?;
+    let times = times.i64().ok()?.into_iter().flatten().collect::<Vec<_>>();
+    let mut steps = times
+        .windows(2)
+        .map(|w| w[1] - w[0])
+        .filter(|s| *s > 0)
+        .collect::<Vec<_>>();
+    steps.sort_unstable();
+    steps.get(steps.len() / 2).map(|step| 1.0e9 / *step as f64)
+}
+
+fn channels(frame: &DataFrame, chain: &FilterChain) -> Vec<String> {
+    if chain.channels.is_empty() {
+        frame
+            .get_columns()
+            .iter()
+            .filter(|s| s.dtype().is_float() && !s.name().ends_with("_filtered"))
+            .map(|s| s.name().to_string())
+            .collect()
+    } else {
+        chain.channels.clone()
+    }
+}
+
+fn apply_with(
+    mut frame: DataFrame,
+    chain: &FilterChain,
+    sample_rate: f64,
+    state: &mut HashMap<String, ChannelFilter>,
+) -> PolarsResult<DataFrame> {
+    for name in channels(&frame, chain) {
+        let Ok(values) = frame.column(&name).and_then(|s| s.cast(&DataType::Float64)) else {
+            continue;
+        };
+        let filter = state
+            .entry(name.clone())
+            .or_insert_with(|| ChannelFilter::new(&chain.filters, sample_rate));
+        let filtered = values
+            .f64()?
+            .into_iter()
+            .map(|v| v.map(|v| filter.process(v)))
+            .collect::<Float64Chunked>()
+            .with_name(&format!("{name}_filtered"));
+        frame.with_column(filtered.into_series())?;
+    }
+    Ok(frame)
+}
+
+// Add a "<channel>_filtered" column for every filtered channel of a complete,
+// time ordered frame
+pub fn apply(frame: DataFrame, chain: &FilterChain) -> PolarsResult<DataFrame> {
+    let Some(sample_rate) = sample_rate(&frame) else {
+        return Ok(frame);
+    };
+    apply_with(frame, chain, sample_rate, &mut HashMap::new())
+}
+
+// Filter a newly received frame, continuing from the state left by the
+// previous frames of the same connection. `period` is the connection's sample
+// period in nanoseconds so far, as frames may be too short to tell it.
+pub fn apply_incremental(
+    frame: DataFrame,
+    chain: &FilterChain,
+    state: &mut Option<FilterState>,
+    period: Option<i64>,
+) -> PolarsResult<DataFrame> {
+    if !state.as_ref().is_some_and(|s| s.matches(chain)) {
+        let sample_rate = period
+            .map(|period| 1.0e9 / period as f64)
+            .or_else(|| sample_rate(&frame));
+        let Some(sample_rate) = sample_rate else {
+            // A first sample, nothing to tell the sample rate from yet
+            return Ok(frame);
+        };
+        *state = Some(FilterState {
+            chain: chain.clone(),
+            sample_rate,
+            channels: HashMap::new(),
+        });
+    }
+    let state = state.as_mut().unwrap();
+    apply_with(frame, chain, state.sample_rate, &mut state.channels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Samples at 100 Hz starting at `start`
+    fn frame(start: usize, values: &[f64]) -> DataFrame {
+        let time = Series::new(
+            "time",
+            (start..start + values.len())
+                .map(|i| i as i64 * 10_000_000)
+                .collect::<Vec<_>>(),
+        )
+        .cast(&DataType::Duration(TimeUnit::Nanoseconds))
+        .unwrap();
+        DataFrame::new(vec![time, Series::new("acc_x", values)]).unwrap()
+    }
+
+    fn chain(filters: Vec<Filter>) -> FilterChain {
+        FilterChain {
+            channels: Vec::new(),
+            filters,
+            stage: Stage::Ingest,
+        }
+    }
+
+    fn filtered(frame: &DataFrame) -> Vec<f64> {
+        frame
+            .column("acc_x_filtered")
+            .unwrap()
+            .f64()
+            .unwrap()
+            .into_no_null_iter()
+            .collect()
+    }
+
+    fn sine(frequency: f64, samples: usize) -> Vec<f64> {
+        (0..samples)
+            .map(|i| (2.0 * PI * frequency * i as f64 / 100.0).sin())
+            .collect()
+    }
+
+    #[test]
+    fn rejects_invalid_parameters() {
+        let invalid = [
+            Filter::BandPass {
+                low: 5.0,
+                high: 2.0,
+                order: 2,
+            },
+            Filter::LowPass {
+                cutoff: 0.0,
+                order: 2,
+            },
+            Filter::HighPass {
+                cutoff: f64::NAN,
+                order: 2,
+            },
+            Filter::LowPass {
+                cutoff: 10.0,
+                order: 0,
+            },
+            Filter::LowPass {
+                cutoff: 50.0,
+                order: 2,
+            },
+            Filter::MovingAverage { window: 0 },
+            Filter::Median { window: 0 },
+        ];
+        for filter in invalid {
+            assert!(
+                chain(vec![filter.clone()]).validate(Some(100.0)).is_err(),
+                "{filter:?}"
+            );
+        }
+
+        let valid = chain(vec![
+            Filter::BandPass {
+                low: 1.0,
+                high: 10.0,
+                order: 4,
+            },
+            Filter::Median { window: 5 },
+        ]);
+        assert!(valid.validate(Some(100.0)).is_ok());
+        // Without a known sample rate only the Nyquist check is skipped
+        let high = chain(vec![Filter::LowPass {
+            cutoff: 80.0,
+            order: 2,
+        }]);
+        assert!(high.validate(None).is_ok());
+    }
+
+    #[test]
+    fn low_pass_attenuates_high_frequencies() {
+        let chain = chain(vec![Filter::LowPass {
+            cutoff: 2.0,
+            order: 4,
+        }]);
+        let slow = filtered(&apply(frame(0, &sine(0.5, 1000)), &chain).unwrap());
+        let fast = filtered(&apply(frame(0, &sine(30.0, 1000)), &chain).unwrap());
+        let peak = |values: &[f64]| values[500..].iter().fold(0.0_f64, |m, v| m.max(v.abs()));
+        assert!(peak(&slow) > 0.9);
+        assert!(peak(&fast) < 0.01);
+    }
+
+    #[test]
+    fn median_removes_spikes() {
+        let chain = chain(vec![Filter::Median { window: 3 }]);
+        let values = [1.0, 1.0, 9.0, 1.0, 1.0];
+        assert_eq!(
+            filtered(&apply(frame(0, &values), &chain).unwrap()),
+            [1.0, 1.0, 1.0, 1.0, 1.0]
+        );
+    }
+
+    #[test]
+    fn filters_single_sample_frames_like_a_whole_frame() {
+        let chain = chain(vec![Filter::LowPass {
+            cutoff: 5.0,
+            order: 2,
+        }]);
+        let values = sine(10.0, 50);
+        // The state starts with the second sample, when the period is known
+        let whole = filtered(&apply(frame(1, &values[1..]), &chain).unwrap());
+
+        let mut state = None;
+        let mut incremental = Vec::new();
+        for (i, value) in values.iter().enumerate() {
+            let period = (i > 0).then_some(10_000_000);
+            let frame = apply_incremental(frame(i, &[*value]), &chain, &mut state, period).unwrap();
+            if i > 0 {
+                incremental.extend(filtered(&frame));
+            }
+        }
+        // The first sample arrives before the sample rate is known
+        assert_eq!(incremental.len(), values.len() - 1);
+        assert!(state.is_some());
+        for (a, b) in incremental.iter().zip(&whole) {
+            assert!((a - b).abs() < 1.0e-9);
+        }
+    }
+}
@@ -0,0 +1,87 @@
+use polars::prelude::*;
+use rocket::{
+    http::{Accept, ContentType, Header, MediaType, Status},
+    response::{self, Responder, Response},
+    serde::json::Json,
+    Request,
+};
+use std::io::Cursor;
+
+// Media type of the Arrow IPC streaming format
+pub fn arrow_stream() -> MediaType {
+    MediaType::new("application", "vnd.apache.arrow.stream")
+}
+
+// Whether the client asked for an Arrow stream with a q-value above zero and
+// at least that of JSON, which `*/*` and `application/*` also stand for
+fn prefers_arrow(accept: &Accept) -> bool {
+    let weight = |matches: &dyn Fn(&MediaType) -> bool| {
+        accept
+            .iter()
+            .filter(|m| matches(m.media_type()))
+            .map(|m| m.weight_or(1.0))
+            .fold(0.0, f32::max)
+    };
+    let arrow = arrow_stream();
+    let arrow_weight = weight(&|m: &MediaType| *m == arrow);
+    let json_weight = weight(&|m: &MediaType| {
+        m.top() == "*" || (m.top() == "application" && (m.sub() == "*" || m.sub() == "json"))
+    });
+    arrow_weight > 0.0 && arrow_weight >= json_weight
+}
+
+// A frame sent as Arrow IPC stream to clients that accept it, which is far
+// cheaper to decode than JSON, and as JSON otherwise
+pub struct DataResponse(pub DataFrame);
+
+impl<'r> Responder<'r, 'static> for DataResponse {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        if !request.accept().is_some_and(prefers_arrow) {
+            return Response::build_from(Json(self.0).respond_to(request)?)
+                .raw_header("Vary", "Accept")
+                .ok();
+        }
+
+        let mut frame = self.0;
+        let mut bytes = Vec::new();
+        IpcStreamWriter::new(&mut bytes)
+            .finish(&mut frame)
+            .map_err(|e| {
+                log::warn!("Failed to encode data as Arrow: {e}");
+                Status::InternalServerError
+            })?;
+        Response::build()
+            .header(ContentType(arrow_stream()))
+            .header(Header::new("Vary", "Accept"))
+            .sized_body(bytes.len(), Cursor::new(bytes))
+            .ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prefers(accept: &str) -> bool {
+        prefers_arrow(&accept.parse::<Accept>().unwrap())
+    }
+
+    #[test]
+    fn honours_q_values() {
+        assert!(prefers("application/vnd.apache.arrow.stream"));
+        assert!(prefers("application/vnd.apache.arrow.stream, */*;q=0.5"));
+        assert!(prefers(
+            "application/json;q=0.5, application/vnd.apache.arrow.stream"
+        ));
+
+        assert!(!prefers("application/json"));
+        assert!(!prefers("*/*"));
+        assert!(!prefers("application/vnd.apache.arrow.stream;q=0"));
+        assert!(!prefers(
+            "application/vnd.apache.arrow.stream;q=0.5, application/json"
+        ));
+        assert!(!prefers(
+            "application/vnd.apache.arrow.stream;q=0.5, application/*"
+        ));
+    }
+}
@@ -7,12 +7,22 @@ pub trait Frame {
     fn frame(&self) -> Result<DataFrame, Self::Error>;
 }
 
+// The time of a sample, in seconds since the device started. Devices are not
+// trusted to send a finite, non-negative number that fits in nanoseconds.
+fn time(seconds: f32) -> PolarsResult<chrono::Duration> {
+    StdDuration::try_from_secs_f32(seconds)
+        .ok()
+        .and_then(|time| chrono::Duration::from_std(time).ok())
+        .filter(|time| time.num_nanoseconds().is_some())
+        .ok_or_else(|| polars_err!(ComputeError: "invalid sample time {}", seconds))
+}
+
 impl Frame for proto::SensorData {
     type Error = PolarsError;
 
     fn frame(&self) -> Result<DataFrame, PolarsError> {
         df!(
-            "time" => self.samples.iter().map(|s| chrono::Duration::from_std(StdDuration::from_secs_f32(s.time)).unwrap()).collect::<Vec<_>>(),
+            "time" => self.samples.iter().map(|s| time(s.time)).collect::<PolarsResult<Vec<_>>>()?,
             "acc_x" => self.samples.iter().map(|s| s.acceleration.x).collect::<Vec<_>>(),
             "acc_y" => self.samples.iter().map(|s| s.acceleration.y).collect::<Vec<_>>(),
             "acc_z" => self.samples.iter().map(|s| s.acceleration.z).collect::<Vec<_>>(),
@@ -25,3 +35,28 @@ impl Frame for proto::SensorData {
         )
     }
 }
+
+// Samples at the given times, all zero, as a device would send them
+#[cfg(test)]
+pub fn sensor_data(times: &[f32]) -> proto::SensorData {
+    let mut data = proto::SensorData::default();
+    data.samples.resize_with(times.len(), Default::default);
+    for (sample, time) in data.samples.iter_mut().zip(times) {
+        sample.time = *time;
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_invalid_sample_times() {
+        let frame = sensor_data(&[0.0, 0.5]).frame().unwrap();
+        assert_eq!(frame.height(), 2);
+        for time in [-1.0, f32::NAN, f32::INFINITY, 1.0e10, f32::MAX] {
+            assert!(sensor_data(&[0.0, time]).frame().is_err(), "{time}");
+        }
+    }
+}
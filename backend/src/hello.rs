@@ -0,0 +1,577 @@
+use crate::frame::Frame;
+
+use hecate_protobuf as proto;
+use polars::prelude::*;
+use proto::Message;
+use rocket::serde::{json, Deserialize, Serialize};
+
+// Versions of the device protocol, i.e. of the layout of binary messages,
+// this server understands. Version 1 is `SensorData` with its fixed IMU
+// channels, version 2 is `wire::ChannelData` with the channels declared in the
+// hello.
+pub const PROTOCOL_VERSIONS: std::ops::RangeInclusive<u32> = 1..=2;
+
+pub mod wire {
+    use prost::Message;
+
+    // Samples of the declared channels. `channels[i]` holds the values of the
+    // i-th declared channel, one per entry in `time`.
+    #[derive(Clone, PartialEq, Message)]
+    pub struct ChannelData {
+        // Seconds
+        #[prost(double, repeated, tag = "1")]
+        pub time: Vec<f64>,
+        #[prost(message, repeated, tag = "2")]
+        pub channels: Vec<Values>,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    pub struct Values {
+        #[prost(double, repeated, tag = "1")]
+        pub values: Vec<f64>,
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum ChannelType {
+    #[default]
+    F32,
+    F64,
+    I64,
+    Bool,
+}
+
+impl ChannelType {
+    pub fn dtype(self) -> DataType {
+        match self {
+            Self::F32 => DataType::Float32,
+            Self::F64 => DataType::Float64,
+            Self::I64 => DataType::Int64,
+            Self::Bool => DataType::Boolean,
+        }
+    }
+
+    pub fn of(dtype: &DataType) -> Option<Self> {
+        match dtype {
+            DataType::Float32 => Some(Self::F32),
+            DataType::Float64 => Some(Self::F64),
+            DataType::Boolean => Some(Self::Bool),
+            dtype if dtype.is_integer() => Some(Self::I64),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ChannelSpec {
+    pub name: String,
+    #[serde(default)]
+    pub unit: Option<String>,
+    #[serde(rename = "type", default)]
+    pub kind: ChannelType,
+    // The sensor the channel belongs to, e.g. "acc", "mag" or "gyro", and its
+    // axis "x", "y" or "z". Saturation, events and magnitude alerts find the
+    // channels of a sensor through these rather than by name.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sensor: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub axis: Option<String>,
+}
+
+// Names of the x, y and z channels of a sensor in a schema, if it has all three
+pub fn axes<'a>(schema: &'a [ChannelSpec], sensor: &str) -> Option<[&'a str; 3]> {
+    let axis = |axis: &str| {
+        schema
+            .iter()
+            .find(|c| c.sensor.as_deref() == Some(sensor) && c.axis.as_deref() == Some(axis))
+            .map(|c| c.name.as_str())
+    };
+    Some([axis("x")?, axis("y")?, axis("z")?])
+}
+
+// Whether a channel may be called `name`. "time", "quality" and the
+// "_filtered" columns are taken by the server.
+pub fn valid_channel_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !matches!(name, "time" | "quality")
+        && !name.ends_with("_filtered")
+}
+
+// Channels of a stored frame, for devices that did not declare any
+pub fn channels_of(frame: &DataFrame) -> Vec<ChannelSpec> {
+    frame
+        .get_columns()
+        .iter()
+        .filter(|s| !matches!(s.name(), "time" | "quality") && !s.name().ends_with("_filtered"))
+        .filter_map(|s| {
+            Some(ChannelSpec {
+                name: s.name().to_string(),
+                unit: None,
+                kind: ChannelType::of(s.dtype())?,
+                sensor: None,
+                axis: None,
+            })
+        })
+        .collect()
+}
+
+// Where the id of a device on the WebSocket comes from
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum DeviceIdentity {
+    // Trust the id in the hello
+    #[default]
+    Hello,
+    // The common name of the client certificate, which the hello may leave out
+    Certificate,
+}
+
+impl DeviceIdentity {
+    // The hello with the id of the device. With certificates the id is the
+    // common name, which the hello may leave out but otherwise must match.
+    pub fn verify(self, mut hello: Hello, common_name: Option<&str>) -> Result<Hello, String> {
+        match (self, common_name) {
+            (Self::Hello, _) => {}
+            (Self::Certificate, None) => return Err(String::from("client certificate required")),
+            (Self::Certificate, Some(name)) if hello.device_id.is_empty() => {
+                hello.device_id = name.to_string();
+            }
+            (Self::Certificate, Some(name)) if name != hello.device_id => {
+                return Err(format!(
+                    "device id '{}' does not match client certificate '{name}'",
+                    hello.device_id
+                ))
+            }
+            (Self::Certificate, Some(_)) => {}
+        }
+        if hello.device_id.is_empty() || hello.device_id.chars().any(char::is_control) {
+            return Err(String::from("invalid device id"));
+        }
+        if hello.device_id.len() > MAX_DEVICE_ID {
+            return Err(format!("device id longer than {MAX_DEVICE_ID} bytes"));
+        }
+        Ok(hello)
+    }
+}
+
+// The longest device id a hello may carry, in bytes
+pub const MAX_DEVICE_ID: usize = 128;
+
+fn protocol() -> u32 {
+    1
+}
+
+// The longest reason a WebSocket close frame can carry, in bytes
+const MAX_CLOSE_REASON: usize = 123;
+
+// An error as the reason of a close frame, cut to fit on a character boundary.
+// The hello reply carries the full text.
+pub fn close_reason(error: &str) -> String {
+    let mut end = error.len().min(MAX_CLOSE_REASON);
+    while !error.is_char_boundary(end) {
+        end -= 1;
+    }
+    error[..end].to_string()
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct SensorCapability {
+    // "acceleration", "magnetometer", "gyroscope", ...
+    pub kind: String,
+    #[serde(default)]
+    pub model: Option<String>,
+    // Unit the sensor reports in, e.g. "m/s^2", "uT" or "dps"
+    #[serde(default)]
+    pub unit: Option<String>,
+    // Full scale range in that unit
+    #[serde(default)]
+    pub range: Option<f64>,
+    // Sample rates in Hz the sensor supports
+    #[serde(default)]
+    pub sample_rates: Vec<f64>,
+}
+
+// First message of a device on the WebSocket, as JSON text
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Hello {
+    // May be left out when the id is taken from the client certificate
+    #[serde(default)]
+    pub device_id: String,
+    #[serde(default)]
+    pub firmware: Option<String>,
+    #[serde(default = "protocol")]
+    pub protocol: u32,
+    #[serde(default)]
+    pub sensors: Vec<SensorCapability>,
+    // Channels of the binary messages, required from protocol version 2 on
+    #[serde(default)]
+    pub channels: Vec<ChannelSpec>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct HelloReply {
+    pub accepted: bool,
+    pub protocol: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Hello {
+    // Devices predating the handshake only send their id
+    pub fn legacy(id: &str) -> Self {
+        Self {
+            device_id: id.to_string(),
+            firmware: None,
+            protocol: 1,
+            sensors: ["acceleration", "magnetometer", "gyroscope"]
+                .into_iter()
+                .map(|kind| SensorCapability {
+                    kind: kind.to_string(),
+                    model: None,
+                    unit: None,
+                    range: None,
+                    sample_rates: Vec::new(),
+                })
+                .collect(),
+            channels: Vec::new(),
+        }
+    }
+
+    // Parse the first text message of a device. The second value tells
+    // whether the device expects a reply, which legacy devices do not.
+    pub fn parse(text: &str) -> (Result<Self, String>, bool) {
+        let text = text.trim();
+        if !text.starts_with('{') {
+            let hello = Self::legacy(text);
+            return (hello.validate().map(|_| hello), false);
+        }
+        let hello = json::from_str::<Self>(text)
+            .map_err(|e| format!("invalid hello: {e}"))
+            .and_then(|hello| hello.validate().map(|_| hello));
+        (hello, true)
+    }
+
+    // Everything but the device id, which DeviceIdentity::verify checks
+    fn validate(&self) -> Result<(), String> {
+        if !PROTOCOL_VERSIONS.contains(&self.protocol) {
+            return Err(format!(
+                "unsupported protocol version {}, supported are {} to {}",
+                self.protocol,
+                PROTOCOL_VERSIONS.start(),
+                PROTOCOL_VERSIONS.end()
+            ));
+        }
+        if let Some(sensor) = self
+            .sensors
+            .iter()
+            .find(|s| s.kind.is_empty() || s.sample_rates.iter().any(|r| !(*r > 0.0)))
+        {
+            return Err(format!("invalid capabilities of sensor '{}'", sensor.kind));
+        }
+
+        if self.protocol >= 2 && self.channels.is_empty() {
+            return Err(format!(
+                "protocol version {} requires channels",
+                self.protocol
+            ));
+        }
+        let mut names = std::collections::HashSet::new();
+        let mut axes = std::collections::HashSet::new();
+        for channel in &self.channels {
+            if !valid_channel_name(&channel.name) || !names.insert(channel.name.as_str()) {
+                return Err(format!("invalid channel name '{}'", channel.name));
+            }
+            let valid = match (&channel.sensor, &channel.axis) {
+                (Some(sensor), axis) => {
+                    valid_channel_name(sensor)
+                        && axis.as_ref().map_or(true, |a| {
+                            matches!(a.as_str(), "x" | "y" | "z") && axes.insert((sensor, a))
+                        })
+                }
+                (None, axis) => axis.is_none(),
+            };
+            if !valid {
+                return Err(format!("invalid sensor axis of channel '{}'", channel.name));
+            }
+        }
+        Ok(())
+    }
+
+    // A hello sent on a transport that already names the device, such as the
+    // MQTT topic or the envelope, which the hello may then leave out
+    pub fn announced(id: &str, text: &str) -> Result<Self, String> {
+        let mut hello = Self::parse(text).0?;
+        if hello.device_id.is_empty() {
+            hello.device_id = id.to_string();
+        }
+        if hello.device_id != id {
+            return Err(format!(
+                "device id '{}' does not match '{id}'",
+                hello.device_id
+            ));
+        }
+        Ok(hello)
+    }
+
+    pub fn reply(result: &Result<Self, String>) -> String {
+        let reply = match result {
+            Ok(hello) => HelloReply {
+                accepted: true,
+                protocol: hello.protocol,
+                error: None,
+            },
+            Err(e) => HelloReply {
+                accepted: false,
+                protocol: *PROTOCOL_VERSIONS.end(),
+                error: Some(e.clone()),
+            },
+        };
+        json::to_string(&reply).unwrap_or_default()
+    }
+
+    // Channels the device sends
+    pub fn schema(&self) -> Vec<ChannelSpec> {
+        if self.protocol >= 2 {
+            return self.channels.clone();
+        }
+        let unit = |kind: &str| {
+            self.sensors
+                .iter()
+                .find(|s| s.kind == kind)
+                .and_then(|s| s.unit.clone())
+        };
+        [
+            ("acc", "acceleration"),
+            ("mag", "magnetometer"),
+            ("gyro", "gyroscope"),
+        ]
+        .into_iter()
+        .flat_map(|(prefix, kind)| {
+            ["x", "y", "z"].map(|axis| ChannelSpec {
+                name: format!("{prefix}_{axis}"),
+                unit: unit(kind),
+                kind: ChannelType::F32,
+                sensor: Some(prefix.to_string()),
+                axis: Some(axis.to_string()),
+            })
+        })
+        .collect()
+    }
+
+    // Decode a binary data message according to the negotiated protocol
+    pub fn decode(&self, bytes: &[u8]) -> Option<DataFrame> {
+        match self.protocol {
+            1 => proto::SensorData::decode(bytes).ok()?.frame().ok(),
+            2 => self.decode_channels(bytes),
+            _ => None,
+        }
+    }
+
+    fn decode_channels(&self, bytes: &[u8]) -> Option<DataFrame> {
+        let data = wire::ChannelData::decode(bytes).ok()?;
+        let samples = data.time.len();
+        if data.channels.len() != self.channels.len()
+            || data.channels.iter().any(|c| c.values.len() != samples)
+        {
+            return None;
+        }
+
+        let time = Series::new(
+            "time",
+            data.time
+                .iter()
+                .map(|t| (t * 1.0e9) as i64)
+                .collect::<Vec<_>>(),
+        )
+        .cast(&DataType::Duration(TimeUnit::Nanoseconds))
+        .ok()?;
+        let mut columns = vec![time];
+        for (spec, values) in self.channels.iter().zip(data.channels) {
+            let values = match spec.kind {
+                ChannelType::Bool => Series::new(
+                    &spec.name,
+                    values.values.iter().map(|v| *v != 0.0).collect::<Vec<_>>(),
+                ),
+                kind => Series::new(&spec.name, values.values)
+                    .cast(&kind.dtype())
+                    .ok()?,
+            };
+            columns.push(values);
+        }
+        DataFrame::new(columns).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prost::Message as _;
+
+    #[test]
+    fn accepts_a_bare_id_from_legacy_devices() {
+        let (hello, expects_reply) = Hello::parse("bench-1\n");
+        let hello = hello.unwrap();
+        assert!(!expects_reply);
+        assert_eq!(hello.device_id, "bench-1");
+        assert_eq!(hello.protocol, 1);
+        assert_eq!(hello.schema().len(), 9);
+    }
+
+    #[test]
+    fn parses_a_json_hello() {
+        let text = r#"{
+            "device_id": "bench-2",
+            "firmware": "1.4.0",
+            "protocol": 2,
+            "channels": [
+                { "name": "temperature", "unit": "degC", "type": "f64" },
+                { "name": "door_open", "type": "bool" }
+            ]
+        }"#;
+        let (hello, expects_reply) = Hello::parse(text);
+        let hello = hello.unwrap();
+        assert!(expects_reply);
+        assert_eq!(hello.firmware.as_deref(), Some("1.4.0"));
+        assert_eq!(hello.schema(), hello.channels);
+        assert_eq!(hello.channels[1].kind, ChannelType::Bool);
+    }
+
+    #[test]
+    fn rejects_invalid_hellos() {
+        let invalid = [
+            r#"{ "device_id": "a", "protocol": 9 }"#,
+            r#"{ "device_id": "a", "protocol": 2 }"#,
+            r#"{ "device_id": "a", "sensors": [{ "kind": "acceleration", "sample_rates": [0] }] }"#,
+            r#"{ "device_id": "a", "protocol": 2, "channels": [{ "name": "time" }] }"#,
+            r#"{ "device_id": "a", "protocol": 2, "channels": [{ "name": "x_filtered" }] }"#,
+            r#"{ "device_id": "a", "protocol": 2, "channels": [{ "name": "x" }, { "name": "x" }] }"#,
+            r#"{ "device_id": "a", "protocol": 2, "channels": [{ "name": "x", "axis": "x" }] }"#,
+            r#"{ "device_id": "a", "protocol": 2, "channels": [{ "name": "x", "sensor": "acc", "axis": "w" }] }"#,
+            r#"{ "device_id": "a", "protocol": 2, "channels": [
+                { "name": "a", "sensor": "acc", "axis": "x" }, { "name": "b", "sensor": "acc", "axis": "x" }
+            ] }"#,
+            r#"{ "device_id": "a", "protocol": "#,
+        ];
+        for text in invalid {
+            let (hello, expects_reply) = Hello::parse(text);
+            assert!(hello.is_err(), "{text}");
+            assert!(expects_reply);
+        }
+    }
+
+    #[test]
+    fn takes_the_id_from_the_certificate() {
+        let verify = |identity: DeviceIdentity, text: &str, name: Option<&str>| {
+            identity.verify(Hello::parse(text).0.unwrap(), name)
+        };
+        let hello = DeviceIdentity::Hello;
+        let certificate = DeviceIdentity::Certificate;
+
+        assert_eq!(verify(hello, "bench-1", None).unwrap().device_id, "bench-1");
+        assert!(verify(hello, r#"{ "device_id": "" }"#, None).is_err());
+        assert!(verify(hello, r#"{ "firmware": "1.4.0" }"#, Some("bench-1")).is_err());
+
+        for text in ["", "bench-1", r#"{ "firmware": "1.4.0" }"#] {
+            let verified = verify(certificate, text, Some("bench-1"));
+            assert_eq!(verified.unwrap().device_id, "bench-1", "{text}");
+        }
+        assert!(verify(certificate, "bench-2", Some("bench-1")).is_err());
+        assert!(verify(certificate, "bench-1", None).is_err());
+
+        let long = "b".repeat(MAX_DEVICE_ID + 1);
+        assert!(verify(hello, &long, None).is_err());
+        assert!(verify(certificate, "", Some(&long)).is_err());
+        assert!(verify(hello, &long[1..], None).is_ok());
+    }
+
+    #[test]
+    fn finds_the_axes_of_a_sensor() {
+        let legacy = Hello::legacy("a").schema();
+        assert_eq!(axes(&legacy, "gyro"), Some(["gyro_x", "gyro_y", "gyro_z"]));
+
+        let (hello, _) = Hello::parse(
+            r#"{ "device_id": "a", "protocol": 2, "channels": [
+                { "name": "az", "sensor": "acc", "axis": "z" },
+                { "name": "ax", "sensor": "acc", "axis": "x" },
+                { "name": "ay", "sensor": "acc", "axis": "y" },
+                { "name": "wx", "sensor": "gyro", "axis": "x" }
+            ] }"#,
+        );
+        let schema = hello.unwrap().schema();
+        assert_eq!(axes(&schema, "acc"), Some(["ax", "ay", "az"]));
+        assert_eq!(axes(&schema, "gyro"), None);
+    }
+
+    #[test]
+    fn announces_on_a_transport_naming_the_device() {
+        let hello = Hello::announced(
+            "weather-1",
+            r#"{ "protocol": 2, "channels": [{ "name": "t" }] }"#,
+        );
+        assert_eq!(hello.unwrap().device_id, "weather-1");
+        assert!(Hello::announced("weather-1", r#"{ "device_id": "weather-1" }"#).is_ok());
+        assert!(Hello::announced("weather-1", r#"{ "device_id": "weather-2" }"#).is_err());
+        assert!(Hello::announced("weather-1", r#"{ "protocol": 2 }"#).is_err());
+    }
+
+    #[test]
+    fn replies_with_the_error() {
+        let (hello, _) = Hello::parse(r#"{ "device_id": "a", "protocol": 9 }"#);
+        let reply = Hello::reply(&hello);
+        assert!(reply.contains("\"accepted\":false"));
+        assert!(reply.contains("unsupported protocol version 9"));
+    }
+
+    #[test]
+    fn cuts_close_reasons_on_a_character_boundary() {
+        assert_eq!(close_reason("invalid hello"), "invalid hello");
+        let long = "ä".repeat(100);
+        let reason = close_reason(&long);
+        assert_eq!(reason.len(), 122);
+        assert!(long.starts_with(&reason));
+    }
+
+    #[test]
+    fn decodes_declared_channels() {
+        let (hello, _) = Hello::parse(
+            r#"{ "device_id": "a", "protocol": 2, "channels": [
+                { "name": "level", "type": "i64" }, { "name": "open", "type": "bool" }
+            ] }"#,
+        );
+        let hello = hello.unwrap();
+        let data = wire::ChannelData {
+            time: vec![0.0, 0.5],
+            channels: vec![
+                wire::Values {
+                    values: vec![3.0, 4.0],
+                },
+                wire::Values {
+                    values: vec![0.0, 1.0],
+                },
+            ],
+        };
+        let frame = hello.decode(&data.encode_to_vec()).unwrap();
+        assert_eq!(frame.get_column_names(), ["time", "level", "open"]);
+        assert_eq!(frame.column("level").unwrap().dtype(), &DataType::Int64);
+        let open = frame.column("open").unwrap().bool().unwrap();
+        assert_eq!(open.get(1), Some(true));
+
+        // Every channel needs a value per sample
+        let short = wire::ChannelData {
+            time: vec![0.0, 0.5],
+            channels: vec![
+                wire::Values { values: vec![3.0] },
+                wire::Values {
+                    values: vec![0.0, 1.0],
+                },
+            ],
+        };
+        assert!(hello.decode(&short.encode_to_vec()).is_none());
+    }
+}
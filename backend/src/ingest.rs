@@ -0,0 +1,187 @@
+use crate::alert::Alerts;
+use crate::connection::{Connection, Connections};
+use crate::events::{Detector, EventConfig};
+use crate::filter::{self, Filters, Stage};
+use crate::memory::{self, MemoryConfig};
+use crate::quality::{self, PeriodEstimate, QualityConfig};
+use crate::throttle::{self, Throttle, Verdict};
+
+use polars::prelude::*;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+// Everything a newly received frame passes through before it is stored. Shared
+// by all ingest paths so they behave identically.
+#[derive(Clone)]
+pub struct Pipeline {
+    pub connections: Connections,
+    pub quality: QualityConfig,
+    pub events: EventConfig,
+    pub filters: Filters,
+    pub alerts: Alerts,
+    // How long live data is kept
+    pub retention: chrono::Duration,
+    pub memory: MemoryConfig,
+    // Rate limits per device, shared with the WebSocket
+    pub throttle: Throttle,
+    // Set on shutdown, after which no more data is taken in
+    pub stopped: Arc<AtomicBool>,
+}
+
+impl Pipeline {
+    // Refuse all further data, so the live data can be saved as it is. Checked
+    // under the connections lock, so no ingest is in progress once the lock is
+    // taken after this.
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+
+    fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+
+    // Decode a binary message of a device in the protocol of its last hello,
+    // whichever transport it came on
+    pub async fn decode(&self, id: &str, bytes: &[u8]) -> Option<DataFrame> {
+        self.connections.hello(id).await.decode(bytes)
+    }
+
+    // Ingest a message of a transport without sessions to close within the
+    // rate limits of the device. Returns whether any of it was taken in.
+    pub async fn ingest_limited(&self, id: &str, bytes: usize, frame: DataFrame) -> bool {
+        match self
+            .throttle
+            .check_sessionless(id, bytes, frame.height())
+            .await
+        {
+            Verdict::Accept => self.ingest(id, frame).await,
+            Verdict::Downsample(step) => self.ingest(id, throttle::downsample(&frame, step)).await,
+            Verdict::Drop | Verdict::Disconnect => return false,
+        }
+        true
+    }
+
+    pub async fn ingest(&self, id: &str, frame: DataFrame) {
+        let chain = self
+            .filters
+            .get(id)
+            .await
+            .filter(|chain| chain.stage == Stage::Ingest);
+
+        let alerts = {
+            let mut lock = self.connections.connections.lock().await;
+            if self.is_stopped() {
+                return;
+            }
+            let Some(connection) = Connections::get(&mut lock, id) else {
+                return;
+            };
+            let previous = connection.last_time();
+            let schema = connection.schema();
+            let Ok(frame) = quality::annotate(
+                frame,
+                previous,
+                &mut connection.period,
+                &schema,
+                &self.quality,
+            ) else {
+                return;
+            };
+            let frame = match &chain {
+                Some(chain) => {
+                    let period = connection.period.period();
+                    match filter::apply_incremental(
+                        frame,
+                        chain,
+                        &mut connection.filter_state,
+                        period,
+                    ) {
+                        Ok(frame) => frame,
+                        Err(_) => return,
+                    }
+                }
+                None => frame,
+            };
+
+            connection.last_seen = Some(Instant::now());
+            let events = connection.detector.process(&frame, &schema, &self.events);
+            connection.add_events(events);
+            let alerts = self.alerts.evaluate(id, &frame, &schema).await;
+            _ = connection
+                .append_data(frame)
+                .and_then(|_| connection.discard_older_than(self.retention));
+            memory::enforce(&mut lock, id, &self.memory);
+            alerts
+        };
+        self.alerts.dispatch(alerts);
+    }
+
+    // Merge a backlog a device buffered while offline. It may predate the data
+    // already stored, so it is processed on its own rather than continuing the
+    // live state, and alerts are not raised for it.
+    pub async fn ingest_batch(&self, id: &str, frame: DataFrame) -> PolarsResult<()> {
+        let chain = self
+            .filters
+            .get(id)
+            .await
+            .filter(|chain| chain.stage == Stage::Ingest);
+
+        let schema = self.connections.hello(id).await.schema();
+        let frame = quality::annotate(
+            frame,
+            None,
+            &mut PeriodEstimate::default(),
+            &schema,
+            &self.quality,
+        )?;
+        let frame = match &chain {
+            Some(chain) => filter::apply(frame, chain)?,
+            None => frame,
+        };
+        let events = Detector::default().process(&frame, &schema, &self.events);
+
+        let mut lock = self.connections.connections.lock().await;
+        if self.is_stopped() {
+            return Err(PolarsError::ComputeError(
+                "the server is shutting down".into(),
+            ));
+        }
+        let connection = lock.entry(id.to_string()).or_insert_with(Connection::new);
+        connection.merge_events(events);
+        connection.merge_data(frame)?;
+        connection.discard_older_than(self.retention)?;
+        memory::enforce(&mut lock, id, &self.memory);
+        Ok(())
+    }
+
+    // Raise inactivity alerts for devices that have stopped sending data
+    pub async fn check_inactive(&self) {
+        let idle = {
+            let lock = self.connections.connections.lock().await;
+            lock.iter()
+                .filter_map(|(id, c)| c.last_seen.map(|t| (id.clone(), t.elapsed())))
+                .collect::<Vec<_>>()
+        };
+        let alerts = self.alerts.evaluate_inactive(&idle).await;
+        self.alerts.dispatch(alerts);
+    }
+}
+
+#[cfg(test)]
+impl Pipeline {
+    // The default configuration, keeping five minutes of data
+    pub fn for_test() -> Self {
+        Self {
+            connections: Connections::new(Default::default()),
+            quality: QualityConfig::default(),
+            events: EventConfig::default(),
+            filters: Filters::new(),
+            alerts: Alerts::new(Default::default()),
+            retention: chrono::Duration::minutes(5),
+            memory: MemoryConfig::default(),
+            throttle: Throttle::new(Default::default()),
+            stopped: Default::default(),
+        }
+    }
+}
@@ -1,75 +1,311 @@
+mod alert;
+mod assets;
+mod atomic;
+mod batch;
+mod cli;
+mod command;
 mod connection;
+mod decimate;
+mod duration;
+mod events;
+mod filter;
+mod format;
 mod frame;
+mod hello;
+mod ingest;
+mod memory;
+mod mqtt;
+mod persist;
+mod quality;
+mod recording;
+mod registry;
+mod replay;
+mod socket;
+mod spectrum;
+mod throttle;
 
-use connection::Connection;
-use frame::Frame;
+use alert::{Alert, AlertConfig, Alerts, Rule};
+use assets::{Asset, Assets, Negotiation};
+use batch::{BatchSummary, Encoding};
+use clap::Parser;
+use cli::{Cli, Command};
+use command::{CommandRecord, CommandRequest, Commands};
+use connection::{Connections, DuplicatePolicy, SessionInfo};
+use decimate::Decimation;
+use events::{Event, EventConfig, EventKind};
+use filter::{FilterChain, Filters, Stage};
+use format::DataResponse;
+use hello::{close_reason, ChannelSpec, DeviceIdentity, Hello};
+use ingest::Pipeline;
+use memory::{MemoryConfig, MemoryReport};
+use mqtt::MqttConfig;
+use persist::Snapshot;
+use quality::{QualityConfig, QualitySummary};
+use recording::Recordings;
+use registry::{Device, DeviceInfo, Registry};
+use replay::{ReplayRequest, ReplayStatus, Replays, StartError};
+use socket::SocketConfig;
+use spectrum::{Analysis, Mode, Window};
+use throttle::{IngestStats, Throttle, ThrottleConfig, Verdict};
 
-use bytes::Bytes;
-use hecate_protobuf as proto;
 use polars::prelude::*;
-use proto::Message;
 use rocket::{
-    fs::NamedFile,
-    futures::lock::{Mutex, MutexGuard},
-    get, launch, post,
-    response::status::NotFound,
+    data::{Data, Limits, ToByteUnit},
+    delete,
+    fairing::AdHoc,
+    figment::Figment,
+    form::Form,
+    fs::TempFile,
+    get,
+    http::Status,
+    mtls::Certificate,
+    post, put,
+    response::status::{BadRequest, Custom},
     routes,
     serde::json::Json,
-    tokio::time::timeout,
-    State,
+    shutdown::Shutdown,
+    tokio::{
+        io::AsyncReadExt,
+        select,
+        task::spawn_blocking,
+        time::{interval, timeout},
+    },
+    Build, FromForm, Rocket, State,
 };
-use rocket_ws as ws;
-use std::collections::HashMap;
-use std::path::PathBuf;
+use rocket_ws::{
+    self as ws,
+    frame::{CloseCode, CloseFrame},
+};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 
-struct Connections {
-    connections: Arc<Mutex<HashMap<String, Connection>>>,
+#[rocket::main]
+async fn main() {
+    let cli = Cli::parse();
+    let figment = cli.figment();
+    let result = match cli.command {
+        None | Some(Command::Serve(_)) => match rocket(figment) {
+            Ok(rocket) => rocket.launch().await.map(|_| ()).map_err(|e| e.to_string()),
+            Err(e) => Err(e),
+        },
+        Some(command) => cli::run(command, &figment).await,
+    };
+    if let Err(e) = result {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
 }
 
-impl Connections {
-    pub fn new() -> Self {
-        Self {
-            connections: Arc::new(Mutex::new(HashMap::new())),
-        }
+// The configured server, or what is wrong with the configuration
+fn rocket(figment: Figment) -> Result<Rocket<Build>, String> {
+    let rocket = rocket::custom(figment);
+    let quality: QualityConfig = rocket
+        .figment()
+        .extract_inner("quality")
+        .unwrap_or_default();
+    let events: EventConfig = rocket.figment().extract_inner("events").unwrap_or_default();
+    let alerts: AlertConfig = rocket.figment().extract_inner("alerts").unwrap_or_default();
+    let data_dir = cli::data_dir(rocket.figment());
+    let retention: String = rocket
+        .figment()
+        .extract_inner("retention")
+        .unwrap_or_else(|_| String::from("5m"));
+    let retention = cli::parse_retention(&retention)?;
+    let ui_dir: PathBuf = rocket
+        .figment()
+        .extract_inner("ui_dir")
+        .unwrap_or_else(|_| assets::default_dir());
+    let mqtt: Option<MqttConfig> = rocket.figment().extract_inner("mqtt").ok();
+    let socket: Option<SocketConfig> = rocket.figment().extract_inner("socket").ok();
+    if let Some(socket) = &socket {
+        socket.validate()?;
     }
 
-    pub fn get<'a>(
-        lock: &'a mut MutexGuard<'_, HashMap<String, Connection>>,
-        id: &str,
-    ) -> Option<&'a mut Connection> {
-        (*lock).get_mut(id)
+    let duplicate_policy: DuplicatePolicy = rocket
+        .figment()
+        .extract_inner("duplicate_policy")
+        .unwrap_or_default();
+
+    // A negative budget must not silently fall back to unlimited
+    let memory: MemoryConfig = match rocket.figment().find_value("memory") {
+        Ok(_) => rocket
+            .figment()
+            .extract_inner("memory")
+            .map_err(|e| format!("Invalid memory budget: {e}"))?,
+        Err(_) => MemoryConfig::default(),
+    };
+    memory.validate()?;
+    let throttle: ThrottleConfig = rocket
+        .figment()
+        .extract_inner("throttle")
+        .unwrap_or_default();
+    throttle.validate()?;
+    let device_identity: DeviceIdentity = rocket
+        .figment()
+        .extract_inner("device_identity")
+        .unwrap_or_default();
+    if device_identity == DeviceIdentity::Certificate
+        && rocket.figment().find_value("tls.mutual").is_err()
+    {
+        return Err(String::from(
+            "device_identity = \"certificate\" requires client certificates, configure tls.mutual",
+        ));
     }
-}
 
-#[launch]
-fn rocket() -> _ {
-    rocket::build().manage(Connections::new()).mount(
-        "/",
-        routes![
-            index,
-            static_files,
-            connections,
-            sensor_connected,
-            sensor_data,
-            sensor_data_reset,
-            ws_data,
-        ],
-    )
+    let connections = Connections::new(duplicate_policy);
+    let pipeline = Pipeline {
+        connections: connections.clone(),
+        quality,
+        events,
+        filters: Filters::new(),
+        alerts: Alerts::new(alerts),
+        retention,
+        memory,
+        throttle: Throttle::new(throttle),
+        stopped: Default::default(),
+    };
+
+    Ok(rocket
+        .manage(connections)
+        .manage(pipeline.alerts.clone())
+        .manage(pipeline.filters.clone())
+        .manage(Snapshot::new(data_dir.join("state")))
+        .manage(Recordings::new(data_dir.join("recordings")))
+        .manage(Registry::load(data_dir.join("devices.json"))?)
+        .manage(Replays::new())
+        .manage(Commands::new())
+        .manage(Assets::new(ui_dir))
+        .manage(device_identity)
+        .manage(pipeline.throttle.clone())
+        .manage(pipeline)
+        .attach(AdHoc::on_ignite("Restore live data", |rocket| async move {
+            let snapshot = rocket.state::<Snapshot>().unwrap();
+            let connections = rocket.state::<Connections>().unwrap();
+            match snapshot.restore(connections).await {
+                Ok(0) => {}
+                Ok(devices) => log::info!("Restored live data of {devices} devices"),
+                Err(e) => log::warn!("Failed to restore live data: {e}"),
+            }
+            rocket
+        }))
+        .attach(AdHoc::on_shutdown("Persist live data", |rocket| {
+            Box::pin(async move {
+                // Sessions are still open at this point, stop taking in their
+                // data so the snapshot is the final state
+                rocket.state::<Pipeline>().unwrap().stop();
+                let snapshot = rocket.state::<Snapshot>().unwrap();
+                let connections = rocket.state::<Connections>().unwrap();
+                if let Err(e) = snapshot.save(connections).await {
+                    log::warn!("Failed to persist live data: {e}");
+                }
+            })
+        }))
+        .attach(AdHoc::on_liftoff("Inactivity watchdog", |rocket| {
+            Box::pin(async move {
+                let pipeline = rocket.state::<Pipeline>().unwrap().clone();
+                rocket::tokio::spawn(async move {
+                    let mut interval = interval(std::time::Duration::from_secs(1));
+                    loop {
+                        interval.tick().await;
+                        pipeline.check_inactive().await;
+                    }
+                });
+            })
+        }))
+        .attach(AdHoc::on_liftoff("Throttle pruning", |rocket| {
+            Box::pin(async move {
+                let throttle = rocket.state::<Throttle>().unwrap().clone();
+                rocket::tokio::spawn(async move {
+                    let mut interval = interval(std::time::Duration::from_secs(60));
+                    loop {
+                        interval.tick().await;
+                        throttle.prune().await;
+                    }
+                });
+            })
+        }))
+        .attach(AdHoc::on_liftoff("MQTT ingest", |rocket| {
+            Box::pin(async move {
+                let Some(config) = mqtt else {
+                    return;
+                };
+                let pipeline = rocket.state::<Pipeline>().unwrap().clone();
+                rocket::tokio::spawn(mqtt::run(config, pipeline));
+            })
+        }))
+        .attach(AdHoc::on_liftoff("UDP/TCP ingest", |rocket| {
+            Box::pin(async move {
+                let Some(config) = socket else {
+                    return;
+                };
+                let pipeline = rocket.state::<Pipeline>().unwrap().clone();
+                socket::run(config, pipeline).await;
+            })
+        }))
+        .mount(
+            "/",
+            routes![
+                index,
+                static_files,
+                connections,
+                sensor_connected,
+                sensor_sessions,
+                sensor_info,
+                sensor_schema,
+                sensor_stats,
+                stats,
+                admin_memory,
+                devices,
+                device,
+                device_update,
+                device_delete,
+                sensor_data,
+                sensor_data_upload,
+                sensor_data_reset,
+                sensor_quality,
+                sensor_quality_windows,
+                sensor_events,
+                sensor_spectrum,
+                sensor_filters,
+                sensor_filters_set,
+                sensor_filters_delete,
+                sensor_commands,
+                sensor_command_send,
+                recordings,
+                recording_create,
+                recording_import,
+                recording_data,
+                recording_delete,
+                replays,
+                replay_status,
+                replay_start,
+                replay_upload,
+                replay_control,
+                replay_stop,
+                alert_rules,
+                alert_rule,
+                alert_rule_create,
+                alert_rule_update,
+                alert_rule_delete,
+                alert_history,
+                ws_data,
+            ],
+        ))
 }
 
 #[get("/")]
-async fn index() -> Result<NamedFile, NotFound<String>> {
-    NamedFile::open("../ui/dist/index.html")
-        .await
-        .map_err(|e| NotFound(e.to_string()))
+async fn index(assets: &State<Assets>, negotiation: Negotiation) -> Result<Asset, Status> {
+    assets.get(Path::new("index.html"), &negotiation).await
 }
 
 #[get("/<path..>")]
-async fn static_files(path: PathBuf) -> Result<NamedFile, NotFound<String>> {
-    let path = PathBuf::from("../ui/dist").join(path);
-    NamedFile::open(path)
-        .await
-        .map_err(|e| NotFound(e.to_string()))
+async fn static_files(
+    path: PathBuf,
+    assets: &State<Assets>,
+    negotiation: Negotiation,
+) -> Result<Asset, Status> {
+    assets.get(&path, &negotiation).await
 }
 
 #[get("/sensor/connections")]
@@ -82,61 +318,273 @@ async fn connections(state: &State<Connections>) -> Json<Vec<String>> {
 #[get("/sensor/<id>/connected")]
 async fn sensor_connected(id: &str, state: &State<Connections>) -> Option<Json<bool>> {
     let mut lock = state.connections.lock().await;
-    Connections::get(&mut lock, id).map(|c| Json(c.active))
+    Connections::get(&mut lock, id).map(|c| Json(c.active()))
+}
+
+#[get("/sensor/<id>/sessions")]
+async fn sensor_sessions(id: &str, state: &State<Connections>) -> Option<Json<Vec<SessionInfo>>> {
+    let mut lock = state.connections.lock().await;
+    Connections::get(&mut lock, id).map(|c| Json(c.sessions()))
+}
+
+// What the device announced in its hello when it last connected
+#[get("/sensor/<id>/info")]
+async fn sensor_info(id: &str, state: &State<Connections>) -> Option<Json<Hello>> {
+    let mut lock = state.connections.lock().await;
+    Connections::get(&mut lock, id).and_then(|c| c.hello.clone().map(Json))
+}
+
+// Channels of a device, as declared in its hello or found in its data
+#[get("/sensor/<id>/schema")]
+async fn sensor_schema(id: &str, state: &State<Connections>) -> Option<Json<Vec<ChannelSpec>>> {
+    let mut lock = state.connections.lock().await;
+    Connections::get(&mut lock, id)
+        .map(|c| match &c.hello {
+            Some(hello) => hello.schema(),
+            None => hello::channels_of(c.recent_data()),
+        })
+        .map(Json)
+}
+
+// What the rate limits accepted from and refused a device
+#[get("/sensor/<id>/stats")]
+async fn sensor_stats(id: &str, throttle: &State<Throttle>) -> Option<Json<IngestStats>> {
+    throttle.stats(id).await.map(Json)
+}
+
+#[get("/stats")]
+async fn stats(throttle: &State<Throttle>) -> Json<BTreeMap<String, IngestStats>> {
+    Json(throttle.all_stats().await)
+}
+
+// Memory held by the live data of each device
+#[get("/admin/memory")]
+async fn admin_memory(pipeline: &State<Pipeline>) -> Json<MemoryReport> {
+    let lock = pipeline.connections.connections.lock().await;
+    Json(memory::report(&lock, &pipeline.memory))
+}
+
+// Known devices and connected ones, whether they are registered or not
+#[get("/devices")]
+async fn devices(state: &State<Connections>, registry: &State<Registry>) -> Json<Vec<Device>> {
+    let mut devices = registry
+        .all()
+        .await
+        .into_iter()
+        .map(|(id, info)| Device {
+            id,
+            known: true,
+            online: false,
+            info,
+        })
+        .collect::<Vec<_>>();
+
+    let lock = state.connections.lock().await;
+    for (id, connection) in lock.iter() {
+        match devices.iter_mut().find(|d| d.id == *id) {
+            Some(device) => device.online = connection.active(),
+            None => devices.push(Device {
+                id: id.clone(),
+                known: false,
+                online: connection.active(),
+                info: DeviceInfo::default(),
+            }),
+        }
+    }
+    devices.sort_by(|a, b| a.id.cmp(&b.id));
+    Json(devices)
 }
 
-#[get("/sensor/<id>/data?<interval>&<duration>")]
+#[get("/devices/<id>")]
+async fn device(id: &str, registry: &State<Registry>) -> Option<Json<DeviceInfo>> {
+    registry.get(id).await.map(Json)
+}
+
+#[put("/devices/<id>", data = "<info>")]
+async fn device_update(id: &str, info: Json<DeviceInfo>, registry: &State<Registry>) {
+    registry.set(id, info.into_inner()).await;
+}
+
+#[delete("/devices/<id>")]
+async fn device_delete(id: &str, registry: &State<Registry>) -> Option<Json<DeviceInfo>> {
+    registry.remove(id).await.map(Json)
+}
+
+// A duration query parameter, `default` if it is not given
+fn query_duration(
+    value: Option<&str>,
+    default: &str,
+) -> Result<chrono::Duration, BadRequest<String>> {
+    duration::parse(value.unwrap_or(default)).map_err(BadRequest)
+}
+
+// Resample `data` to `interval` and keep the last `duration` of it
+fn aggregate(
+    data: DataFrame,
+    interval: Option<chrono::Duration>,
+    duration: chrono::Duration,
+) -> Option<DataFrame> {
+    match interval {
+        None => Some(data),
+        Some(interval) => {
+            let interval = duration::polars(interval);
+            data.lazy()
+                .with_column(
+                    (col("time")
+                        + lit(
+                            chrono::NaiveDate::from_isoywd_opt(0, 1, chrono::Weekday::Mon).unwrap(),
+                        ))
+                    .alias("time_abs"),
+                )
+                .sort(["time_abs"], Default::default())
+                .group_by_dynamic(
+                    col("time_abs"),
+                    [],
+                    DynamicGroupOptions {
+                        every: interval,
+                        period: interval,
+                        offset: Duration::parse("0"),
+                        ..Default::default()
+                    },
+                )
+                .agg([col("*").exclude(["quality"]).mean()])
+                .select([col("*").exclude(["time_abs"])])
+                .filter(col("time").gt(col("time").max() - lit(duration)))
+                .collect()
+                .ok()
+        }
+    }
+}
+
+// Reduce each channel to `max_points` samples, for plotting long windows
+fn decimate(
+    data: DataFrame,
+    max_points: Option<usize>,
+    decimation: Option<Decimation>,
+) -> Option<DataFrame> {
+    match max_points {
+        Some(points) => decimate::apply(&data, points, decimation.unwrap_or_default()).ok(),
+        None => Some(data),
+    }
+}
+
+#[get("/sensor/<id>/data?<interval>&<duration>&<filtered>&<max_points>&<decimation>")]
 async fn sensor_data(
     id: &str,
     interval: Option<String>,
     duration: Option<String>,
+    filtered: Option<bool>,
+    max_points: Option<usize>,
+    decimation: Option<Decimation>,
     state: &State<Connections>,
-) -> Option<Json<DataFrame>> {
-    let mut lock = state.connections.lock().await;
-    Connections::get(&mut lock, id)
-        .and_then(|c| {
-            let duration_ns =
-                polars::time::Duration::parse(&duration.unwrap_or(String::from("1m")))
-                    .nanoseconds();
-            let duration = chrono::Duration::nanoseconds(duration_ns);
-
-            match interval {
-                None => Some(c.recent_data().clone()),
-                Some(interval) => {
-                    let interval = polars::time::Duration::parse(&interval);
-                    c.recent_data()
-                        .clone()
-                        .lazy()
-                        .with_column(
-                            (col("time")
-                                + lit(chrono::NaiveDate::from_isoywd_opt(
-                                    0,
-                                    1,
-                                    chrono::Weekday::Mon,
-                                )
-                                .unwrap()))
-                            .alias("time_abs"),
-                        )
-                        .sort(["time_abs"], Default::default())
-                        .group_by_dynamic(
-                            col("time_abs"),
-                            [],
-                            DynamicGroupOptions {
-                                every: interval,
-                                period: interval,
-                                offset: Duration::parse("0"),
-                                ..Default::default()
-                            },
-                        )
-                        .agg([col("*").mean()])
-                        .select([col("*").exclude(["time_abs"])])
-                        .filter(col("time").gt(col("time").max() - lit(duration)))
-                        .collect()
-                        .ok()
-                }
+    filters: &State<Filters>,
+) -> Result<Option<DataResponse>, BadRequest<String>> {
+    let interval = interval
+        .as_deref()
+        .map(duration::parse)
+        .transpose()
+        .map_err(BadRequest)?;
+    let duration = query_duration(duration.as_deref(), "1m")?;
+    // Chains filtering at ingest already stored their output alongside the raw data
+    let chain = match filtered {
+        Some(true) => filters
+            .get(id)
+            .await
+            .filter(|chain| chain.stage == Stage::Query),
+        _ => None,
+    };
+
+    // Only copy the data while holding the lock, ingest waits for it
+    let data = {
+        let mut lock = state.connections.lock().await;
+        let Some(connection) = Connections::get(&mut lock, id) else {
+            return Ok(None);
+        };
+        connection.recent_data().clone()
+    };
+
+    let data = spawn_blocking(move || {
+        let data = match &chain {
+            Some(chain) => data
+                .lazy()
+                .sort(["time"], Default::default())
+                .collect()
+                .and_then(|data| filter::apply(data, chain))
+                .ok()?,
+            None if filtered == Some(true) => data,
+            // Leave out what a chain filtering at ingest stored
+            None => {
+                let raw = data
+                    .get_column_names()
+                    .into_iter()
+                    .filter(|name| !name.ends_with("_filtered"))
+                    .collect::<Vec<_>>();
+                data.select(raw).ok()?
             }
-        })
-        .map(Json)
+        };
+        aggregate(data, interval, duration).and_then(|data| decimate(data, max_points, decimation))
+    })
+    .await;
+    Ok(data.ok().flatten().map(DataResponse))
+}
+
+// Merge a backlog of length-delimited data messages, optionally gzip or zstd
+// compressed, into a device's data. The whole backlog is also kept in the
+// "backlog-<id>" recording, as the live window only covers the retention.
+#[post("/sensor/<id>/data", data = "<data>")]
+async fn sensor_data_upload(
+    id: &str,
+    data: Data<'_>,
+    encoding: Encoding,
+    limits: &Limits,
+    pipeline: &State<Pipeline>,
+    recordings: &State<Recordings>,
+) -> Result<Json<BatchSummary>, Custom<String>> {
+    let bad_request = |e: String| Custom(Status::BadRequest, e);
+    let too_large = || {
+        Custom(
+            Status::PayloadTooLarge,
+            String::from("body exceeds the sensor-data limit"),
+        )
+    };
+
+    // The limit applies to the body both as sent and decompressed
+    let limit = limits.get("sensor-data").unwrap_or(16.mebibytes());
+    let bytes = data
+        .open(limit)
+        .into_bytes()
+        .await
+        .map_err(|e| bad_request(e.to_string()))?;
+    if !bytes.is_complete() {
+        return Err(too_large());
+    }
+    let internal = |e: String| Custom(Status::InternalServerError, e);
+    let bytes = bytes.into_inner();
+    let limit = limit.as_u64() as usize;
+    let hello = pipeline.connections.hello(id).await;
+    let (frame, summary) = spawn_blocking(move || {
+        batch::decompress(bytes, encoding, limit)
+            .map_err(|e| bad_request(e.to_string()))?
+            .ok_or_else(too_large)
+            .and_then(|bytes| batch::decode(&bytes, &hello).map_err(bad_request))
+    })
+    .await
+    .map_err(|e| internal(e.to_string()))??;
+
+    // Loads and rewrites the whole recording, so keep it off the executor.
+    // Nothing is merged unless the backlog was archived.
+    let recordings = recordings.inner().clone();
+    let archived = frame.clone();
+    let backlog = recording::backlog_name(id);
+    spawn_blocking(move || recordings.append(&backlog, &archived))
+        .await
+        .map_err(|e| internal(e.to_string()))?
+        .map_err(|e| internal(format!("failed to archive the backlog: {e}")))?;
+    pipeline
+        .ingest_batch(id, frame)
+        .await
+        .map_err(|e| bad_request(e.to_string()))?;
+    Ok(Json(summary))
 }
 
 #[post("/sensor/<id>/data/reset")]
@@ -147,84 +595,654 @@ async fn sensor_data_reset(id: &str, state: &State<Connections>) {
     }
 }
 
+#[get("/sensor/<id>/quality")]
+async fn sensor_quality(id: &str, state: &State<Connections>) -> Option<Json<QualitySummary>> {
+    let mut lock = state.connections.lock().await;
+    Connections::get(&mut lock, id)
+        .and_then(|c| quality::summarize(c.recent_data()).ok())
+        .map(Json)
+}
+
+#[get("/sensor/<id>/quality/windows?<interval>&<duration>")]
+async fn sensor_quality_windows(
+    id: &str,
+    interval: Option<String>,
+    duration: Option<String>,
+    state: &State<Connections>,
+) -> Result<Option<Json<Vec<QualitySummary>>>, BadRequest<String>> {
+    let interval = query_duration(interval.as_deref(), "10s")?;
+    let duration = query_duration(duration.as_deref(), "1m")?;
+    let mut lock = state.connections.lock().await;
+    Ok(Connections::get(&mut lock, id)
+        .and_then(|c| {
+            let windows = quality::summarize_windows(c.recent_data(), interval).ok()?;
+            let end = windows.last().and_then(|w| w.start).unwrap_or(0.0);
+            let span = duration.num_nanoseconds().unwrap_or(i64::MAX) as f64 * 1.0e-9;
+            let windows = windows
+                .into_iter()
+                .filter(|w| w.start.unwrap_or(0.0) > end - span)
+                .collect();
+            Some(windows)
+        })
+        .map(Json))
+}
+
+#[get("/sensor/<id>/events?<duration>&<kind>")]
+async fn sensor_events(
+    id: &str,
+    duration: Option<String>,
+    kind: Option<EventKind>,
+    state: &State<Connections>,
+) -> Result<Option<Json<Vec<Event>>>, BadRequest<String>> {
+    let duration_ns = query_duration(duration.as_deref(), "1m")?
+        .num_nanoseconds()
+        .unwrap_or(i64::MAX);
+    let mut lock = state.connections.lock().await;
+    Ok(Connections::get(&mut lock, id)
+        .map(|c| {
+            let cutoff = c
+                .last_time()
+                .map_or(f64::MIN, |t| t.saturating_sub(duration_ns) as f64 * 1.0e-9);
+
+            c.events()
+                .iter()
+                .filter(|e| e.time > cutoff && kind.map_or(true, |k| e.kind == k))
+                .cloned()
+                .collect()
+        })
+        .map(Json))
+}
+
+#[get("/sensor/<id>/spectrum?<duration>&<channels>&<mode>&<window>&<size>")]
+async fn sensor_spectrum(
+    id: &str,
+    duration: Option<String>,
+    channels: Option<String>,
+    mode: Option<Mode>,
+    window: Option<Window>,
+    size: Option<usize>,
+    state: &State<Connections>,
+) -> Result<Option<Json<Analysis>>, BadRequest<String>> {
+    let duration = query_duration(duration.as_deref(), "1m")?;
+    // Only copy the window while holding the lock, ingest waits for it
+    let frame = {
+        let mut lock = state.connections.lock().await;
+        let Some(connection) = Connections::get(&mut lock, id) else {
+            return Ok(None);
+        };
+        connection.recent_data().clone()
+    };
+    let Ok(frame) = frame
+        .lazy()
+        .filter(col("time").gt(col("time").max() - lit(duration)))
+        .collect()
+    else {
+        return Ok(None);
+    };
+    let channels: Option<Vec<String>> =
+        channels.map(|c| c.split(',').map(|c| c.trim().to_string()).collect());
+    let size = size
+        .unwrap_or(256)
+        .clamp(2, spectrum::MAX_SIZE)
+        .next_power_of_two();
+
+    let analysis = spawn_blocking(move || {
+        spectrum::analyse(
+            &frame,
+            channels,
+            mode.unwrap_or(Mode::Psd),
+            window.unwrap_or(Window::Hann),
+            size,
+        )
+    })
+    .await;
+    Ok(analysis.ok().flatten().map(Json))
+}
+
+#[get("/sensor/<id>/filters")]
+async fn sensor_filters(id: &str, filters: &State<Filters>) -> Option<Json<FilterChain>> {
+    filters.get(id).await.map(Json)
+}
+
+#[put("/sensor/<id>/filters", data = "<chain>")]
+async fn sensor_filters_set(
+    id: &str,
+    chain: Json<FilterChain>,
+    state: &State<Connections>,
+    filters: &State<Filters>,
+) -> Result<Json<FilterChain>, BadRequest<String>> {
+    let sample_rate = {
+        let mut lock = state.connections.lock().await;
+        Connections::get(&mut lock, id).and_then(|c| {
+            c.period
+                .period()
+                .map(|period| 1.0e9 / period as f64)
+                .or_else(|| filter::sample_rate(c.recent_data()))
+        })
+    };
+    chain.validate(sample_rate).map_err(BadRequest)?;
+    filters.set(id, chain.0.clone()).await;
+    Ok(chain)
+}
+
+#[delete("/sensor/<id>/filters")]
+async fn sensor_filters_delete(id: &str, filters: &State<Filters>) -> Option<Json<FilterChain>> {
+    filters.remove(id).await.map(Json)
+}
+
+#[get("/recordings")]
+async fn recordings(recordings: &State<Recordings>) -> Json<Vec<String>> {
+    Json(recordings.list())
+}
+
+// Check a name for a new recording: 400 if invalid, 409 if already taken
+fn new_recording(name: &str, recordings: &Recordings) -> Result<(), Custom<String>> {
+    if !recording::valid_name(name) {
+        return Err(Custom(
+            Status::BadRequest,
+            format!("invalid recording name '{name}'"),
+        ));
+    }
+    if recordings.exists(name) {
+        return Err(Custom(
+            Status::Conflict,
+            format!("recording '{name}' already exists"),
+        ));
+    }
+    Ok(())
+}
+
+// Store the data currently held for a device as a recording
+#[post("/recordings/<name>?<device>")]
+async fn recording_create(
+    name: &str,
+    device: &str,
+    state: &State<Connections>,
+    recordings: &State<Recordings>,
+) -> Result<Option<Json<String>>, Custom<String>> {
+    new_recording(name, recordings)?;
+    let mut frame = {
+        let mut lock = state.connections.lock().await;
+        match Connections::get(&mut lock, device) {
+            Some(connection) => connection.recent_data().clone(),
+            None => return Ok(None),
+        }
+    };
+    recordings
+        .save(name, &mut frame)
+        .map_err(|e| Custom(Status::InternalServerError, e.to_string()))?;
+    Ok(Some(Json(name.to_string())))
+}
+
+#[derive(FromForm)]
+struct Import<'r> {
+    name: String,
+    file: TempFile<'r>,
+}
+
+// Store an uploaded CSV, Parquet or Arrow IPC file as a recording
+#[post("/recordings/import", data = "<upload>")]
+async fn recording_import(
+    upload: Form<Import<'_>>,
+    recordings: &State<Recordings>,
+) -> Result<Json<String>, Custom<String>> {
+    let name = &upload.name;
+    new_recording(name, recordings)?;
+    let bad_request = |e: &dyn std::fmt::Display| Custom(Status::BadRequest, e.to_string());
+
+    let mut bytes = Vec::new();
+    let file = upload.file.open().await.map_err(|e| bad_request(&e))?;
+    rocket::tokio::pin!(file);
+    file.read_to_end(&mut bytes)
+        .await
+        .map_err(|e| bad_request(&e))?;
+
+    let mut frame = read_upload(bytes).await?;
+    let (recordings, stored) = (recordings.inner().clone(), name.clone());
+    spawn_blocking(move || recordings.save(&stored, &mut frame))
+        .await
+        .map_err(|e| Custom(Status::InternalServerError, e.to_string()))?
+        .map_err(|e| Custom(Status::InternalServerError, e.to_string()))?;
+    Ok(Json(name.clone()))
+}
+
+// Parse and check an uploaded recording off the async executor
+async fn read_upload(bytes: Vec<u8>) -> Result<DataFrame, Custom<String>> {
+    spawn_blocking(move || {
+        recording::read_file(bytes).and_then(|frame| recording::validate(&frame))
+    })
+    .await
+    .map_err(|e| Custom(Status::InternalServerError, e.to_string()))?
+    .map_err(|e| Custom(Status::BadRequest, e.to_string()))
+}
+
+#[get("/recordings/<name>/data?<interval>&<duration>&<max_points>&<decimation>")]
+async fn recording_data(
+    name: &str,
+    interval: Option<String>,
+    duration: Option<String>,
+    max_points: Option<usize>,
+    decimation: Option<Decimation>,
+    recordings: &State<Recordings>,
+) -> Result<Option<DataResponse>, BadRequest<String>> {
+    let interval = interval
+        .as_deref()
+        .map(duration::parse)
+        .transpose()
+        .map_err(BadRequest)?;
+    let duration = query_duration(duration.as_deref(), "1m")?;
+    let Ok(data) = recordings.load(name) else {
+        return Ok(None);
+    };
+    Ok(aggregate(data, interval, duration)
+        .and_then(|data| decimate(data, max_points, decimation))
+        .map(DataResponse))
+}
+
+#[delete("/recordings/<name>")]
+async fn recording_delete(name: &str, recordings: &State<Recordings>) -> Option<()> {
+    recordings.remove(name).then_some(())
+}
+
+#[get("/replays")]
+async fn replays(replays: &State<Replays>) -> Json<Vec<ReplayStatus>> {
+    Json(replays.list().await)
+}
+
+#[get("/replays/<device>")]
+async fn replay_status(device: &str, replays: &State<Replays>) -> Option<Json<ReplayStatus>> {
+    replays.status(device).await.map(Json)
+}
+
+#[post("/replays", data = "<request>")]
+async fn replay_start(
+    request: Json<ReplayRequest>,
+    pipeline: &State<Pipeline>,
+    recordings: &State<Recordings>,
+    replays: &State<Replays>,
+) -> Result<Option<Json<ReplayStatus>>, Custom<String>> {
+    let request = request.into_inner();
+    if !replay::valid_speed(request.speed) {
+        return Err(Custom(
+            Status::BadRequest,
+            format!("invalid speed {}", request.speed),
+        ));
+    }
+    let Ok(frame) = recordings
+        .load(&request.recording)
+        .and_then(|frame| recording::raw_channels(&frame))
+    else {
+        return Ok(None);
+    };
+    let device = request
+        .device
+        .unwrap_or_else(|| format!("replay-{}", request.recording));
+
+    replays
+        .start(
+            pipeline,
+            device.clone(),
+            request.recording,
+            frame,
+            request.speed,
+            request.repeat,
+        )
+        .await
+        .map(|status| Some(Json(status)))
+        .map_err(|e| replay_error(e, &device))
+}
+
+fn replay_error(error: StartError, device: &str) -> Custom<String> {
+    match error {
+        StartError::Empty => Custom(Status::BadRequest, String::from("no data to replay")),
+        StartError::InUse => Custom(
+            Status::Conflict,
+            format!("device id '{device}' is already in use"),
+        ),
+    }
+}
+
+// Replay an uploaded Arrow IPC, Parquet or CSV file without storing it
+#[post("/replays/upload?<device>&<speed>&<repeat>", data = "<data>")]
+async fn replay_upload(
+    device: &str,
+    speed: Option<f64>,
+    repeat: Option<bool>,
+    data: Data<'_>,
+    limits: &Limits,
+    pipeline: &State<Pipeline>,
+    replays: &State<Replays>,
+) -> Result<Option<Json<ReplayStatus>>, Custom<String>> {
+    let speed = speed.unwrap_or(1.0);
+    if !replay::valid_speed(speed) {
+        return Err(Custom(Status::BadRequest, format!("invalid speed {speed}")));
+    }
+    let Some(bytes) = data
+        .open(limits.get("file").unwrap_or(Limits::FILE))
+        .into_bytes()
+        .await
+        .ok()
+        .filter(|bytes| bytes.is_complete())
+    else {
+        return Ok(None);
+    };
+    // Checked like an import, which also converts a time in seconds
+    let frame = read_upload(bytes.into_inner()).await?;
+
+    replays
+        .start(
+            pipeline,
+            device.to_string(),
+            String::from("upload"),
+            frame,
+            speed,
+            repeat.unwrap_or(false),
+        )
+        .await
+        .map(|status| Some(Json(status)))
+        .map_err(|e| replay_error(e, device))
+}
+
+#[post("/replays/<device>/control?<paused>&<speed>&<seek>")]
+async fn replay_control(
+    device: &str,
+    paused: Option<bool>,
+    speed: Option<f64>,
+    seek: Option<f64>,
+    replays: &State<Replays>,
+) -> Option<Json<ReplayStatus>> {
+    replays.update(device, paused, speed, seek).await.map(Json)
+}
+
+#[delete("/replays/<device>")]
+async fn replay_stop(
+    device: &str,
+    state: &State<Connections>,
+    replays: &State<Replays>,
+) -> Option<Json<ReplayStatus>> {
+    replays.stop(state, device).await.map(Json)
+}
+
+#[get("/alerts/rules")]
+async fn alert_rules(alerts: &State<Alerts>) -> Json<Vec<Rule>> {
+    Json(alerts.rules().await)
+}
+
+#[get("/alerts/rules/<id>")]
+async fn alert_rule(id: u64, alerts: &State<Alerts>) -> Option<Json<Rule>> {
+    alerts.rule(id).await.map(Json)
+}
+
+#[post("/alerts/rules", data = "<rule>")]
+async fn alert_rule_create(
+    rule: Json<Rule>,
+    alerts: &State<Alerts>,
+) -> Result<Json<Rule>, BadRequest<String>> {
+    rule.validate().map_err(BadRequest)?;
+    Ok(Json(alerts.add_rule(rule.into_inner()).await))
+}
+
+#[put("/alerts/rules/<id>", data = "<rule>")]
+async fn alert_rule_update(
+    id: u64,
+    rule: Json<Rule>,
+    alerts: &State<Alerts>,
+) -> Result<Option<Json<Rule>>, BadRequest<String>> {
+    rule.validate().map_err(BadRequest)?;
+    Ok(alerts.update_rule(id, rule.into_inner()).await.map(Json))
+}
+
+#[delete("/alerts/rules/<id>")]
+async fn alert_rule_delete(id: u64, alerts: &State<Alerts>) -> Option<Json<Rule>> {
+    alerts.remove_rule(id).await.map(Json)
+}
+
+#[get("/alerts/history?<limit>")]
+async fn alert_history(limit: Option<usize>, alerts: &State<Alerts>) -> Json<Vec<Alert>> {
+    Json(alerts.history(limit.unwrap_or(100)).await)
+}
+
+#[get("/sensor/<id>/commands")]
+async fn sensor_commands(id: &str, commands: &State<Commands>) -> Json<Vec<CommandRecord>> {
+    Json(commands.history(id).await)
+}
+
+// Send a command to a device connected over WebSocket
+#[post("/sensor/<id>/commands", data = "<command>")]
+async fn sensor_command_send(
+    id: &str,
+    command: Json<CommandRequest>,
+    commands: &State<Commands>,
+) -> Option<Json<CommandRecord>> {
+    commands.send(id, command.into_inner()).await.map(Json)
+}
+
+// Reply to a hello if the device expects it, and close the socket if the
+// hello was rejected
+async fn answer_hello(
+    stream: &mut ws::stream::DuplexStream,
+    hello: Result<Hello, String>,
+    expects_reply: bool,
+) -> Result<Option<Hello>, ws::result::Error> {
+    use rocket::futures::SinkExt;
+
+    if expects_reply {
+        stream.send(ws::Message::Text(Hello::reply(&hello))).await?;
+    }
+    match hello {
+        Ok(hello) => Ok(Some(hello)),
+        Err(e) => {
+            log::warn!("Rejected device hello: {e}");
+            stream
+                .send(ws::Message::Close(Some(CloseFrame {
+                    code: CloseCode::Protocol,
+                    reason: close_reason(&e).into(),
+                })))
+                .await?;
+            Ok(None)
+        }
+    }
+}
+
 #[get("/ws")]
-async fn ws_data(ws: ws::WebSocket, state: &State<Connections>) -> ws::Channel<'_> {
+async fn ws_data<'r>(
+    ws: ws::WebSocket,
+    state: &'r State<Connections>,
+    pipeline: &'r State<Pipeline>,
+    commands: &'r State<Commands>,
+    identity: &'r State<DeviceIdentity>,
+    throttle: &'r State<Throttle>,
+    shutdown: Shutdown,
+    certificate: Option<Certificate<'_>>,
+    remote: Option<SocketAddr>,
+) -> ws::Channel<'r> {
     use rocket::futures::{SinkExt, StreamExt};
 
+    let common_name = certificate
+        .as_ref()
+        .and_then(|c| c.subject().common_name())
+        .map(String::from);
+
+    let ws = ws.config(throttle.websocket_config());
     ws.channel(move |mut stream| {
         Box::pin(async move {
-            // First thing a sensor must send is its ID as text
-            let id = match stream.next().await {
-                Some(Ok(ws::Message::Text(id))) => id,
-                _ => {
-                    stream.send(ws::Message::Close(None)).await?;
-                    return Ok(());
-                }
+            let read_hello = |text: &str| {
+                let (hello, expects_reply) = Hello::parse(text);
+                let hello =
+                    hello.and_then(|hello| identity.verify(hello, common_name.as_deref()));
+                (hello, expects_reply)
             };
 
-            // Register the connection as active
-            {
-                let mut lock = state.connections.lock().await;
-                if let Some(connection) = lock.get_mut(&id) {
-                    connection.active = true;
-                } else {
-                    let mut new_connection = Connection::new();
-                    new_connection.active = true;
-                    lock.insert(id.clone(), new_connection);
-                }
-            }
-
-            // Process data as it comes in. On timeout send a courtesy close, then
-            // drop the connection.
-            loop {
-                match timeout(std::time::Duration::from_secs(10), stream.next()).await {
-                    Err(_) => {
-                        // This means we timed out
+            // With client certificates the common name is the device id, so the
+            // session opens right away and the hello is optional. Otherwise the
+            // first thing a sensor must send is a hello as JSON text, or just
+            // its ID as text for devices predating the handshake.
+            let certified = match (**identity, &common_name) {
+                (DeviceIdentity::Certificate, Some(name)) => Some(name.clone()),
+                _ => None,
+            };
+            let (hello, expects_reply) = match &certified {
+                Some(name) => (identity.verify(Hello::legacy(name), Some(name)), false),
+                None => match stream.next().await {
+                    Some(Ok(ws::Message::Text(text))) => read_hello(&text),
+                    _ => {
                         stream.send(ws::Message::Close(None)).await?;
-                        break;
+                        return Ok(());
                     }
-                    Ok(None) => {
-                        // This means the stream iterator has ended, which shouldn't
-                        // actually happen
-                        stream.send(ws::Message::Close(None)).await?;
-                        break;
+                },
+            };
+            let Some(mut hello) = answer_hello(&mut stream, hello, expects_reply).await? else {
+                return Ok(());
+            };
+            let id = hello.device_id.clone();
+
+            // Register the connection as active, unless the duplicate policy
+            // refuses another session for this id
+            let source = remote.map_or(String::from("ws"), |r| format!("ws://{r}"));
+            let Some(mut session) = state.open(&id, &source).await else {
+                log::warn!("Rejected duplicate connection for {id} from {source}");
+                stream
+                    .send(ws::Message::Close(Some(CloseFrame {
+                        code: CloseCode::Policy,
+                        reason: "device id already connected".into(),
+                    })))
+                    .await?;
+                return Ok(());
+            };
+            state.set_hello(&id, hello.clone()).await;
+
+            // Process data as it comes in and forward commands for the device.
+            // On timeout send a courtesy close, then drop the connection.
+            // The session is closed however the loop ends.
+            let result: Result<(), ws::result::Error> = async {
+                // A certified device may still send a hello first. Commands are
+                // only forwarded after it, so it is not taken for an
+                // acknowledgement.
+                let mut pending = None;
+                if certified.is_some() {
+                    match timeout(std::time::Duration::from_secs(10), stream.next()).await {
+                        Ok(Some(Ok(ws::Message::Text(text)))) => {
+                            let (announced, expects_reply) = read_hello(&text);
+                            let Some(announced) =
+                                answer_hello(&mut stream, announced, expects_reply).await?
+                            else {
+                                return Ok(());
+                            };
+                            hello = announced;
+                            state.set_hello(&id, hello.clone()).await;
+                        }
+                        first => pending = Some(first),
                     }
-                    Ok(Some(message)) => {
-                        match message {
-                            // Stop processing on receiving a close frame
-                            Ok(ws::Message::Close(_)) => {
+                }
+                let mut outgoing = commands.register(&id, session.id).await;
+
+                rocket::tokio::pin!(shutdown);
+                loop {
+                    let message = match pending.take() {
+                        Some(message) => message,
+                        None => select! {
+                            message = timeout(std::time::Duration::from_secs(10), stream.next()) => message,
+                            Some(command) = outgoing.recv() => {
+                                stream.send(ws::Message::Binary(command)).await?;
+                                continue;
+                            }
+                            _ = &mut session.kicked => {
+                                stream
+                                    .send(ws::Message::Close(Some(CloseFrame {
+                                        code: CloseCode::Policy,
+                                        reason: "replaced by a newer connection".into(),
+                                    })))
+                                    .await?;
+                                break;
+                            }
+                            _ = &mut shutdown => {
+                                stream
+                                    .send(ws::Message::Close(Some(CloseFrame {
+                                        code: CloseCode::Away,
+                                        reason: "server going away".into(),
+                                    })))
+                                    .await?;
                                 break;
                             }
-                            // Decode received data and add it to the dataframe for
-                            // this connection
-                            Ok(ws::Message::Binary(data)) => {
-                                if let Some(frame) = proto::SensorData::decode(Bytes::from(data))
-                                    .ok()
-                                    .and_then(|d| d.frame().ok())
-                                {
-                                    let mut lock = state.connections.lock().await;
-                                    if let Some(connection) = Connections::get(&mut lock, &id) {
-                                        _ = connection.append_data(frame).and_then(|_| {
-                                            connection
-                                                .discard_older_than(chrono::Duration::minutes(5))
-                                        });
+                        },
+                    };
+                    match message {
+                        Err(_) => {
+                            // This means we timed out
+                            stream.send(ws::Message::Close(None)).await?;
+                            break;
+                        }
+                        Ok(None) => {
+                            // This means the stream iterator has ended, which shouldn't
+                            // actually happen
+                            stream.send(ws::Message::Close(None)).await?;
+                            break;
+                        }
+                        Ok(Some(message)) => {
+                            match message {
+                                // Stop processing on receiving a close frame
+                                Ok(ws::Message::Close(_)) => {
+                                    break;
+                                }
+                                // Text after the hello acknowledges a command
+                                Ok(ws::Message::Text(text)) => {
+                                    commands.acknowledge(&id, &text).await;
+                                }
+                                // Decode received data and add it to the dataframe for
+                                // this connection
+                                Ok(ws::Message::Binary(data)) => {
+                                    let Some(frame) = hello.decode(&data) else {
+                                        continue;
+                                    };
+                                    match throttle.check(&id, data.len(), frame.height()).await {
+                                        Verdict::Accept => {
+                                            pipeline.ingest(&id, frame).await;
+                                        }
+                                        Verdict::Downsample(step) => {
+                                            pipeline
+                                                .ingest(&id, throttle::downsample(&frame, step))
+                                                .await;
+                                        }
+                                        Verdict::Disconnect => {
+                                            log::warn!("Disconnecting {id} for exceeding its rate limits");
+                                            stream
+                                                .send(ws::Message::Close(Some(CloseFrame {
+                                                    code: CloseCode::Policy,
+                                                    reason: "rate limit exceeded".into(),
+                                                })))
+                                                .await?;
+                                            break;
+                                        }
+                                        Verdict::Drop => {}
                                     }
                                 }
+                                // The socket refuses messages over the size
+                                // limit before buffering them, which leaves
+                                // the stream unusable
+                                Err(ws::result::Error::Capacity(e)) => {
+                                    log::warn!("Disconnecting {id}: {e}");
+                                    throttle.oversized(&id).await;
+                                    _ = stream
+                                        .send(ws::Message::Close(Some(CloseFrame {
+                                            code: CloseCode::Size,
+                                            reason: "message too large".into(),
+                                        })))
+                                        .await;
+                                    break;
+                                }
+                                _ => {}
                             }
-                            _ => {}
                         }
-                    }
-                };
-            }
-
-            {
-                let mut lock = state.connections.lock().await;
-                if let Some(connection) = Connections::get(&mut lock, &id) {
-                    connection.active = false;
+                    };
                 }
+                Ok(())
             }
+            .await;
+
+            commands.unregister(&id, session.id).await;
+            state.close(&id, session.id).await;
 
-            Ok(())
+            result
         })
     })
 }
@@ -0,0 +1,184 @@
+use crate::connection::Connection;
+
+use rocket::serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+// How buffered data is reduced to fit a budget
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum Reduction {
+    // Drop the oldest samples
+    #[default]
+    Evict,
+    // Thin out the older samples, keeping the newest at full resolution
+    Downsample,
+}
+
+// Budgets for the live data held in memory, in bytes. Unset budgets are
+// unlimited.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct MemoryConfig {
+    #[serde(default)]
+    pub device_bytes: Option<usize>,
+    #[serde(default)]
+    pub total_bytes: Option<usize>,
+    #[serde(default)]
+    pub reduction: Reduction,
+}
+
+impl MemoryConfig {
+    // A budget of zero would evict every sample as soon as it arrives
+    pub fn validate(&self) -> Result<(), String> {
+        let budgets = [
+            ("device_bytes", self.device_bytes),
+            ("total_bytes", self.total_bytes),
+        ];
+        for (name, budget) in budgets {
+            if budget == Some(0) {
+                return Err(format!("memory.{name} must be positive, not 0"));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct DeviceMemory {
+    pub bytes: usize,
+    pub samples: usize,
+    pub events: usize,
+    pub sessions: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct MemoryReport {
+    pub total_bytes: usize,
+    pub budget: MemoryConfig,
+    pub devices: BTreeMap<String, DeviceMemory>,
+}
+
+// Shrink the data of `id` to the per-device budget, then all devices in
+// proportion to their usage if together they exceed the total budget
+pub fn enforce(connections: &mut HashMap<String, Connection>, id: &str, config: &MemoryConfig) {
+    if let (Some(budget), Some(connection)) = (config.device_bytes, connections.get_mut(id)) {
+        if let Err(e) = connection.shrink_to(budget, config.reduction) {
+            log::warn!("Failed to reduce buffered data of {id}: {e}");
+        }
+    }
+
+    let Some(budget) = config.total_bytes else {
+        return;
+    };
+    let total = connections
+        .values()
+        .map(|c| c.memory_usage())
+        .sum::<usize>();
+    if total <= budget {
+        return;
+    }
+    let share = budget as f64 / total as f64;
+    for (id, connection) in connections.iter_mut() {
+        let target = (connection.memory_usage() as f64 * share) as usize;
+        if let Err(e) = connection.shrink_to(target, config.reduction) {
+            log::warn!("Failed to reduce buffered data of {id}: {e}");
+        }
+    }
+}
+
+pub fn report(connections: &HashMap<String, Connection>, config: &MemoryConfig) -> MemoryReport {
+    let devices = connections
+        .iter()
+        .map(|(id, c)| {
+            (
+                id.clone(),
+                DeviceMemory {
+                    bytes: c.memory_usage(),
+                    samples: c.recent_data().height(),
+                    events: c.events().len(),
+                    sessions: c.sessions().len(),
+                },
+            )
+        })
+        .collect::<BTreeMap<_, _>>();
+    MemoryReport {
+        total_bytes: devices.values().map(|d| d.bytes).sum(),
+        budget: config.clone(),
+        devices,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connection(samples: usize) -> Connection {
+        let times = (0..samples).map(|i| i as f32 * 0.01).collect::<Vec<_>>();
+        let mut connection = Connection::new();
+        connection
+            .merge_data(crate::frame::sensor_data(&times).frame().unwrap())
+            .unwrap();
+        connection
+    }
+
+    fn usage(connections: &HashMap<String, Connection>) -> Vec<usize> {
+        ["a", "b"]
+            .iter()
+            .map(|id| connections[*id].memory_usage())
+            .collect()
+    }
+
+    #[test]
+    fn shrinks_to_the_budgets() {
+        let mut connections = HashMap::from([
+            (String::from("a"), connection(1000)),
+            (String::from("b"), connection(1000)),
+        ]);
+        let full = usage(&connections)[0];
+
+        enforce(&mut connections, "a", &MemoryConfig::default());
+        assert_eq!(usage(&connections), [full, full]);
+
+        // Only the device that received data is held to the device budget
+        let config = MemoryConfig {
+            device_bytes: Some(full / 2),
+            ..Default::default()
+        };
+        enforce(&mut connections, "a", &config);
+        let [a, b] = usage(&connections)[..] else {
+            unreachable!()
+        };
+        assert!(a > 0 && a <= full / 2, "{a}");
+        assert_eq!(b, full);
+
+        // All devices share the total budget
+        let config = MemoryConfig {
+            total_bytes: Some(full),
+            ..Default::default()
+        };
+        enforce(&mut connections, "a", &config);
+        let [a, b] = usage(&connections)[..] else {
+            unreachable!()
+        };
+        assert!(a > 0 && b > 0 && a + b <= full, "{a} {b}");
+    }
+
+    #[test]
+    fn validates_the_budgets() {
+        assert!(MemoryConfig::default().validate().is_ok());
+        let config = |device_bytes, total_bytes| MemoryConfig {
+            device_bytes,
+            total_bytes,
+            ..Default::default()
+        };
+        assert!(config(Some(1 << 20), Some(1 << 30)).validate().is_ok());
+        assert!(config(Some(0), None).validate().is_err());
+        assert!(config(None, Some(0)).validate().is_err());
+
+        // Negative budgets don't parse at all
+        let figment = rocket::figment::Figment::from(("device_bytes", -1));
+        assert!(figment.extract::<MemoryConfig>().is_err());
+    }
+}
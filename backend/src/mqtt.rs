@@ -0,0 +1,331 @@
+use crate::connection::Presence;
+use crate::hello::Hello;
+use crate::ingest::Pipeline;
+
+use rocket::{
+    serde::Deserialize,
+    tokio::{
+        select,
+        time::{interval, sleep},
+    },
+};
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
+use std::time::Duration as StdDuration;
+
+fn port() -> u16 {
+    1883
+}
+
+fn client_id() -> String {
+    String::from("hecate-server")
+}
+
+fn topic_prefix() -> String {
+    String::from("hecate")
+}
+
+fn idle_timeout_secs() -> u64 {
+    10
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct MqttConfig {
+    // Broker to subscribe to
+    pub host: String,
+    #[serde(default = "port")]
+    pub port: u16,
+    #[serde(default = "client_id")]
+    pub client_id: String,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    // Devices publish to "<topic_prefix>/<device_id>/data", and their hello to
+    // "<topic_prefix>/<device_id>/hello"
+    #[serde(default = "topic_prefix")]
+    pub topic_prefix: String,
+    // A device is considered disconnected after this long without data
+    #[serde(default = "idle_timeout_secs")]
+    pub idle_timeout_secs: u64,
+}
+
+impl MqttConfig {
+    // The device of a topic ending in `/<kind>`, e.g. "data"
+    fn device_id<'a>(&self, topic: &'a str, kind: &str) -> Option<&'a str> {
+        topic
+            .strip_prefix(self.topic_prefix.as_str())?
+            .strip_prefix('/')?
+            .strip_suffix(kind)?
+            .strip_suffix('/')
+            .filter(|id| !id.is_empty() && !id.contains('/'))
+    }
+}
+
+// Take in a message published to `topic`, if it is the hello or holds samples
+// of a device. Samples are decoded in the protocol of the device's last hello.
+// Returns whether it was taken in.
+async fn receive(
+    config: &MqttConfig,
+    presence: &mut Presence,
+    pipeline: &Pipeline,
+    topic: &str,
+    payload: &[u8],
+) -> bool {
+    if let Some(id) = config.device_id(topic, "hello") {
+        let hello = std::str::from_utf8(payload)
+            .map_err(|e| e.to_string())
+            .and_then(|text| Hello::announced(id, text));
+        return match hello {
+            Ok(hello) if presence.seen(id).await => {
+                pipeline.connections.set_hello(id, hello).await;
+                true
+            }
+            Ok(_) => false,
+            Err(e) => {
+                log::warn!("Rejected MQTT hello of {id}: {e}");
+                false
+            }
+        };
+    }
+
+    let Some(id) = config.device_id(topic, "data") else {
+        return false;
+    };
+    let Some(frame) = pipeline.decode(id, payload).await else {
+        return false;
+    };
+    if !presence.seen(id).await {
+        return false;
+    }
+    pipeline.ingest_limited(id, payload.len(), frame).await
+}
+
+// Subscribe to the device topics and feed received samples into the pipeline,
+// reconnecting whenever the broker goes away
+pub async fn run(config: MqttConfig, pipeline: Pipeline) {
+    let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+    options.set_keep_alive(StdDuration::from_secs(5));
+    options.set_max_packet_size(1 << 20, 1 << 20);
+    if let Some(username) = &config.username {
+        options.set_credentials(username, config.password.as_deref().unwrap_or_default());
+    }
+
+    let (client, mut eventloop) = AsyncClient::new(options, 16);
+    let topics = ["data", "hello"].map(|kind| format!("{}/+/{kind}", config.topic_prefix));
+    let mut presence = Presence::new(
+        pipeline.connections.clone(),
+        "mqtt",
+        StdDuration::from_secs(config.idle_timeout_secs),
+    );
+    let mut ticker = interval(StdDuration::from_secs(1));
+
+    loop {
+        select! {
+            event = eventloop.poll() => match event {
+                // Subscriptions do not survive a reconnect with a clean session
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    log::info!("MQTT connected to {}:{}", config.host, config.port);
+                    for topic in &topics {
+                        if let Err(e) = client.try_subscribe(topic, QoS::AtMostOnce) {
+                            log::warn!("MQTT subscribe to {topic} failed: {e}");
+                        }
+                    }
+                }
+                Ok(Event::Incoming(Packet::Publish(publish))) => {
+                    receive(&config, &mut presence, &pipeline, &publish.topic, &publish.payload).await;
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    log::warn!("MQTT connection to {}:{} failed: {e}", config.host, config.port);
+                    sleep(StdDuration::from_secs(1)).await;
+                }
+            },
+            _ = ticker.tick() => presence.expire().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::sensor_data;
+    use crate::hello::wire;
+    use polars::prelude::DataType;
+    use prost::Message;
+
+    fn config(host: &str, topic_prefix: &str) -> MqttConfig {
+        MqttConfig {
+            host: host.to_string(),
+            port: port(),
+            client_id: client_id(),
+            username: None,
+            password: None,
+            topic_prefix: topic_prefix.to_string(),
+            idle_timeout_secs: idle_timeout_secs(),
+        }
+    }
+
+    // Two samples, 10 ms apart
+    fn payload() -> Vec<u8> {
+        let mut data = hecate_protobuf::SensorData::default();
+        for (i, sample) in [0.5, 0.51].into_iter().enumerate() {
+            data.samples.push(Default::default());
+            data.samples[i].time = sample;
+            data.samples[i].acceleration.x = 1.0;
+        }
+        data.encode_to_vec()
+    }
+
+    async fn height(pipeline: &Pipeline, id: &str) -> Option<usize> {
+        let lock = pipeline.connections.connections.lock().await;
+        lock.get(id).map(|c| c.recent_data().height())
+    }
+
+    #[test]
+    fn takes_the_device_id_from_the_topic() {
+        let config = config("localhost", "hecate");
+        let data = |topic| config.device_id(topic, "data");
+        assert_eq!(data("hecate/bench-1/data"), Some("bench-1"));
+        assert_eq!(data("hecate//data"), None);
+        assert_eq!(data("hecate/a/b/data"), None);
+        assert_eq!(data("hecate/bench-1/status"), None);
+        assert_eq!(data("hecate/bench-1xdata"), None);
+        assert_eq!(data("other/bench-1/data"), None);
+        assert_eq!(data("hecatex/bench-1/data"), None);
+        assert_eq!(
+            config.device_id("hecate/bench-1/hello", "hello"),
+            Some("bench-1")
+        );
+    }
+
+    #[rocket::async_test]
+    async fn ingests_published_samples() {
+        let config = config("localhost", "hecate");
+        let pipeline = Pipeline::for_test();
+        let mut presence = Presence::new(
+            pipeline.connections.clone(),
+            "mqtt",
+            StdDuration::from_secs(10),
+        );
+
+        let topic = "hecate/bench-1/data";
+        assert!(receive(&config, &mut presence, &pipeline, topic, &payload()).await);
+        assert_eq!(height(&pipeline, "bench-1").await, Some(2));
+
+        assert!(!receive(&config, &mut presence, &pipeline, topic, b"\xff\xff").await);
+        let status = "hecate/bench-1/status";
+        assert!(!receive(&config, &mut presence, &pipeline, status, &payload()).await);
+        assert_eq!(height(&pipeline, "bench-1").await, Some(2));
+    }
+
+    #[rocket::async_test]
+    async fn drops_samples_with_invalid_times() {
+        let config = config("localhost", "hecate");
+        let pipeline = Pipeline::for_test();
+        let mut presence = Presence::new(
+            pipeline.connections.clone(),
+            "mqtt",
+            StdDuration::from_secs(10),
+        );
+
+        let topic = "hecate/bench-1/data";
+        for time in [-1.0, f32::NAN, f32::INFINITY, f32::MAX] {
+            let payload = sensor_data(&[0.5, time]).encode_to_vec();
+            assert!(!receive(&config, &mut presence, &pipeline, topic, &payload).await);
+        }
+        // Not even counted as connected
+        assert_eq!(height(&pipeline, "bench-1").await, None);
+    }
+
+    #[rocket::async_test]
+    async fn decodes_declared_channels_after_a_hello() {
+        let config = config("localhost", "hecate");
+        let pipeline = Pipeline::for_test();
+        let mut presence = Presence::new(
+            pipeline.connections.clone(),
+            "mqtt",
+            StdDuration::from_secs(10),
+        );
+        let data = wire::ChannelData {
+            time: vec![0.0, 0.5],
+            channels: vec![wire::Values {
+                values: vec![3.0, 4.0],
+            }],
+        }
+        .encode_to_vec();
+
+        let hello = br#"{ "protocol": 2, "channels": [{ "name": "level", "type": "i64" }] }"#;
+        let other = br#"{ "device_id": "weather-2" }"#;
+        assert!(
+            !receive(
+                &config,
+                &mut presence,
+                &pipeline,
+                "hecate/weather-1/hello",
+                other
+            )
+            .await
+        );
+        assert!(
+            receive(
+                &config,
+                &mut presence,
+                &pipeline,
+                "hecate/weather-1/hello",
+                hello
+            )
+            .await
+        );
+        assert!(
+            receive(
+                &config,
+                &mut presence,
+                &pipeline,
+                "hecate/weather-1/data",
+                &data
+            )
+            .await
+        );
+
+        let lock = pipeline.connections.connections.lock().await;
+        let frame = lock["weather-1"].recent_data();
+        assert_eq!(frame.column("level").unwrap().dtype(), &DataType::Int64);
+    }
+
+    // Run a broker first, e.g. `mosquitto`, then `cargo test -- --ignored`
+    #[rocket::async_test]
+    #[ignore = "needs an MQTT broker on localhost:1883"]
+    async fn ingests_through_a_local_broker() {
+        let pipeline = Pipeline::for_test();
+        rocket::tokio::spawn(run(config("localhost", "hecate-test"), pipeline.clone()));
+
+        let options = MqttOptions::new("hecate-test-device", "localhost", port());
+        let (client, mut eventloop) = AsyncClient::new(options, 16);
+        rocket::tokio::spawn(async move {
+            loop {
+                if eventloop.poll().await.is_err() {
+                    sleep(StdDuration::from_millis(100)).await;
+                }
+            }
+        });
+
+        // Publish until the server has subscribed and received a message
+        for _ in 0..50 {
+            client
+                .publish(
+                    "hecate-test/broker-1/data",
+                    QoS::AtLeastOnce,
+                    false,
+                    payload(),
+                )
+                .await
+                .unwrap();
+            sleep(StdDuration::from_millis(100)).await;
+            if height(&pipeline, "broker-1").await.is_some_and(|h| h > 0) {
+                return;
+            }
+        }
+        panic!("no samples arrived through the broker");
+    }
+}
@@ -0,0 +1,240 @@
+use crate::atomic;
+use crate::connection::{Connection, Connections};
+use crate::events::Event;
+use crate::hello::Hello;
+
+use polars::prelude::*;
+use rocket::serde::{json, Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::PathBuf;
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct SavedDevice {
+    hello: Option<Hello>,
+    events: Vec<Event>,
+    // Columns of the device's data, which in the shared file may also be
+    // null throughout
+    #[serde(default)]
+    columns: Vec<String>,
+}
+
+// Live data and state of all devices, written on shutdown and read back on
+// the next start: the data of all devices as one Arrow IPC file with a
+// "device" column, and the rest as JSON
+pub struct Snapshot {
+    dir: PathBuf,
+}
+
+impl Snapshot {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    fn data_path(&self) -> PathBuf {
+        self.dir.join("live.arrow")
+    }
+
+    fn devices_path(&self) -> PathBuf {
+        self.dir.join("live.json")
+    }
+
+    pub async fn save(&self, connections: &Connections) -> PolarsResult<()> {
+        let lock = connections.connections.lock().await;
+        let mut devices = BTreeMap::new();
+        let mut frames = Vec::new();
+        for (id, connection) in lock.iter() {
+            devices.insert(
+                id.clone(),
+                SavedDevice {
+                    hello: connection.hello.clone(),
+                    events: connection.events().to_vec(),
+                    columns: connection
+                        .recent_data()
+                        .get_column_names()
+                        .into_iter()
+                        .map(String::from)
+                        .collect(),
+                },
+            );
+            let data = connection.recent_data();
+            if data.height() > 0 {
+                frames.push(
+                    data.clone()
+                        .lazy()
+                        .with_column(lit(id.as_str()).alias("device")),
+                );
+            }
+        }
+        drop(lock);
+
+        std::fs::create_dir_all(&self.dir)?;
+        if frames.is_empty() {
+            _ = std::fs::remove_file(self.data_path());
+        } else {
+            let mut data = concat_lf_diagonal(frames, Default::default())?.collect()?;
+            atomic::write_with(&self.data_path(), |file| {
+                IpcWriter::new(file).finish(&mut data)
+            })?;
+        }
+        // Written last, as restoring starts from it
+        let text = json::to_string(&devices).map_err(std::io::Error::other)?;
+        atomic::write(&self.devices_path(), text)?;
+        Ok(())
+    }
+
+    // Load what `save` wrote as devices without sessions. The files stay until
+    // the next snapshot replaces them, so a crash before then loses nothing.
+    // Devices that are already known are left alone.
+    pub async fn restore(&self, connections: &Connections) -> PolarsResult<usize> {
+        let Some(devices) = self.devices()? else {
+            return Ok(0);
+        };
+        let data = self.data()?;
+
+        let mut lock = connections.connections.lock().await;
+        for (id, saved) in &devices {
+            if lock.contains_key(id) {
+                continue;
+            }
+            let mut connection = Connection::new();
+            connection.hello = saved.hello.clone();
+            connection.merge_events(saved.events.clone());
+            if let Some(data) = &data {
+                let data = device_data(data, id, &saved.columns)?;
+                if data.height() > 0 {
+                    connection.merge_data(data)?;
+                }
+            }
+            lock.insert(id.clone(), connection);
+        }
+        drop(lock);
+        Ok(devices.len())
+    }
+
+    // The saved live data of one device, if the snapshot has any
+    pub fn device(&self, id: &str) -> PolarsResult<Option<DataFrame>> {
+        let (Some(devices), Some(data)) = (self.devices()?, self.data()?) else {
+            return Ok(None);
+        };
+        let Some(saved) = devices.get(id) else {
+            return Ok(None);
+        };
+        let data = device_data(&data, id, &saved.columns)?;
+        Ok((data.height() > 0).then_some(data))
+    }
+
+    fn devices(&self) -> PolarsResult<Option<BTreeMap<String, SavedDevice>>> {
+        match std::fs::read_to_string(self.devices_path()) {
+            Ok(text) => Ok(Some(json::from_str(&text).map_err(std::io::Error::other)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn data(&self) -> PolarsResult<Option<DataFrame>> {
+        match File::open(self.data_path()) {
+            Ok(file) => Ok(Some(IpcReader::new(file).finish()?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+// The rows of one device with its own columns. Snapshots that did not record
+// them fall back to the columns that are not null throughout.
+fn device_data(data: &DataFrame, id: &str, columns: &[String]) -> PolarsResult<DataFrame> {
+    let rows = data
+        .clone()
+        .lazy()
+        .filter(col("device").eq(lit(id)))
+        .collect()?;
+    let columns = rows
+        .get_columns()
+        .iter()
+        .filter(|s| s.name() != "device")
+        .filter(|s| match columns {
+            [] => s.null_count() < rows.height(),
+            columns => columns.iter().any(|c| c == s.name()),
+        })
+        .map(|s| s.name().to_string())
+        .collect::<Vec<_>>();
+    rows.select(columns)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ingest::Pipeline;
+
+    fn frame(values: &[f64]) -> DataFrame {
+        let time = Series::new(
+            "time",
+            (0..values.len() as i64)
+                .map(|i| i * 1_000_000)
+                .collect::<Vec<_>>(),
+        )
+        .cast(&DataType::Duration(TimeUnit::Nanoseconds))
+        .unwrap();
+        DataFrame::new(vec![time, Series::new("acc_x", values)]).unwrap()
+    }
+
+    #[rocket::async_test]
+    async fn restores_what_was_saved_before_the_pipeline_stopped() {
+        let dir = std::env::temp_dir().join(format!("hecate-persist-{}", std::process::id()));
+        let snapshot = Snapshot::new(dir.clone());
+
+        let pipeline = Pipeline::for_test();
+        pipeline.connections.open("a", "test").await.unwrap();
+        pipeline.ingest("a", frame(&[1.0, 2.0])).await;
+        pipeline.stop();
+        pipeline.ingest("a", frame(&[3.0])).await;
+        snapshot.save(&pipeline.connections).await.unwrap();
+        assert!(!dir.join("live.arrow.tmp").exists());
+        assert!(!dir.join("live.json.tmp").exists());
+        assert_eq!(snapshot.device("a").unwrap().unwrap().height(), 2);
+        assert!(snapshot.device("b").unwrap().is_none());
+
+        let connections = Connections::new(Default::default());
+        assert_eq!(snapshot.restore(&connections).await.unwrap(), 1);
+        let lock = connections.connections.lock().await;
+        let connection = lock.get("a").unwrap();
+        assert!(!connection.active());
+        let values = connection.recent_data().column("acc_x").unwrap();
+        assert_eq!(values.f64().unwrap().sum(), Some(3.0));
+        drop(lock);
+
+        // Kept until the next snapshot, in case the server stops before it
+        assert!(snapshot.data_path().exists());
+        assert_eq!(snapshot.device("a").unwrap().unwrap().height(), 2);
+        let empty = Connections::new(Default::default());
+        snapshot.save(&empty).await.unwrap();
+        assert!(!snapshot.data_path().exists());
+        assert!(snapshot.device("a").unwrap().is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[rocket::async_test]
+    async fn keeps_columns_without_values() {
+        let dir = std::env::temp_dir().join(format!("hecate-persist-null-{}", std::process::id()));
+        let snapshot = Snapshot::new(dir.clone());
+
+        let connections = Connections::new(Default::default());
+        let mut lock = connections.connections.lock().await;
+        for (id, column) in [("a", "level"), ("b", "other")] {
+            let mut data = frame(&[1.0, 2.0]);
+            data.with_column(Series::new(column, [None::<f64>, None]))
+                .unwrap();
+            let mut connection = Connection::new();
+            connection.merge_data(data).unwrap();
+            lock.insert(id.to_string(), connection);
+        }
+        drop(lock);
+        snapshot.save(&connections).await.unwrap();
+
+        let data = snapshot.device("a").unwrap().unwrap();
+        assert_eq!(data.get_column_names(), ["time", "acc_x", "level"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,375 @@
+use crate::hello::ChannelSpec;
+
+use polars::prelude::*;
+use rocket::serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+
+// Bit flags stored in the "quality" column of every annotated sample
+pub const GAP: u32 = 1 << 0;
+pub const DUPLICATE: u32 = 1 << 1;
+pub const OUT_OF_ORDER: u32 = 1 << 2;
+pub const SATURATED: u32 = 1 << 3;
+pub const NAN: u32 = 1 << 4;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct QualityConfig {
+    // A step between two samples larger than this multiple of the nominal
+    // sample period is reported as a gap
+    pub gap_factor: f64,
+    // Absolute values at or beyond these limits are considered clipped, for
+    // the channels of the "acc", "mag" and "gyro" sensors of a device
+    pub acc_limit: f64,
+    pub mag_limit: f64,
+    pub gyro_limit: f64,
+}
+
+impl Default for QualityConfig {
+    fn default() -> Self {
+        Self {
+            gap_factor: 1.5,
+            acc_limit: 16.0 * 9.81,
+            mag_limit: 4900.0,
+            gyro_limit: 2000.0,
+        }
+    }
+}
+
+impl QualityConfig {
+    fn limit(&self, channel: &ChannelSpec) -> Option<f64> {
+        match channel.sensor.as_deref()? {
+            "acc" => Some(self.acc_limit),
+            "mag" => Some(self.mag_limit),
+            "gyro" => Some(self.gyro_limit),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct QualitySummary {
+    // Start of the summarized window in seconds, if summarizing per window
+    pub start: Option<f64>,
+    pub samples: usize,
+    pub gaps: usize,
+    pub missing_samples: usize,
+    pub duplicates: usize,
+    pub out_of_order: usize,
+    pub saturated: usize,
+    pub nan: usize,
+}
+
+impl QualitySummary {
+    fn add(&mut self, flags: u32, missing: usize) {
+        self.samples += 1;
+        if flags & GAP != 0 {
+            self.gaps += 1;
+            self.missing_samples += missing;
+        }
+        if flags & DUPLICATE != 0 {
+            self.duplicates += 1;
+        }
+        if flags & OUT_OF_ORDER != 0 {
+            self.out_of_order += 1;
+        }
+        if flags & SATURATED != 0 {
+            self.saturated += 1;
+        }
+        if flags & NAN != 0 {
+            self.nan += 1;
+        }
+    }
+}
+
+fn time_ns(frame: &DataFrame) -> PolarsResult<Vec<Option<i64>>> {
+    Ok(frame
+        .column("time")?
+        .cast(&DataType::Int64)?
+        .i64()?
+        .into_iter()
+        .collect())
+}
+
+// How many of the latest steps between samples the period is estimated from
+const PERIOD_STEPS: usize = 128;
+
+// Running estimate of a device's nominal sample period, the median of the
+// latest positive steps between its samples. Kept across frames, as a frame
+// may hold a single sample.
+#[derive(Debug, Clone, Default)]
+pub struct PeriodEstimate {
+    steps: VecDeque<i64>,
+}
+
+impl PeriodEstimate {
+    fn add(&mut self, step: i64) {
+        if step <= 0 {
+            return;
+        }
+        if self.steps.len() == PERIOD_STEPS {
+            self.steps.pop_front();
+        }
+        self.steps.push_back(step);
+    }
+
+    pub fn period(&self) -> Option<i64> {
+        if self.steps.is_empty() {
+            return None;
+        }
+        let mut steps = self.steps.iter().copied().collect::<Vec<_>>();
+        steps.sort_unstable();
+        Some(steps[steps.len() / 2])
+    }
+}
+
+// Median of the positive steps between consecutive timestamps
+fn nominal_period(times: impl Iterator<Item = i64>) -> Option<i64> {
+    let times = times.collect::<Vec<_>>();
+    let mut steps = times
+        .windows(2)
+        .map(|w| w[1] - w[0])
+        .filter(|step| *step > 0)
+        .collect::<Vec<_>>();
+    if steps.is_empty() {
+        return None;
+    }
+    steps.sort_unstable();
+    Some(steps[steps.len() / 2])
+}
+
+fn missing_samples(step: i64, period: Option<i64>) -> usize {
+    match period {
+        Some(period) if period > 0 => {
+            ((step as f64 / period as f64).round() as usize).saturating_sub(1)
+        }
+        _ => 0,
+    }
+}
+
+// Add a "quality" column flagging suspicious samples in a newly received frame.
+// `previous` is the latest timestamp already stored for the device, so that
+// gaps and ordering problems across frame boundaries are caught as well, and
+// `estimate` the device's period so far, which the frame's steps are added to.
+// Saturation is checked for the channels `schema` assigns to a sensor.
+pub fn annotate(
+    mut frame: DataFrame,
+    previous: Option<i64>,
+    estimate: &mut PeriodEstimate,
+    schema: &[ChannelSpec],
+    config: &QualityConfig,
+) -> PolarsResult<DataFrame> {
+    let times = time_ns(&frame)?;
+    let mut last = previous;
+    for time in times.iter().flatten().copied() {
+        if let Some(last) = last {
+            estimate.add(time - last);
+        }
+        last = Some(time);
+    }
+    let period = estimate.period();
+    let mut flags = vec![0u32; frame.height()];
+
+    let mut latest = previous;
+    for (flag, time) in flags.iter_mut().zip(&times) {
+        let Some(time) = *time else {
+            continue;
+        };
+        if let Some(latest) = latest {
+            if time == latest {
+                *flag |= DUPLICATE;
+            } else if time < latest {
+                *flag |= OUT_OF_ORDER;
+            } else if period.is_some_and(|p| (time - latest) as f64 > config.gap_factor * p as f64)
+            {
+                *flag |= GAP;
+            }
+        }
+        latest = Some(latest.map_or(time, |l| l.max(time)));
+    }
+
+    for column in frame.get_columns() {
+        if column.name() == "time" || !column.dtype().is_float() {
+            continue;
+        }
+        let limit = schema
+            .iter()
+            .find(|c| c.name == column.name())
+            .and_then(|c| config.limit(c));
+        let values = column.cast(&DataType::Float64)?;
+        for (flag, value) in flags.iter_mut().zip(values.f64()?) {
+            match value {
+                Some(v) if v.is_nan() => *flag |= NAN,
+                Some(v) if limit.is_some_and(|l| v.abs() >= l) => *flag |= SATURATED,
+                _ => {}
+            }
+        }
+    }
+
+    frame.with_column(Series::new("quality", flags))?;
+    Ok(frame)
+}
+
+// Walk an annotated frame and hand every sample's time, flags and estimated
+// number of preceding missing samples to `f`
+fn for_each_sample(frame: &DataFrame, mut f: impl FnMut(i64, u32, usize)) -> PolarsResult<()> {
+    if frame.height() == 0 {
+        return Ok(());
+    }
+    let times = time_ns(frame)?;
+    let flags = frame.column("quality")?.u32()?;
+    let period = nominal_period(times.iter().flatten().copied());
+
+    let mut latest: Option<i64> = None;
+    for (time, flags) in times.into_iter().zip(flags) {
+        let (Some(time), Some(flags)) = (time, flags) else {
+            continue;
+        };
+        let missing = latest.map_or(0, |l| missing_samples(time - l, period));
+        f(time, flags, missing);
+        latest = Some(latest.map_or(time, |l| l.max(time)));
+    }
+    Ok(())
+}
+
+pub fn summarize(frame: &DataFrame) -> PolarsResult<QualitySummary> {
+    let mut summary = QualitySummary::default();
+    for_each_sample(frame, |_, flags, missing| summary.add(flags, missing))?;
+    Ok(summary)
+}
+
+pub fn summarize_windows(
+    frame: &DataFrame,
+    interval: chrono::Duration,
+) -> PolarsResult<Vec<QualitySummary>> {
+    let interval = interval.num_nanoseconds().unwrap_or(i64::MAX).max(1);
+    let mut windows = BTreeMap::<i64, QualitySummary>::new();
+    for_each_sample(frame, |time, flags, missing| {
+        let start = time.div_euclid(interval) * interval;
+        windows
+            .entry(start)
+            .or_insert_with(|| QualitySummary {
+                start: Some(start as f64 * 1.0e-9),
+                ..Default::default()
+            })
+            .add(flags, missing);
+    })?;
+    Ok(windows.into_values().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hello::Hello;
+
+    fn imu() -> Vec<ChannelSpec> {
+        Hello::legacy("a").schema()
+    }
+
+    // A frame with samples at the given times in milliseconds
+    fn frame(times: &[i64], values: &[f32]) -> DataFrame {
+        let time = Series::new(
+            "time",
+            times.iter().map(|t| t * 1_000_000).collect::<Vec<_>>(),
+        )
+        .cast(&DataType::Duration(TimeUnit::Nanoseconds))
+        .unwrap();
+        DataFrame::new(vec![time, Series::new("acc_x", values)]).unwrap()
+    }
+
+    fn flags(frame: &DataFrame) -> Vec<u32> {
+        frame
+            .column("quality")
+            .unwrap()
+            .u32()
+            .unwrap()
+            .into_no_null_iter()
+            .collect()
+    }
+
+    #[test]
+    fn flags_duplicates_out_of_order_and_gaps() {
+        let frame = frame(&[0, 10, 10, 5, 20, 60, 70], &[0.0; 7]);
+        let annotated = annotate(
+            frame,
+            None,
+            &mut PeriodEstimate::default(),
+            &imu(),
+            &QualityConfig::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            flags(&annotated),
+            [0, 0, DUPLICATE, OUT_OF_ORDER, 0, GAP, 0]
+        );
+    }
+
+    #[test]
+    fn flags_gaps_between_single_sample_frames() {
+        let config = QualityConfig::default();
+        let mut estimate = PeriodEstimate::default();
+        let mut previous = None;
+        let mut all = Vec::new();
+        for time in [0, 10, 20, 30, 80, 90] {
+            let annotated = annotate(
+                frame(&[time], &[0.0]),
+                previous,
+                &mut estimate,
+                &imu(),
+                &config,
+            )
+            .unwrap();
+            all.extend(flags(&annotated));
+            previous = Some(time * 1_000_000);
+        }
+        assert_eq!(all, [0, 0, 0, 0, GAP, 0]);
+        assert_eq!(estimate.period(), Some(10_000_000));
+    }
+
+    #[test]
+    fn flags_saturated_and_nan_values() {
+        let config = QualityConfig::default();
+        let frame = frame(&[0, 10, 20], &[1.0, f32::NAN, -200.0]);
+        let annotated = annotate(
+            frame.clone(),
+            None,
+            &mut PeriodEstimate::default(),
+            &imu(),
+            &config,
+        )
+        .unwrap();
+        assert_eq!(flags(&annotated), [0, NAN, SATURATED]);
+
+        // The limit follows the sensor a channel is declared for, not its name
+        let (hello, _) = Hello::parse(
+            r#"{ "device_id": "a", "protocol": 2, "channels": [{ "name": "acc_x" }] }"#,
+        );
+        let schema = hello.unwrap().schema();
+        let annotated = annotate(
+            frame,
+            None,
+            &mut PeriodEstimate::default(),
+            &schema,
+            &config,
+        )
+        .unwrap();
+        assert_eq!(flags(&annotated), [0, NAN, 0]);
+    }
+
+    #[test]
+    fn summarizes_missing_samples() {
+        let frame = frame(&[0, 10, 20, 50, 60], &[0.0; 5]);
+        let annotated = annotate(
+            frame,
+            None,
+            &mut PeriodEstimate::default(),
+            &imu(),
+            &QualityConfig::default(),
+        )
+        .unwrap();
+        let summary = summarize(&annotated).unwrap();
+        assert_eq!(summary.samples, 5);
+        assert_eq!(summary.gaps, 1);
+        assert_eq!(summary.missing_samples, 2);
+    }
+}
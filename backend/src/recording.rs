@@ -0,0 +1,317 @@
+use crate::atomic;
+use crate::hello::{self, ChannelType};
+use polars::prelude::*;
+use std::fs::File;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+// The recording a device's uploaded backlog is kept in. Device ids may hold
+// any character, those a recording name can't are escaped as ".<hex>" per
+// byte, so distinct ids never share a recording.
+pub fn backlog_name(device: &str) -> String {
+    let mut name = String::from("backlog-");
+    for byte in device.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_') {
+            name.push(byte as char);
+        } else {
+            name.push_str(&format!(".{byte:02x}"));
+        }
+    }
+    name
+}
+
+// Stored recordings, one Arrow IPC file per recording in the recordings directory
+#[derive(Clone)]
+pub struct Recordings {
+    dir: PathBuf,
+}
+
+// Recording names double as file names, so keep them to a safe character set
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+impl Recordings {
+    pub fn new(dir: PathBuf) -> Self {
+        _ = std::fs::create_dir_all(&dir);
+        Self { dir }
+    }
+
+    fn path(&self, name: &str) -> Option<PathBuf> {
+        valid_name(name).then(|| self.dir.join(format!("{name}.arrow")))
+    }
+
+    pub fn list(&self) -> Vec<String> {
+        let mut names = std::fs::read_dir(&self.dir)
+            .map(|entries| {
+                entries
+                    .filter_map(|e| e.ok())
+                    .filter_map(|e| {
+                        e.file_name()
+                            .to_str()
+                            .and_then(|n| n.strip_suffix(".arrow"))
+                            .map(String::from)
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        names.sort();
+        names
+    }
+
+    pub fn exists(&self, name: &str) -> bool {
+        self.path(name).is_some_and(|p| p.is_file())
+    }
+
+    pub fn load(&self, name: &str) -> PolarsResult<DataFrame> {
+        let path = self
+            .path(name)
+            .ok_or_else(|| polars_err!(ComputeError: "invalid recording name '{}'", name))?;
+        IpcReader::new(File::open(path)?).finish()
+    }
+
+    pub fn save(&self, name: &str, frame: &mut DataFrame) -> PolarsResult<()> {
+        let path = self
+            .path(name)
+            .ok_or_else(|| polars_err!(ComputeError: "invalid recording name '{}'", name))?;
+        // Through a temporary file, so readers never see half a recording
+        atomic::write_with(&path, |file| IpcWriter::new(file).finish(frame))
+    }
+
+    // Merge `frame` into a recording, creating it if needed
+    pub fn append(&self, name: &str, frame: &DataFrame) -> PolarsResult<()> {
+        let mut merged = if self.exists(name) {
+            concat_lf_diagonal(
+                [self.load(name)?.lazy(), frame.clone().lazy()],
+                Default::default(),
+            )?
+            .unique_stable(Some(vec![String::from("time")]), UniqueKeepStrategy::First)
+            .sort(["time"], Default::default())
+            .collect()?
+        } else {
+            frame.clone()
+        };
+        self.save(name, &mut merged)
+    }
+
+    pub fn remove(&self, name: &str) -> bool {
+        self.path(name)
+            .is_some_and(|p| std::fs::remove_file(p).is_ok())
+    }
+}
+
+// Sensor channels of a frame as produced by `Frame::frame`, besides "time".
+// Imports keep them at its type so they line up with live data.
+pub const CHANNELS: [&str; 9] = [
+    "acc_x", "acc_y", "acc_z", "mag_x", "mag_y", "mag_z", "gyro_x", "gyro_y", "gyro_z",
+];
+
+// Read an uploaded recording, an Arrow IPC, Parquet or CSV file
+pub fn read_file(bytes: Vec<u8>) -> PolarsResult<DataFrame> {
+    if bytes.starts_with(b"PAR1") {
+        ParquetReader::new(Cursor::new(bytes)).finish()
+    } else if bytes.starts_with(b"ARROW1") {
+        IpcReader::new(Cursor::new(bytes)).finish()
+    } else {
+        CsvReader::new(Cursor::new(bytes)).has_header(true).finish()
+    }
+}
+
+// Write a recording to a file, in the format given by its extension. CSV has
+// "time" in seconds, as `read_file` and `validate` accept it back.
+pub fn write_file(frame: &mut DataFrame, path: &Path) -> PolarsResult<()> {
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+    match extension {
+        "csv" => {
+            let mut frame = frame
+                .clone()
+                .lazy()
+                .with_column(
+                    (col("time").cast(DataType::Int64).cast(DataType::Float64) * lit(1.0e-9))
+                        .alias("time"),
+                )
+                .collect()?;
+            CsvWriter::new(File::create(path)?)
+                .include_header(true)
+                .finish(&mut frame)
+        }
+        "parquet" => ParquetWriter::new(File::create(path)?)
+            .finish(frame)
+            .map(|_| ()),
+        "arrow" | "ipc" => IpcWriter::new(File::create(path)?).finish(frame),
+        _ => Err(polars_err!(
+            ComputeError: "unsupported file type '{}', use csv, parquet or arrow", extension
+        )),
+    }
+}
+
+// Check that an imported frame has the schema of live data: a "time" column
+// and channels as a device could declare them, with the IMU channels of
+// `Frame::frame` as complete Float32 triples. "time" may be given as a
+// duration or as a number of seconds, as in the protobuf samples, and must
+// not be negative. Derived columns are dropped.
+pub fn validate(frame: &DataFrame) -> PolarsResult<DataFrame> {
+    let time = match frame.column("time").map(|s| s.dtype()) {
+        Ok(DataType::Duration(_)) => col("time").cast(DataType::Duration(TimeUnit::Nanoseconds)),
+        Ok(dtype) if dtype.is_numeric() => (col("time").cast(DataType::Float64) * lit(1.0e9))
+            .cast(DataType::Int64)
+            .cast(DataType::Duration(TimeUnit::Nanoseconds)),
+        Ok(dtype) => {
+            return Err(polars_err!(
+                SchemaMismatch: "column 'time' has unsupported type {}", dtype
+            ))
+        }
+        Err(_) => return Err(polars_err!(SchemaMismatch: "missing columns: time")),
+    };
+
+    let mut channels = Vec::new();
+    for column in frame.get_columns() {
+        let name = column.name();
+        if name == "time" || name == "quality" || name.ends_with("_filtered") {
+            continue;
+        }
+        if !hello::valid_channel_name(name) {
+            return Err(polars_err!(SchemaMismatch: "invalid channel name '{}'", name));
+        }
+        let kind = match ChannelType::of(column.dtype()) {
+            Some(ChannelType::Bool) if CHANNELS.contains(&name) => None,
+            Some(_) if CHANNELS.contains(&name) => Some(ChannelType::F32),
+            kind => kind,
+        };
+        let Some(kind) = kind else {
+            return Err(polars_err!(
+                SchemaMismatch: "column '{}' has unsupported type {}", name, column.dtype()
+            ));
+        };
+        channels.push(col(name).cast(kind.dtype()));
+    }
+    if channels.is_empty() {
+        return Err(polars_err!(SchemaMismatch: "no channels besides 'time'"));
+    }
+    for sensor in CHANNELS.chunks(3) {
+        let present = sensor.iter().filter(|c| frame.column(c).is_ok()).count();
+        if present != 0 && present != sensor.len() {
+            return Err(polars_err!(
+                SchemaMismatch: "incomplete sensor, expected columns {}", sensor.join(", ")
+            ));
+        }
+    }
+
+    let frame = frame
+        .clone()
+        .lazy()
+        .select(std::iter::once(time).chain(channels).collect::<Vec<_>>())
+        .drop_nulls(Some(vec![col("time")]))
+        .sort(["time"], Default::default())
+        .collect()?;
+    let first = frame.column("time")?.cast(&DataType::Int64)?.i64()?.min();
+    if first.is_some_and(|t| t < 0) {
+        return Err(polars_err!(ComputeError: "column 'time' has negative values"));
+    }
+    Ok(frame)
+}
+
+// The raw sensor channels of a recording, without anything the ingest
+// pipeline derives from them
+pub fn raw_channels(frame: &DataFrame) -> PolarsResult<DataFrame> {
+    let columns = frame
+        .get_column_names()
+        .into_iter()
+        .filter(|name| *name != "quality" && !name.ends_with("_filtered"))
+        .collect::<Vec<_>>();
+    frame
+        .select(columns)?
+        .lazy()
+        .sort(["time"], Default::default())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn csv(text: &str) -> PolarsResult<DataFrame> {
+        read_file(text.as_bytes().to_vec()).and_then(|frame| validate(&frame))
+    }
+
+    #[test]
+    fn reads_csv_parquet_and_arrow() {
+        let mut frame = csv("time,acc_x,acc_y,acc_z\n0.0,1,2,3\n0.5,4,5,6\n").unwrap();
+
+        let mut parquet = Vec::new();
+        ParquetWriter::new(&mut parquet).finish(&mut frame).unwrap();
+        let mut arrow = Vec::new();
+        IpcWriter::new(&mut arrow).finish(&mut frame).unwrap();
+        for bytes in [parquet, arrow] {
+            assert_eq!(read_file(bytes).unwrap(), frame);
+        }
+    }
+
+    #[test]
+    fn converts_imports_to_the_live_schema() {
+        let frame = csv("time,acc_x,acc_y,acc_z,level,open,acc_x_filtered\n0.5,1,2,3,7,true,0\n0.0,4,5,6,8,false,0\n")
+            .unwrap();
+        assert_eq!(
+            frame.get_column_names(),
+            ["time", "acc_x", "acc_y", "acc_z", "level", "open"]
+        );
+        assert_eq!(
+            frame.column("time").unwrap().dtype(),
+            &DataType::Duration(TimeUnit::Nanoseconds)
+        );
+        assert_eq!(frame.column("acc_x").unwrap().dtype(), &DataType::Float32);
+        assert_eq!(frame.column("level").unwrap().dtype(), &DataType::Int64);
+        assert_eq!(frame.column("open").unwrap().dtype(), &DataType::Boolean);
+        // Sorted by time, given in seconds
+        let time = frame
+            .column("time")
+            .unwrap()
+            .cast(&DataType::Int64)
+            .unwrap();
+        assert_eq!(time.i64().unwrap().get(1), Some(500_000_000));
+    }
+
+    #[test]
+    fn rejects_imports_unlike_live_data() {
+        let invalid = [
+            "acc_x,acc_y,acc_z\n1,2,3\n",
+            "time\n0.0\n",
+            "time,acc_x\n0.0,1\n",
+            "time,acc_x,acc_y,acc_z\n-1.0,1,2,3\n",
+            "time,name\n0.0,bench\n",
+            "time,bad name\n0.0,1\n",
+        ];
+        for text in invalid {
+            assert!(csv(text).is_err(), "{text}");
+        }
+    }
+
+    #[test]
+    fn escapes_device_ids_in_backlog_names() {
+        assert_eq!(backlog_name("bench-1"), "backlog-bench-1");
+        assert_eq!(backlog_name("site:a b"), "backlog-site.3aa.20b");
+        assert_eq!(backlog_name("a.b"), "backlog-a.2eb");
+        assert_ne!(backlog_name("a.2eb"), backlog_name("a.b"));
+        for id in ["site:a b", "../x", "ä", "a/b"] {
+            assert!(valid_name(&backlog_name(id)), "{id}");
+        }
+    }
+
+    #[test]
+    fn saves_recordings_under_valid_names() {
+        let dir = std::env::temp_dir().join(format!("hecate-recording-{}", std::process::id()));
+        let recordings = Recordings::new(dir.clone());
+        let mut frame = csv("time,level\n0.0,1\n").unwrap();
+
+        assert!(recordings.save("../escape", &mut frame).is_err());
+        recordings.save("bench", &mut frame).unwrap();
+        assert_eq!(recordings.list(), ["bench"]);
+        assert_eq!(recordings.load("bench").unwrap(), frame);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
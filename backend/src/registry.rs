@@ -0,0 +1,148 @@
+use crate::atomic;
+
+use rocket::{
+    futures::lock::Mutex,
+    serde::{json, Deserialize, Serialize},
+};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+// Mounting orientation of the sensor relative to the object it is attached to,
+// in degrees
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Orientation {
+    pub roll: f64,
+    pub pitch: f64,
+    pub yaw: f64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct DeviceInfo {
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub location: Option<String>,
+    pub tags: Vec<String>,
+    pub model: Option<String>,
+    pub orientation: Option<Orientation>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Device {
+    pub id: String,
+    // Whether the device has an entry in the registry
+    pub known: bool,
+    pub online: bool,
+    #[serde(flatten)]
+    pub info: DeviceInfo,
+}
+
+// Metadata of known devices, persisted as JSON in the data directory
+#[derive(Clone)]
+pub struct Registry {
+    path: PathBuf,
+    devices: Arc<Mutex<BTreeMap<String, DeviceInfo>>>,
+}
+
+impl Registry {
+    // An empty registry if the file does not exist yet. A file that can't be
+    // read is an error rather than silently replaced on the next change.
+    pub fn load(path: PathBuf) -> Result<Self, String> {
+        let devices = match std::fs::read_to_string(&path) {
+            Ok(text) => json::from_str(&text)
+                .map_err(|e| format!("invalid device registry {}: {e}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => {
+                return Err(format!(
+                    "failed to read device registry {}: {e}",
+                    path.display()
+                ))
+            }
+        };
+        Ok(Self {
+            path,
+            devices: Arc::new(Mutex::new(devices)),
+        })
+    }
+
+    fn save(&self, devices: &BTreeMap<String, DeviceInfo>) {
+        let result = json::to_pretty_string(devices)
+            .map_err(std::io::Error::other)
+            .and_then(|text| atomic::write(&self.path, text));
+        if let Err(e) = result {
+            log::warn!(
+                "Failed to save device registry {}: {e}",
+                self.path.display()
+            );
+        }
+    }
+
+    pub async fn all(&self) -> BTreeMap<String, DeviceInfo> {
+        self.devices.lock().await.clone()
+    }
+
+    pub async fn get(&self, id: &str) -> Option<DeviceInfo> {
+        self.devices.lock().await.get(id).cloned()
+    }
+
+    pub async fn set(&self, id: &str, info: DeviceInfo) {
+        let mut devices = self.devices.lock().await;
+        devices.insert(id.to_string(), info);
+        self.save(&devices);
+    }
+
+    pub async fn remove(&self, id: &str) -> Option<DeviceInfo> {
+        let mut devices = self.devices.lock().await;
+        let removed = devices.remove(id);
+        if removed.is_some() {
+            self.save(&devices);
+        }
+        removed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("hecate-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[rocket::async_test]
+    async fn persists_devices() {
+        let dir = dir("registry");
+        let path = dir.join("devices.json");
+        let registry = Registry::load(path.clone()).unwrap();
+        assert!(registry.all().await.is_empty());
+
+        let info = DeviceInfo {
+            name: Some(String::from("Bench")),
+            tags: vec![String::from("lab")],
+            ..Default::default()
+        };
+        registry.set("bench-1", info.clone()).await;
+        registry.set("bench-2", DeviceInfo::default()).await;
+        registry.remove("bench-2").await;
+
+        let reloaded = Registry::load(path).unwrap();
+        assert_eq!(reloaded.get("bench-1").await, Some(info));
+        assert_eq!(reloaded.all().await.len(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_and_keeps_an_invalid_file() {
+        let dir = dir("registry-invalid");
+        let path = dir.join("devices.json");
+        std::fs::write(&path, "{\"bench-1\": {\"name\": ").unwrap();
+        assert!(Registry::load(path.clone()).is_err());
+        assert!(path.exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
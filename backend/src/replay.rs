@@ -0,0 +1,402 @@
+use crate::connection::{Connection, Connections, DuplicatePolicy, SessionHandle};
+use crate::ingest::Pipeline;
+
+use polars::prelude::*;
+use rocket::{
+    futures::lock::Mutex,
+    serde::{Deserialize, Serialize},
+    tokio::time::{interval, Instant},
+};
+use std::collections::HashMap;
+use std::time::Duration as StdDuration;
+
+fn speed() -> f64 {
+    1.0
+}
+
+// Whether a replay advances at this speed
+pub fn valid_speed(speed: f64) -> bool {
+    speed.is_finite() && speed > 0.0
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ReplayRequest {
+    pub recording: String,
+    // Virtual device id to replay under, derived from the recording if not given
+    #[serde(default)]
+    pub device: Option<String>,
+    #[serde(default = "speed")]
+    pub speed: f64,
+    #[serde(default)]
+    pub repeat: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ReplayStatus {
+    pub device: String,
+    pub source: String,
+    pub speed: f64,
+    pub paused: bool,
+    pub repeat: bool,
+    pub finished: bool,
+    // Playback position and total length in seconds
+    pub position: f64,
+    pub duration: f64,
+}
+
+// Why a replay could not be started
+#[derive(Debug, PartialEq)]
+pub enum StartError {
+    // The frame has no samples with a time
+    Empty,
+    // A device or a running replay already uses the id
+    InUse,
+}
+
+struct Replay {
+    status: ReplayStatus,
+    seek: Option<f64>,
+    stop: bool,
+}
+
+#[derive(Clone)]
+pub struct Replays {
+    replays: Arc<Mutex<HashMap<String, Replay>>>,
+}
+
+impl Replays {
+    pub fn new() -> Self {
+        Self {
+            replays: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub async fn list(&self) -> Vec<ReplayStatus> {
+        let replays = self.replays.lock().await;
+        let mut list = replays
+            .values()
+            .map(|r| r.status.clone())
+            .collect::<Vec<_>>();
+        list.sort_by(|a, b| a.device.cmp(&b.device));
+        list
+    }
+
+    pub async fn status(&self, device: &str) -> Option<ReplayStatus> {
+        let replays = self.replays.lock().await;
+        replays.get(device).map(|r| r.status.clone())
+    }
+
+    // Start feeding `frame` through the ingest pipeline as device `device`.
+    // Fails if the id belongs to any device other than a finished replay, so
+    // the data of a device that is offline is never replaced.
+    pub async fn start(
+        &self,
+        pipeline: &Pipeline,
+        device: String,
+        source: String,
+        frame: DataFrame,
+        speed: f64,
+        repeat: bool,
+    ) -> Result<ReplayStatus, StartError> {
+        let times = frame
+            .column("time")
+            .and_then(|s| s.cast(&DataType::Int64))
+            .ok()
+            .and_then(|s| s.i64().ok()?.into_iter().collect::<Option<Vec<_>>>())
+            .ok_or(StartError::Empty)?;
+        let (Some(&first), Some(&last)) = (times.first(), times.last()) else {
+            return Err(StartError::Empty);
+        };
+
+        let status = ReplayStatus {
+            device: device.clone(),
+            source,
+            speed,
+            paused: false,
+            repeat,
+            finished: false,
+            position: 0.0,
+            duration: (last - first) as f64 * 1.0e-9,
+        };
+
+        let session = {
+            let mut replays = self.replays.lock().await;
+            let mut lock = pipeline.connections.connections.lock().await;
+            let replayed = match replays.get(&device) {
+                Some(replay) if !replay.status.finished => return Err(StartError::InUse),
+                Some(_) => true,
+                None => false,
+            };
+            if !replayed && lock.contains_key(&device) {
+                return Err(StartError::InUse);
+            }
+            let connection = lock.entry(device.clone()).or_insert_with(Connection::new);
+            let session = connection
+                .open_session("replay", DuplicatePolicy::Reject)
+                .ok_or(StartError::InUse)?;
+            // Only ever the data of an earlier replay under this id
+            connection.reset_recent_data();
+            replays.insert(
+                device.clone(),
+                Replay {
+                    status: status.clone(),
+                    seek: None,
+                    stop: false,
+                },
+            );
+            session
+        };
+
+        let replays = self.clone();
+        let pipeline = pipeline.clone();
+        rocket::tokio::spawn(async move {
+            replays.run(pipeline, device, session, frame, times).await;
+        });
+        Ok(status)
+    }
+
+    pub async fn update(
+        &self,
+        device: &str,
+        paused: Option<bool>,
+        speed: Option<f64>,
+        seek: Option<f64>,
+    ) -> Option<ReplayStatus> {
+        let mut replays = self.replays.lock().await;
+        let replay = replays.get_mut(device)?;
+        if let Some(paused) = paused {
+            replay.status.paused = paused;
+        }
+        if let Some(speed) = speed.filter(|s| valid_speed(*s)) {
+            replay.status.speed = speed;
+        }
+        if let Some(seek) = seek {
+            replay.seek = Some(seek.clamp(0.0, replay.status.duration));
+            replay.status.position = replay.seek.unwrap();
+        }
+        Some(replay.status.clone())
+    }
+
+    // Stop a replay and remove its virtual device, so the id is free again
+    pub async fn stop(&self, connections: &Connections, device: &str) -> Option<ReplayStatus> {
+        let mut replays = self.replays.lock().await;
+        let replay = replays.get_mut(device)?;
+        let status = replay.status.clone();
+        if status.finished {
+            replays.remove(device);
+            Self::remove(connections, device).await;
+        } else {
+            // The replay task removes it once it notices
+            replay.stop = true;
+        }
+        Some(status)
+    }
+
+    async fn run(
+        &self,
+        pipeline: Pipeline,
+        device: String,
+        mut session: SessionHandle,
+        frame: DataFrame,
+        times: Vec<i64>,
+    ) {
+        let first = times[0];
+        let mut index = 0;
+        let mut position = 0i64;
+        let mut ticker = interval(StdDuration::from_millis(50));
+        let mut last_tick = Instant::now();
+        let mut stopped = false;
+
+        loop {
+            ticker.tick().await;
+            let elapsed = last_tick.elapsed();
+            last_tick = Instant::now();
+
+            let (paused, speed, repeat, seek) = {
+                let mut replays = self.replays.lock().await;
+                let Some(replay) = replays.get_mut(&device) else {
+                    break;
+                };
+                if replay.stop {
+                    replays.remove(&device);
+                    stopped = true;
+                    break;
+                }
+                // A live device with the same id took over
+                if session.is_closed() {
+                    replay.status.finished = true;
+                    break;
+                }
+                (
+                    replay.status.paused,
+                    replay.status.speed,
+                    replay.status.repeat,
+                    replay.seek.take(),
+                )
+            };
+
+            if let Some(seek) = seek {
+                position = (seek * 1.0e9) as i64;
+                index = times.partition_point(|t| *t < first + position);
+                Self::reset(&pipeline.connections, &device).await;
+            }
+            if paused {
+                continue;
+            }
+
+            position += (elapsed.as_secs_f64() * speed * 1.0e9) as i64;
+            let end = times.partition_point(|t| *t < first + position);
+            if end > index {
+                pipeline
+                    .ingest(&device, frame.slice(index as i64, end - index))
+                    .await;
+                index = end;
+            }
+
+            let finished = index >= times.len();
+            if finished && repeat {
+                index = 0;
+                position = 0;
+                Self::reset(&pipeline.connections, &device).await;
+            }
+
+            let mut replays = self.replays.lock().await;
+            if let Some(replay) = replays.get_mut(&device) {
+                replay.status.position = (position as f64 * 1.0e-9).min(replay.status.duration);
+                replay.status.finished = finished && !repeat;
+            }
+            if finished && !repeat {
+                break;
+            }
+        }
+
+        pipeline.connections.close(&device, session.id).await;
+        if stopped {
+            Self::remove(&pipeline.connections, &device).await;
+        }
+    }
+
+    // Remove a virtual device, unless a live device took over its id
+    async fn remove(connections: &Connections, device: &str) {
+        let mut lock = connections.connections.lock().await;
+        if lock.get(device).is_some_and(|c| !c.active()) {
+            lock.remove(device);
+        }
+    }
+
+    async fn reset(connections: &Connections, device: &str) {
+        let mut lock = connections.connections.lock().await;
+        if let Some(connection) = Connections::get(&mut lock, device) {
+            connection.reset_recent_data();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::tokio::time::sleep;
+
+    // Ten samples, 10 ms apart
+    fn frame() -> DataFrame {
+        let time = Series::new(
+            "time",
+            (0..10).map(|i| i * 10_000_000).collect::<Vec<i64>>(),
+        )
+        .cast(&DataType::Duration(TimeUnit::Nanoseconds))
+        .unwrap();
+        let level = Series::new("level", (0..10).collect::<Vec<i64>>());
+        DataFrame::new(vec![time, level]).unwrap()
+    }
+
+    async fn finished(replays: &Replays, device: &str) -> ReplayStatus {
+        loop {
+            let status = replays.status(device).await.unwrap();
+            if status.finished {
+                return status;
+            }
+            sleep(StdDuration::from_millis(20)).await;
+        }
+    }
+
+    async fn samples(pipeline: &Pipeline, device: &str) -> Option<usize> {
+        let mut lock = pipeline.connections.connections.lock().await;
+        Connections::get(&mut lock, device).map(|c| c.recent_data().height())
+    }
+
+    #[rocket::async_test]
+    async fn replays_a_frame_as_a_virtual_device() {
+        let (pipeline, replays) = (Pipeline::for_test(), Replays::new());
+        let source = String::from("bench");
+        let start = |device: &str| {
+            replays.start(
+                &pipeline,
+                device.to_string(),
+                source.clone(),
+                frame(),
+                10.0,
+                false,
+            )
+        };
+
+        let status = start("replay-1").await.unwrap();
+        assert!((status.duration - 0.09).abs() < 1e-9);
+        // Not twice at the same time
+        assert_eq!(start("replay-1").await.err(), Some(StartError::InUse));
+        finished(&replays, "replay-1").await;
+        assert_eq!(samples(&pipeline, "replay-1").await, Some(10));
+
+        // Once finished, the id may be replayed onto again
+        start("replay-1").await.unwrap();
+        finished(&replays, "replay-1").await;
+        assert_eq!(samples(&pipeline, "replay-1").await, Some(10));
+
+        // Stopping removes the virtual device
+        replays
+            .stop(&pipeline.connections, "replay-1")
+            .await
+            .unwrap();
+        assert_eq!(samples(&pipeline, "replay-1").await, None);
+        assert!(replays.list().await.is_empty());
+    }
+
+    #[rocket::async_test]
+    async fn never_replays_onto_another_device() {
+        let (pipeline, replays) = (Pipeline::for_test(), Replays::new());
+        let _live = pipeline.connections.open("bench-1", "udp").await.unwrap();
+        pipeline.ingest("bench-1", frame()).await;
+        let mut lock = pipeline.connections.connections.lock().await;
+        lock.insert(String::from("offline"), Connection::new());
+        drop(lock);
+
+        for device in ["bench-1", "offline"] {
+            let started = replays
+                .start(
+                    &pipeline,
+                    device.to_string(),
+                    String::from("bench"),
+                    frame(),
+                    1.0,
+                    false,
+                )
+                .await;
+            assert_eq!(started.err(), Some(StartError::InUse), "{device}");
+        }
+        assert_eq!(samples(&pipeline, "bench-1").await, Some(10));
+
+        let empty = frame().head(Some(0));
+        let started = replays
+            .start(
+                &pipeline,
+                String::from("empty"),
+                String::from("bench"),
+                empty,
+                1.0,
+                false,
+            )
+            .await;
+        assert_eq!(started.err(), Some(StartError::Empty));
+    }
+}
@@ -0,0 +1,449 @@
+use crate::connection::{Presence, SessionHandle};
+use crate::hello::Hello;
+use crate::ingest::Pipeline;
+
+use prost::Message;
+use rocket::{
+    futures::lock::Mutex,
+    serde::Deserialize,
+    tokio::{
+        io::AsyncReadExt,
+        net::{TcpListener, TcpStream, UdpSocket},
+        select,
+        time::{interval, timeout},
+    },
+};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration as StdDuration, Instant};
+
+fn rate_limit() -> f64 {
+    50.0
+}
+
+fn burst() -> f64 {
+    100.0
+}
+
+fn max_frame_size() -> usize {
+    1 << 20
+}
+
+fn idle_timeout_secs() -> u64 {
+    10
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct SocketConfig {
+    // Addresses to listen on, e.g. "0.0.0.0:9001", disabled if not given
+    #[serde(default)]
+    pub udp: Option<String>,
+    #[serde(default)]
+    pub tcp: Option<String>,
+    // Frames per second accepted from a single source address, and the number
+    // of frames it may send in a burst above that rate
+    #[serde(default = "rate_limit")]
+    pub rate_limit: f64,
+    #[serde(default = "burst")]
+    pub burst: f64,
+    // Larger TCP frames close the connection
+    #[serde(default = "max_frame_size")]
+    pub max_frame_size: usize,
+    #[serde(default = "idle_timeout_secs")]
+    pub idle_timeout_secs: u64,
+}
+
+impl SocketConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(self.rate_limit > 0.0 && self.rate_limit.is_finite()) {
+            return Err(format!(
+                "socket.rate_limit must be positive, not {}",
+                self.rate_limit
+            ));
+        }
+        // A bucket holding less than one frame never lets one through
+        if !(self.burst >= 1.0 && self.burst.is_finite()) {
+            return Err(format!(
+                "socket.burst must be at least 1, not {}",
+                self.burst
+            ));
+        }
+        Ok(())
+    }
+}
+
+// Data tagged with the id of the device that sent it, the unit of data on the
+// UDP and TCP listeners. `data` is a binary message as on the WebSocket, in the
+// protocol of the device's last hello, which an envelope may carry as well.
+#[derive(Clone, PartialEq, Message)]
+pub struct Envelope {
+    #[prost(string, tag = "1")]
+    pub device_id: String,
+    #[prost(bytes = "vec", tag = "2")]
+    pub data: Vec<u8>,
+    // JSON, as sent on the WebSocket
+    #[prost(string, optional, tag = "3")]
+    pub hello: Option<String>,
+}
+
+impl Envelope {
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        Self::decode(bytes).ok().filter(|e| !e.device_id.is_empty())
+    }
+
+    // Store the hello the envelope carries, then decode and ingest its data.
+    // Returns whether any data was taken in.
+    async fn receive(self, pipeline: &Pipeline) -> bool {
+        let id = &self.device_id;
+        if let Some(text) = &self.hello {
+            match Hello::announced(id, text) {
+                Ok(hello) => pipeline.connections.set_hello(id, hello).await,
+                Err(e) => {
+                    log::warn!("Rejected hello of {id}: {e}");
+                    return false;
+                }
+            }
+        }
+        if self.data.is_empty() {
+            return false;
+        }
+        let Some(frame) = pipeline.decode(id, &self.data).await else {
+            return false;
+        };
+        pipeline.ingest_limited(id, self.data.len(), frame).await
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+// Token bucket per source address
+#[derive(Clone)]
+struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: Arc<Mutex<HashMap<IpAddr, Bucket>>>,
+}
+
+impl RateLimiter {
+    fn new(rate: f64, burst: f64) -> Self {
+        Self {
+            rate,
+            burst,
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    async fn allow(&self, source: IpAddr) -> bool {
+        let mut buckets = self.buckets.lock().await;
+        let bucket = buckets.entry(source).or_insert(Bucket {
+            tokens: self.burst,
+            last: Instant::now(),
+        });
+        let now = Instant::now();
+        bucket.tokens =
+            (bucket.tokens + (now - bucket.last).as_secs_f64() * self.rate).min(self.burst);
+        bucket.last = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    // Forget sources whose bucket has refilled completely
+    async fn prune(&self) {
+        let mut buckets = self.buckets.lock().await;
+        let full = StdDuration::from_secs_f64(self.burst / self.rate);
+        buckets.retain(|_, b| b.last.elapsed() < full);
+    }
+}
+
+pub async fn run(config: SocketConfig, pipeline: Pipeline) {
+    let limiter = RateLimiter::new(config.rate_limit, config.burst);
+
+    if let Some(address) = config.tcp.clone() {
+        match TcpListener::bind(&address).await {
+            Ok(listener) => {
+                log::info!("Listening for sensor data on tcp://{address}");
+                rocket::tokio::spawn(run_tcp(
+                    listener,
+                    config.clone(),
+                    pipeline.clone(),
+                    limiter.clone(),
+                ));
+            }
+            Err(e) => log::warn!("Failed to listen on tcp://{address}: {e}"),
+        }
+    }
+
+    if let Some(address) = config.udp.clone() {
+        match UdpSocket::bind(&address).await {
+            Ok(socket) => {
+                log::info!("Listening for sensor data on udp://{address}");
+                rocket::tokio::spawn(run_udp(socket, config, pipeline, limiter));
+            }
+            Err(e) => log::warn!("Failed to listen on udp://{address}: {e}"),
+        }
+    }
+}
+
+// Every datagram holds one envelope
+async fn run_udp(
+    socket: UdpSocket,
+    config: SocketConfig,
+    pipeline: Pipeline,
+    limiter: RateLimiter,
+) {
+    let mut presence = Presence::new(
+        pipeline.connections.clone(),
+        "udp",
+        StdDuration::from_secs(config.idle_timeout_secs),
+    );
+    let mut ticker = interval(StdDuration::from_secs(1));
+    let mut buffer = vec![0u8; 65536];
+
+    loop {
+        select! {
+            received = socket.recv_from(&mut buffer) => {
+                let Ok((length, source)) = received else {
+                    continue;
+                };
+                if !limiter.allow(source.ip()).await {
+                    continue;
+                }
+                if let Some(envelope) = Envelope::parse(&buffer[..length]) {
+                    if presence.seen(&envelope.device_id).await {
+                        envelope.receive(&pipeline).await;
+                    }
+                }
+            }
+            _ = ticker.tick() => {
+                presence.expire().await;
+                limiter.prune().await;
+            }
+        }
+    }
+}
+
+async fn run_tcp(
+    listener: TcpListener,
+    config: SocketConfig,
+    pipeline: Pipeline,
+    limiter: RateLimiter,
+) {
+    loop {
+        let Ok((stream, _)) = listener.accept().await else {
+            continue;
+        };
+        rocket::tokio::spawn(handle_tcp(
+            stream,
+            config.clone(),
+            pipeline.clone(),
+            limiter.clone(),
+        ));
+    }
+}
+
+// Envelopes are prefixed with their length as a big endian u32. The devices
+// seen on a connection count as connected until it closes or times out.
+// Devices whose session was refused or kicked are ignored for the rest of the
+// connection.
+async fn handle_tcp(
+    mut stream: TcpStream,
+    config: SocketConfig,
+    pipeline: Pipeline,
+    limiter: RateLimiter,
+) {
+    let Ok(source) = stream.peer_addr().map(|a| a.ip()) else {
+        return;
+    };
+    let idle_timeout = StdDuration::from_secs(config.idle_timeout_secs);
+    let mut sessions = HashMap::<String, Option<SessionHandle>>::new();
+    let mut buffer = Vec::new();
+
+    loop {
+        let length = match timeout(idle_timeout, stream.read_u32()).await {
+            Ok(Ok(length)) => length as usize,
+            _ => break,
+        };
+        if length > config.max_frame_size {
+            log::warn!("Closing tcp connection from {source}: {length} byte frame too large");
+            break;
+        }
+        buffer.resize(length, 0);
+        if !matches!(
+            timeout(idle_timeout, stream.read_exact(&mut buffer)).await,
+            Ok(Ok(_))
+        ) {
+            break;
+        }
+        if !limiter.allow(source).await {
+            continue;
+        }
+
+        let Some(envelope) = Envelope::parse(&buffer) else {
+            continue;
+        };
+        let id = envelope.device_id.clone();
+        if !sessions.contains_key(&id) {
+            let session = pipeline
+                .connections
+                .open(&id, &format!("tcp://{source}"))
+                .await;
+            sessions.insert(id.clone(), session);
+        }
+        let session = sessions.get_mut(&id).unwrap();
+        if session.as_mut().is_some_and(|s| s.is_closed()) {
+            *session = None;
+        }
+        if session.is_some() {
+            envelope.receive(&pipeline).await;
+        }
+    }
+
+    for (id, session) in sessions {
+        if let Some(session) = session {
+            pipeline.connections.close(&id, session.id).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::sensor_data;
+    use crate::hello::wire;
+    use crate::throttle::{Backpressure, Throttle, ThrottleConfig};
+
+    fn config(rate_limit: f64, burst: f64) -> SocketConfig {
+        SocketConfig {
+            udp: None,
+            tcp: None,
+            rate_limit,
+            burst,
+            max_frame_size: max_frame_size(),
+            idle_timeout_secs: idle_timeout_secs(),
+        }
+    }
+
+    #[test]
+    fn rejects_rates_that_are_not_positive() {
+        assert!(config(rate_limit(), burst()).validate().is_ok());
+        assert!(config(0.0, burst()).validate().is_err());
+        assert!(config(-1.0, burst()).validate().is_err());
+        assert!(config(f64::NAN, burst()).validate().is_err());
+        assert!(config(rate_limit(), 0.0).validate().is_err());
+        assert!(config(rate_limit(), 0.5).validate().is_err());
+        assert!(config(rate_limit(), f64::INFINITY).validate().is_err());
+        assert!(config(rate_limit(), 1.0).validate().is_ok());
+    }
+
+    fn envelope(device_id: &str, data: Vec<u8>, hello: Option<&str>) -> Envelope {
+        Envelope {
+            device_id: device_id.to_string(),
+            data,
+            hello: hello.map(String::from),
+        }
+    }
+
+    async fn height(pipeline: &Pipeline, id: &str) -> Option<usize> {
+        let lock = pipeline.connections.connections.lock().await;
+        lock.get(id).map(|c| c.recent_data().height())
+    }
+
+    #[rocket::async_test]
+    async fn drops_hostile_envelopes() {
+        let pipeline = Pipeline::for_test();
+        let _session = pipeline.connections.open("bench-1", "udp").await.unwrap();
+        let data = |times: &[f32]| sensor_data(times).encode_to_vec();
+
+        assert!(
+            envelope("bench-1", data(&[0.0, 0.1]), None)
+                .receive(&pipeline)
+                .await
+        );
+        // Sample times that would not fit a duration must not panic the listener
+        for time in [-1.0, f32::NAN, f32::NEG_INFINITY, f32::MAX] {
+            let envelope = envelope("bench-1", data(&[time]), None);
+            assert!(!envelope.receive(&pipeline).await);
+        }
+        assert_eq!(height(&pipeline, "bench-1").await, Some(2));
+
+        let anonymous = envelope("", data(&[0.0]), None).encode_to_vec();
+        assert!(Envelope::parse(&anonymous).is_none());
+        assert!(Envelope::parse(&[0xff; 16]).is_none());
+    }
+
+    #[rocket::async_test]
+    async fn applies_the_device_rate_limits() {
+        let pipeline = Pipeline {
+            throttle: Throttle::new(ThrottleConfig {
+                messages_per_sec: Some(0.001),
+                burst_secs: 1000.0,
+                backpressure: Backpressure::Disconnect,
+                ..Default::default()
+            }),
+            ..Pipeline::for_test()
+        };
+        let _session = pipeline.connections.open("bench-1", "udp").await.unwrap();
+        let data = sensor_data(&[0.0]).encode_to_vec();
+
+        assert!(
+            envelope("bench-1", data.clone(), None)
+                .receive(&pipeline)
+                .await
+        );
+        assert!(!envelope("bench-1", data, None).receive(&pipeline).await);
+        let stats = pipeline.throttle.stats("bench-1").await.unwrap();
+        assert_eq!((stats.messages, stats.dropped_messages), (1, 1));
+    }
+
+    #[rocket::async_test]
+    async fn decodes_in_the_protocol_of_the_hello() {
+        let pipeline = Pipeline::for_test();
+        let _session = pipeline.connections.open("weather-1", "udp").await.unwrap();
+        let hello = r#"{ "protocol": 2, "channels": [{ "name": "level", "type": "i64" }] }"#;
+        let data = wire::ChannelData {
+            time: vec![0.0, 0.5],
+            channels: vec![wire::Values {
+                values: vec![3.0, 4.0],
+            }],
+        }
+        .encode_to_vec();
+
+        // A hello for another device is refused along with the data
+        let other = envelope(
+            "weather-1",
+            data.clone(),
+            Some(r#"{ "device_id": "weather-2" }"#),
+        );
+        assert!(!other.receive(&pipeline).await);
+        assert!(
+            !envelope("weather-1", Vec::new(), Some(hello))
+                .receive(&pipeline)
+                .await
+        );
+        assert!(envelope("weather-1", data, None).receive(&pipeline).await);
+        assert_eq!(height(&pipeline, "weather-1").await, Some(2));
+    }
+
+    #[rocket::async_test]
+    async fn limits_each_source_to_its_burst() {
+        let limiter = RateLimiter::new(0.001, 2.0);
+        let (a, b): (IpAddr, IpAddr) = ([10, 0, 0, 1].into(), [10, 0, 0, 2].into());
+        assert!(limiter.allow(a).await);
+        assert!(limiter.allow(a).await);
+        assert!(!limiter.allow(a).await);
+        assert!(limiter.allow(b).await);
+
+        // Buckets that are not yet full again are kept
+        limiter.prune().await;
+        assert_eq!(limiter.buckets.lock().await.len(), 2);
+    }
+}
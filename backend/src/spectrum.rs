@@ -0,0 +1,294 @@
+use polars::prelude::*;
+use rocket::{serde::Serialize, FromFormField};
+use rustfft::{num_complex::Complex, FftPlanner};
+use std::collections::BTreeMap;
+use std::f64::consts::PI;
+
+// Largest segment size, in samples
+pub const MAX_SIZE: usize = 1 << 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, FromFormField)]
+pub enum Window {
+    Rectangular,
+    Hann,
+    Hamming,
+    Blackman,
+}
+
+impl Window {
+    fn coefficients(&self, size: usize) -> Vec<f64> {
+        let n = (size.max(2) - 1) as f64;
+        (0..size)
+            .map(|i| {
+                let x = 2.0 * PI * i as f64 / n;
+                match self {
+                    Window::Rectangular => 1.0,
+                    Window::Hann => 0.5 - 0.5 * x.cos(),
+                    Window::Hamming => 0.54 - 0.46 * x.cos(),
+                    Window::Blackman => 0.42 - 0.5 * x.cos() + 0.08 * (2.0 * x).cos(),
+                }
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, FromFormField)]
+pub enum Mode {
+    Psd,
+    Spectrogram,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Spectrum {
+    pub sample_rate: f64,
+    pub frequencies: Vec<f64>,
+    // Welch power spectral density per channel, in unit²/Hz
+    pub channels: BTreeMap<String, Vec<f64>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Spectrogram {
+    pub sample_rate: f64,
+    // Center time of every segment in seconds
+    pub times: Vec<f64>,
+    pub frequencies: Vec<f64>,
+    // Power spectral density per channel, one row per segment
+    pub channels: BTreeMap<String, Vec<Vec<f64>>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(crate = "rocket::serde", untagged)]
+pub enum Analysis {
+    Psd(Spectrum),
+    Spectrogram(Spectrogram),
+}
+
+struct Segments {
+    sample_rate: f64,
+    frequencies: Vec<f64>,
+    times: Vec<f64>,
+    channels: BTreeMap<String, Vec<Vec<f64>>>,
+}
+
+// Channels analysed when none are requested explicitly
+fn default_channels(frame: &DataFrame) -> Vec<String> {
+    frame
+        .get_columns()
+        .iter()
+        .filter(|s| s.dtype().is_float())
+        .map(|s| s.name().to_string())
+        .collect()
+}
+
+fn column(frame: &DataFrame, name: &str) -> Option<Vec<f64>> {
+    let values = frame.column(name).ok()?.cast(&DataType::Float64).ok()?;
+    let values = values.f64().ok()?;
+    Some(values.into_iter().map(|v| v.unwrap_or(f64::NAN)).collect())
+}
+
+fn segments(
+    frame: &DataFrame,
+    channels: Option<Vec<String>>,
+    window: Window,
+    size: usize,
+) -> Option<Segments> {
+    let frame = frame
+        .clone()
+        .lazy()
+        .sort(["time"], Default::default())
+        .collect()
+        .ok()?;
+    let times = frame
+        .column("time")
+        .ok()?
+        .cast(&DataType::Int64)
+        .ok()?
+        .i64()
+        .ok()?
+        .into_iter()
+        .collect::<Option<Vec<_>>>()?;
+    if size < 2 || times.len() < size {
+        return None;
+    }
+
+    // FFTs assume uniform sampling, so use the median step as the sample period
+    let mut steps = times
+        .windows(2)
+        .map(|w| w[1] - w[0])
+        .filter(|s| *s > 0)
+        .collect::<Vec<_>>();
+    steps.sort_unstable();
+    let period = *steps.get(steps.len() / 2)? as f64 * 1.0e-9;
+    let sample_rate = 1.0 / period;
+
+    let coefficients = window.coefficients(size);
+    let scale = 1.0 / (sample_rate * coefficients.iter().map(|w| w * w).sum::<f64>());
+    let fft = FftPlanner::<f64>::new().plan_fft_forward(size);
+    let bins = size / 2 + 1;
+    let step = (size / 2).max(1);
+    let starts = (0..=times.len() - size).step_by(step).collect::<Vec<_>>();
+
+    let mut result = BTreeMap::new();
+    for name in channels.unwrap_or_else(|| default_channels(&frame)) {
+        let Some(values) = column(&frame, &name) else {
+            continue;
+        };
+        let rows = starts
+            .iter()
+            .map(|start| {
+                let segment = &values[*start..*start + size];
+                let (sum, count) = segment
+                    .iter()
+                    .filter(|v| !v.is_nan())
+                    .fold((0.0, 0), |(sum, count), v| (sum + v, count + 1));
+                let mean = if count > 0 { sum / count as f64 } else { 0.0 };
+                let mut buffer = segment
+                    .iter()
+                    .zip(&coefficients)
+                    .map(|(v, w)| {
+                        let v = if v.is_nan() { 0.0 } else { v - mean };
+                        Complex::new(v * w, 0.0)
+                    })
+                    .collect::<Vec<_>>();
+                fft.process(&mut buffer);
+
+                // One-sided spectrum: fold negative frequencies except DC and Nyquist
+                buffer[..bins]
+                    .iter()
+                    .enumerate()
+                    .map(|(k, c)| {
+                        let folded = if k == 0 || (size % 2 == 0 && k == bins - 1) {
+                            1.0
+                        } else {
+                            2.0
+                        };
+                        c.norm_sqr() * scale * folded
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        result.insert(name, rows);
+    }
+
+    Some(Segments {
+        sample_rate,
+        frequencies: (0..bins)
+            .map(|k| k as f64 * sample_rate / size as f64)
+            .collect(),
+        times: starts
+            .iter()
+            .map(|start| (times[*start] + times[*start + size - 1]) as f64 * 0.5e-9)
+            .collect(),
+        channels: result,
+    })
+}
+
+pub fn analyse(
+    frame: &DataFrame,
+    channels: Option<Vec<String>>,
+    mode: Mode,
+    window: Window,
+    size: usize,
+) -> Option<Analysis> {
+    let segments = segments(frame, channels, window, size)?;
+    Some(match mode {
+        Mode::Spectrogram => Analysis::Spectrogram(Spectrogram {
+            sample_rate: segments.sample_rate,
+            times: segments.times,
+            frequencies: segments.frequencies,
+            channels: segments.channels,
+        }),
+        Mode::Psd => Analysis::Psd(Spectrum {
+            sample_rate: segments.sample_rate,
+            channels: segments
+                .channels
+                .into_iter()
+                .map(|(name, rows)| {
+                    let mut mean = vec![0.0; segments.frequencies.len()];
+                    for row in &rows {
+                        for (m, v) in mean.iter_mut().zip(row) {
+                            *m += v / rows.len() as f64;
+                        }
+                    }
+                    (name, mean)
+                })
+                .collect(),
+            frequencies: segments.frequencies,
+        }),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // `values` sampled at 100 Hz
+    fn frame(values: Vec<f64>) -> DataFrame {
+        let time = Series::new(
+            "time",
+            (0..values.len() as i64)
+                .map(|i| i * 10_000_000)
+                .collect::<Vec<_>>(),
+        )
+        .cast(&DataType::Duration(TimeUnit::Nanoseconds))
+        .unwrap();
+        DataFrame::new(vec![time, Series::new("acc_x", values)]).unwrap()
+    }
+
+    fn psd(analysis: Analysis) -> Spectrum {
+        match analysis {
+            Analysis::Psd(spectrum) => spectrum,
+            Analysis::Spectrogram(_) => panic!("expected a PSD"),
+        }
+    }
+
+    #[test]
+    fn finds_the_frequency_of_a_sine() {
+        let values = (0..1024)
+            .map(|i| (2.0 * PI * 12.5 * i as f64 / 100.0).sin())
+            .collect();
+        let spectrum = psd(analyse(&frame(values), None, Mode::Psd, Window::Hann, 64).unwrap());
+        assert!((spectrum.sample_rate - 100.0).abs() < 1.0e-6);
+        let psd = &spectrum.channels["acc_x"];
+        let peak = (0..psd.len())
+            .max_by(|a, b| psd[*a].total_cmp(&psd[*b]))
+            .unwrap();
+        assert!((spectrum.frequencies[peak] - 12.5).abs() < 1.0e-6);
+    }
+
+    #[test]
+    fn removes_the_mean_of_the_finite_samples() {
+        let values = (0..256)
+            .map(|i| if i % 4 == 0 { f64::NAN } else { 5.0 })
+            .collect();
+        let spectrum =
+            psd(analyse(&frame(values), None, Mode::Psd, Window::Rectangular, 64).unwrap());
+        assert!(spectrum.channels["acc_x"][0] < 1.0e-20);
+    }
+
+    #[test]
+    fn spectrogram_has_a_row_per_segment() {
+        let analysis = analyse(
+            &frame(vec![0.0; 256]),
+            None,
+            Mode::Spectrogram,
+            Window::Hann,
+            64,
+        )
+        .unwrap();
+        let Analysis::Spectrogram(spectrogram) = analysis else {
+            panic!("expected a spectrogram");
+        };
+        // Segments overlap by half
+        assert_eq!(spectrogram.times.len(), 7);
+        assert_eq!(spectrogram.channels["acc_x"].len(), 7);
+        assert_eq!(spectrogram.frequencies.len(), 33);
+    }
+
+    #[test]
+    fn needs_a_full_segment() {
+        assert!(analyse(&frame(vec![0.0; 32]), None, Mode::Psd, Window::Hann, 64).is_none());
+    }
+}
@@ -0,0 +1,460 @@
+use polars::prelude::*;
+use rocket::{
+    futures::lock::Mutex,
+    serde::{Deserialize, Serialize},
+};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::{Duration as StdDuration, Instant};
+
+fn burst_secs() -> f64 {
+    2.0
+}
+
+fn max_message_size() -> usize {
+    1 << 20
+}
+
+fn forget_after_secs() -> u64 {
+    3600
+}
+
+// What happens to data above a device's limits
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum Backpressure {
+    #[default]
+    Drop,
+    // Keep as many evenly spaced samples of a message as the sample rate
+    // limit allows; messages over the message or byte limit are dropped
+    Downsample,
+    Disconnect,
+}
+
+// Limits per device on the WebSocket, UDP, TCP and MQTT. Rates that are not
+// set are unlimited.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ThrottleConfig {
+    #[serde(default)]
+    pub messages_per_sec: Option<f64>,
+    #[serde(default)]
+    pub samples_per_sec: Option<f64>,
+    #[serde(default)]
+    pub bytes_per_sec: Option<f64>,
+    // How many seconds worth of each rate may arrive at once
+    #[serde(default = "burst_secs")]
+    pub burst_secs: f64,
+    // Enforced by the WebSocket itself, larger messages close the connection
+    #[serde(default = "max_message_size")]
+    pub max_message_size: usize,
+    #[serde(default)]
+    pub backpressure: Backpressure,
+    // Devices that sent nothing for this long lose their buckets and counters
+    #[serde(default = "forget_after_secs")]
+    pub forget_after_secs: u64,
+}
+
+impl Default for ThrottleConfig {
+    fn default() -> Self {
+        Self {
+            messages_per_sec: None,
+            samples_per_sec: None,
+            bytes_per_sec: None,
+            burst_secs: burst_secs(),
+            max_message_size: max_message_size(),
+            backpressure: Backpressure::default(),
+            forget_after_secs: forget_after_secs(),
+        }
+    }
+}
+
+impl ThrottleConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        let rates = [
+            ("messages_per_sec", self.messages_per_sec),
+            ("samples_per_sec", self.samples_per_sec),
+            ("bytes_per_sec", self.bytes_per_sec),
+        ];
+        for (name, rate) in rates {
+            if let Some(rate) = rate.filter(|r| !(*r > 0.0 && r.is_finite())) {
+                return Err(format!("throttle.{name} must be positive, not {rate}"));
+            }
+        }
+        if !(self.burst_secs > 0.0 && self.burst_secs.is_finite()) {
+            return Err(format!(
+                "throttle.burst_secs must be positive, not {}",
+                self.burst_secs
+            ));
+        }
+        if self.max_message_size == 0 {
+            return Err(String::from("throttle.max_message_size must be positive"));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct IngestStats {
+    // Accepted, including downsampled messages
+    pub messages: u64,
+    pub samples: u64,
+    pub bytes: u64,
+    pub dropped_messages: u64,
+    pub dropped_samples: u64,
+    pub downsampled_messages: u64,
+    pub oversized_messages: u64,
+    pub disconnects: u64,
+}
+
+pub enum Verdict {
+    Accept,
+    // Keep every n-th sample
+    Downsample(usize),
+    Drop,
+    Disconnect,
+}
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, rate: f64, capacity: f64) -> f64 {
+        let now = Instant::now();
+        self.tokens = (self.tokens + (now - self.last).as_secs_f64() * rate).min(capacity);
+        self.last = now;
+        self.tokens
+    }
+}
+
+struct Device {
+    // Messages, samples and bytes
+    buckets: [Bucket; 3],
+    stats: IngestStats,
+    last: Instant,
+}
+
+impl Device {
+    fn new(config: &ThrottleConfig) -> Self {
+        let full = |rate: Option<f64>| Bucket {
+            tokens: rate.unwrap_or(0.0) * config.burst_secs,
+            last: Instant::now(),
+        };
+        Self {
+            buckets: [
+                full(config.messages_per_sec),
+                full(config.samples_per_sec),
+                full(config.bytes_per_sec),
+            ],
+            stats: IngestStats::default(),
+            last: Instant::now(),
+        }
+    }
+}
+
+// Token buckets limiting what each device may send, shared by all its
+// sessions, with counters of what was accepted and refused
+#[derive(Clone)]
+pub struct Throttle {
+    config: ThrottleConfig,
+    devices: Arc<Mutex<HashMap<String, Device>>>,
+}
+
+impl Throttle {
+    pub fn new(config: ThrottleConfig) -> Self {
+        Self {
+            config,
+            devices: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn refuse(
+        &self,
+        backpressure: Backpressure,
+        stats: &mut IngestStats,
+        samples: usize,
+    ) -> Verdict {
+        match backpressure {
+            Backpressure::Disconnect => {
+                stats.disconnects += 1;
+                Verdict::Disconnect
+            }
+            _ => {
+                stats.dropped_messages += 1;
+                stats.dropped_samples += samples as u64;
+                Verdict::Drop
+            }
+        }
+    }
+
+    // Limits of the WebSocket, which refuses oversized messages before they
+    // are buffered
+    pub fn websocket_config(&self) -> rocket_ws::Config {
+        rocket_ws::Config {
+            max_message_size: Some(self.config.max_message_size),
+            max_frame_size: Some(self.config.max_message_size),
+            ..Default::default()
+        }
+    }
+
+    // Count a message the WebSocket refused as too large
+    pub async fn oversized(&self, device: &str) {
+        let mut devices = self.devices.lock().await;
+        let device = devices
+            .entry(device.to_string())
+            .or_insert_with(|| Device::new(&self.config));
+        device.last = Instant::now();
+        device.stats.oversized_messages += 1;
+        device.stats.disconnects += 1;
+    }
+
+    pub async fn check(&self, device: &str, bytes: usize, samples: usize) -> Verdict {
+        self.limit(device, bytes, samples, self.config.backpressure)
+            .await
+    }
+
+    // For transports without a session to close, which drop what would
+    // otherwise disconnect
+    pub async fn check_sessionless(&self, device: &str, bytes: usize, samples: usize) -> Verdict {
+        let backpressure = match self.config.backpressure {
+            Backpressure::Disconnect => Backpressure::Drop,
+            backpressure => backpressure,
+        };
+        self.limit(device, bytes, samples, backpressure).await
+    }
+
+    async fn limit(
+        &self,
+        device: &str,
+        bytes: usize,
+        samples: usize,
+        backpressure: Backpressure,
+    ) -> Verdict {
+        let config = &self.config;
+        let mut devices = self.devices.lock().await;
+        let device = devices
+            .entry(device.to_string())
+            .or_insert_with(|| Device::new(config));
+        device.last = Instant::now();
+
+        // A full bucket lets through even what exceeds its capacity, which
+        // leaves it in debt until the refill catches up. Otherwise messages
+        // larger than the burst would never get through.
+        let [messages, sample_bucket, byte_bucket] = &mut device.buckets;
+        let within = |bucket: &mut Bucket, rate: Option<f64>, amount: f64| {
+            !rate.is_some_and(|rate| {
+                let capacity = rate * config.burst_secs;
+                let tokens = bucket.refill(rate, capacity);
+                tokens < amount && tokens < capacity
+            })
+        };
+        if !within(messages, config.messages_per_sec, 1.0)
+            || !within(byte_bucket, config.bytes_per_sec, bytes as f64)
+        {
+            return self.refuse(backpressure, &mut device.stats, samples);
+        }
+
+        let mut kept = samples;
+        let mut verdict = Verdict::Accept;
+        if let Some(rate) = config.samples_per_sec {
+            let capacity = rate * config.burst_secs;
+            let tokens = sample_bucket.refill(rate, capacity);
+            let available = tokens.max(0.0).floor() as usize;
+            if available < samples && backpressure == Backpressure::Downsample && available > 0 {
+                let step = samples.div_ceil(available);
+                kept = samples.div_ceil(step);
+                verdict = Verdict::Downsample(step);
+                device.stats.downsampled_messages += 1;
+                device.stats.dropped_samples += (samples - kept) as u64;
+            } else if available < samples && tokens < capacity {
+                return self.refuse(backpressure, &mut device.stats, samples);
+            }
+            sample_bucket.tokens -= kept as f64;
+        }
+        if config.messages_per_sec.is_some() {
+            messages.tokens -= 1.0;
+        }
+        if config.bytes_per_sec.is_some() {
+            byte_bucket.tokens -= bytes as f64;
+        }
+
+        device.stats.messages += 1;
+        device.stats.samples += kept as u64;
+        device.stats.bytes += bytes as u64;
+        verdict
+    }
+
+    // Forget devices that have been idle for longer than `forget_after_secs`
+    pub async fn prune(&self) {
+        let idle = StdDuration::from_secs(self.config.forget_after_secs);
+        let mut devices = self.devices.lock().await;
+        devices.retain(|_, d| d.last.elapsed() < idle);
+    }
+
+    pub async fn stats(&self, device: &str) -> Option<IngestStats> {
+        let devices = self.devices.lock().await;
+        devices.get(device).map(|d| d.stats.clone())
+    }
+
+    pub async fn all_stats(&self) -> BTreeMap<String, IngestStats> {
+        let devices = self.devices.lock().await;
+        devices
+            .iter()
+            .map(|(id, d)| (id.clone(), d.stats.clone()))
+            .collect()
+    }
+}
+
+// Every `step`-th sample of a frame
+pub fn downsample(frame: &DataFrame, step: usize) -> DataFrame {
+    frame.gather_every(step)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn throttle(backpressure: Backpressure) -> Throttle {
+        // Buckets of 4 messages and 100 samples, refilling too slowly to
+        // matter during a test
+        Throttle::new(ThrottleConfig {
+            messages_per_sec: Some(0.004),
+            samples_per_sec: Some(0.1),
+            burst_secs: 1000.0,
+            backpressure,
+            ..Default::default()
+        })
+    }
+
+    #[rocket::async_test]
+    async fn drops_messages_over_the_burst() {
+        let throttle = throttle(Backpressure::Drop);
+        for _ in 0..4 {
+            assert!(matches!(throttle.check("a", 10, 1).await, Verdict::Accept));
+        }
+        assert!(matches!(throttle.check("a", 10, 1).await, Verdict::Drop));
+        // Devices have their own buckets
+        assert!(matches!(throttle.check("b", 10, 1).await, Verdict::Accept));
+
+        let stats = throttle.stats("a").await.unwrap();
+        assert_eq!((stats.messages, stats.samples, stats.bytes), (4, 4, 40));
+        assert_eq!((stats.dropped_messages, stats.dropped_samples), (1, 1));
+    }
+
+    #[rocket::async_test]
+    async fn downsamples_to_the_available_samples() {
+        let throttle = throttle(Backpressure::Downsample);
+        assert!(matches!(
+            throttle.check("a", 10, 250).await,
+            Verdict::Downsample(3)
+        ));
+        let stats = throttle.stats("a").await.unwrap();
+        assert_eq!(stats.samples, 84);
+        assert_eq!(stats.dropped_samples, 166);
+        assert_eq!(stats.downsampled_messages, 1);
+
+        // Once the bucket is empty there is nothing left to keep
+        assert!(matches!(throttle.check("a", 10, 16).await, Verdict::Accept));
+        assert!(matches!(throttle.check("a", 10, 1).await, Verdict::Drop));
+    }
+
+    #[rocket::async_test]
+    async fn disconnects_over_the_limit() {
+        let throttle = throttle(Backpressure::Disconnect);
+        assert!(matches!(throttle.check("a", 10, 60).await, Verdict::Accept));
+        assert!(matches!(
+            throttle.check("a", 10, 41).await,
+            Verdict::Disconnect
+        ));
+        assert_eq!(throttle.stats("a").await.unwrap().disconnects, 1);
+
+        // Without a session to close the message is dropped instead
+        assert!(matches!(
+            throttle.check_sessionless("a", 10, 41).await,
+            Verdict::Drop
+        ));
+        assert_eq!(throttle.stats("a").await.unwrap().disconnects, 1);
+    }
+
+    #[rocket::async_test]
+    async fn lets_messages_over_the_capacity_through_a_full_bucket() {
+        let throttle = Throttle::new(ThrottleConfig {
+            samples_per_sec: Some(0.1),
+            bytes_per_sec: Some(0.1),
+            burst_secs: 1000.0,
+            ..Default::default()
+        });
+        assert!(matches!(throttle.check("a", 150, 1).await, Verdict::Accept));
+        assert!(matches!(throttle.check("a", 1, 1).await, Verdict::Drop));
+        assert!(matches!(
+            throttle.check("b", 10, 150).await,
+            Verdict::Accept
+        ));
+        assert!(matches!(throttle.check("b", 10, 1).await, Verdict::Drop));
+    }
+
+    #[test]
+    fn validates_the_config() {
+        assert!(ThrottleConfig::default().validate().is_ok());
+        let invalid = [
+            ThrottleConfig {
+                messages_per_sec: Some(0.0),
+                ..Default::default()
+            },
+            ThrottleConfig {
+                samples_per_sec: Some(-1.0),
+                ..Default::default()
+            },
+            ThrottleConfig {
+                bytes_per_sec: Some(f64::NAN),
+                ..Default::default()
+            },
+            ThrottleConfig {
+                burst_secs: 0.0,
+                ..Default::default()
+            },
+            ThrottleConfig {
+                burst_secs: f64::INFINITY,
+                ..Default::default()
+            },
+            ThrottleConfig {
+                max_message_size: 0,
+                ..Default::default()
+            },
+        ];
+        for config in invalid {
+            assert!(config.validate().is_err(), "{config:?}");
+        }
+    }
+
+    #[rocket::async_test]
+    async fn unlimited_without_rates() {
+        let throttle = Throttle::new(ThrottleConfig::default());
+        for _ in 0..1000 {
+            assert!(matches!(
+                throttle.check("a", 1 << 16, 1000).await,
+                Verdict::Accept
+            ));
+        }
+    }
+
+    #[rocket::async_test]
+    async fn forgets_idle_devices() {
+        let throttle = Throttle::new(ThrottleConfig::default());
+        throttle.check("a", 10, 1).await;
+        throttle.oversized("b").await;
+        throttle.prune().await;
+        assert_eq!(throttle.all_stats().await.len(), 2);
+
+        let throttle = Throttle::new(ThrottleConfig {
+            forget_after_secs: 0,
+            ..Default::default()
+        });
+        throttle.check("a", 10, 1).await;
+        throttle.prune().await;
+        assert!(throttle.stats("a").await.is_none());
+    }
+}
@@ -0,0 +1,171 @@
+use crate::fetch::Fetch;
+use gloo::net::http;
+use serde::Deserialize;
+use wasm_bindgen::JsCast;
+use web_sys::HtmlInputElement;
+use yew::prelude::*;
+use yew_hooks::prelude::*;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+struct Command {
+    #[serde(rename = "type")]
+    kind: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+struct CommandRecord {
+    command: Command,
+    status: String,
+    message: String,
+    sent: String,
+}
+
+fn send_command(device_id: &str, body: String) {
+    let Ok(request) = http::Request::post(&format!("/sensor/{device_id}/commands"))
+        .header("Content-Type", "application/json")
+        .body(body)
+    else {
+        return;
+    };
+    yew::platform::spawn_local(async move {
+        _ = request.send().await;
+    });
+}
+
+#[derive(Debug, Properties, PartialEq)]
+pub struct CommandPanelProps {
+    pub device_id: String,
+}
+
+#[function_component(CommandPanel)]
+pub fn command_panel(CommandPanelProps { device_id }: &CommandPanelProps) -> Html {
+    let history = use_state(Vec::<CommandRecord>::new);
+    let sample_rate = use_state(String::new);
+    let sensors = use_state(|| (true, true, true));
+
+    {
+        let history = history.clone();
+        let device_id = device_id.clone();
+        use_interval(
+            move || {
+                let history = history.clone();
+                let device_id = device_id.clone();
+                yew::platform::spawn_local(async move {
+                    if let Ok(received) =
+                        Vec::<CommandRecord>::fetch(&format!("/sensor/{device_id}/commands")).await
+                    {
+                        history.set(received);
+                    }
+                });
+            },
+            1000,
+        );
+    }
+
+    let button = |label: &'static str, body: &'static str| {
+        let device_id = device_id.clone();
+        html! {
+            <button onclick={Callback::from(move |_| send_command(&device_id, body.to_string()))}>
+                { label }
+            </button>
+        }
+    };
+
+    let sample_rate_onchange = {
+        let sample_rate = sample_rate.clone();
+        Callback::from(move |e: Event| {
+            if let Some(input) = e
+                .target()
+                .and_then(|t| t.dyn_into::<HtmlInputElement>().ok())
+            {
+                sample_rate.set(input.value());
+            }
+        })
+    };
+
+    let sample_rate_onclick = {
+        let sample_rate = sample_rate.clone();
+        let device_id = device_id.clone();
+        Callback::from(move |_| {
+            if let Ok(hz) = sample_rate.parse::<f32>() {
+                send_command(
+                    &device_id,
+                    format!(r#"{{"type":"set_sample_rate","hz":{hz}}}"#),
+                );
+            }
+        })
+    };
+
+    let sensor_checkbox = |label: &'static str, index: usize| {
+        let sensors = sensors.clone();
+        let (acc, mag, gyro) = *sensors;
+        let checked = [acc, mag, gyro][index];
+        let onchange = Callback::from(move |e: Event| {
+            let Some(input) = e
+                .target()
+                .and_then(|t| t.dyn_into::<HtmlInputElement>().ok())
+            else {
+                return;
+            };
+            let mut flags = [sensors.0, sensors.1, sensors.2];
+            flags[index] = input.checked();
+            sensors.set((flags[0], flags[1], flags[2]));
+        });
+        html! {
+            <label>
+                <input type="checkbox" {checked} {onchange} />
+                { label }
+            </label>
+        }
+    };
+
+    let sensors_onclick = {
+        let sensors = sensors.clone();
+        let device_id = device_id.clone();
+        Callback::from(move |_| {
+            let (acceleration, magnetometer, gyroscope) = *sensors;
+            send_command(
+                &device_id,
+                format!(
+                    r#"{{"type":"set_sensors","acceleration":{acceleration},"magnetometer":{magnetometer},"gyroscope":{gyroscope}}}"#
+                ),
+            );
+        })
+    };
+
+    html! {
+        <details class="command-panel">
+            <summary>{ "Commands" }</summary>
+            <div>
+                <span>{ "Sample rate (Hz):" }</span>
+                <input style="width: 7ch;" onchange={sample_rate_onchange} />
+                <button onclick={sample_rate_onclick}>{ "Set" }</button>
+                { sensor_checkbox("Acc", 0) }
+                { sensor_checkbox("Mag", 1) }
+                { sensor_checkbox("Gyro", 2) }
+                <button onclick={sensors_onclick}>{ "Apply" }</button>
+                { button("Calibrate", r#"{"type":"calibrate"}"#) }
+                { button("Identify", r#"{"type":"identify"}"#) }
+                { button("Reboot", r#"{"type":"reboot"}"#) }
+            </div>
+            <table>
+                <tr>
+                    <th>{ "Sent" }</th>
+                    <th>{ "Command" }</th>
+                    <th>{ "Status" }</th>
+                    <th>{ "Message" }</th>
+                </tr>
+                {
+                    for (*history).iter().map(|record| html! {
+                        <tr>
+                            <td>{ &record.sent }</td>
+                            <td>{ &record.command.kind }</td>
+                            <td>{ &record.status }</td>
+                            <td>{ &record.message }</td>
+                        </tr>
+                    })
+                }
+            </table>
+        </details>
+    }
+}
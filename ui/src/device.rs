@@ -0,0 +1,217 @@
+use crate::fetch::Fetch;
+use gloo::net::http;
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::JsCast;
+use web_sys::HtmlInputElement;
+use yew::prelude::*;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Orientation {
+    pub roll: f64,
+    pub pitch: f64,
+    pub yaw: f64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DeviceInfo {
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub location: Option<String>,
+    pub tags: Vec<String>,
+    pub model: Option<String>,
+    pub orientation: Option<Orientation>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Device {
+    pub id: String,
+    pub online: bool,
+    #[serde(flatten)]
+    pub info: DeviceInfo,
+}
+
+impl Device {
+    pub fn label(&self) -> String {
+        self.info.name.clone().unwrap_or_else(|| self.id.clone())
+    }
+
+    // Case-insensitive match of a lowercase filter against id, name and tags
+    pub fn matches(&self, filter: &str) -> bool {
+        filter.is_empty()
+            || self.id.to_lowercase().contains(filter)
+            || self
+                .info
+                .name
+                .as_ref()
+                .is_some_and(|n| n.to_lowercase().contains(filter))
+            || self
+                .info
+                .tags
+                .iter()
+                .any(|t| t.to_lowercase().contains(filter))
+    }
+}
+
+fn optional(value: String) -> Option<String> {
+    let value = value.trim().to_string();
+    (!value.is_empty()).then_some(value)
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+struct SensorCapability {
+    kind: String,
+    unit: Option<String>,
+    sample_rates: Vec<f64>,
+}
+
+// What the device announced when it connected
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+struct Hello {
+    firmware: Option<String>,
+    protocol: u32,
+    sensors: Vec<SensorCapability>,
+}
+
+#[derive(Debug, Properties, PartialEq)]
+pub struct DeviceEditorProps {
+    pub device_id: String,
+}
+
+#[function_component(DeviceEditor)]
+pub fn device_editor(DeviceEditorProps { device_id }: &DeviceEditorProps) -> Html {
+    let info = use_state(DeviceInfo::default);
+    let hello = use_state(|| None::<Hello>);
+    let status = use_state(String::new);
+
+    {
+        let info = info.clone();
+        let hello = hello.clone();
+        let status = status.clone();
+        use_effect_with(device_id.clone(), move |device_id| {
+            let device_id = device_id.clone();
+            status.set(String::new());
+            yew::platform::spawn_local(async move {
+                hello.set(
+                    Hello::fetch(&format!("/sensor/{device_id}/info"))
+                        .await
+                        .ok(),
+                );
+                let received = match http::Request::get(&format!("/devices/{device_id}"))
+                    .send()
+                    .await
+                {
+                    Ok(response) if response.ok() => response.json().await.unwrap_or_default(),
+                    _ => DeviceInfo::default(),
+                };
+                info.set(received);
+            });
+        });
+    }
+
+    // Callback updating one field of the edited info from a text input
+    let field = |update: fn(&mut DeviceInfo, String)| {
+        let info = info.clone();
+        Callback::from(move |e: Event| {
+            if let Some(input) = e
+                .target()
+                .and_then(|t| t.dyn_into::<HtmlInputElement>().ok())
+            {
+                let mut updated = (*info).clone();
+                update(&mut updated, input.value());
+                info.set(updated);
+            }
+        })
+    };
+
+    let save_onclick = {
+        let info = info.clone();
+        let status = status.clone();
+        let device_id = device_id.clone();
+        Callback::from(move |_| {
+            let status = status.clone();
+            let Ok(request) = http::Request::put(&format!("/devices/{device_id}")).json(&*info)
+            else {
+                return;
+            };
+            yew::platform::spawn_local(async move {
+                let ok = request.send().await.is_ok_and(|r| r.ok());
+                status.set(String::from(if ok { "Saved" } else { "Saving failed" }));
+            });
+        })
+    };
+
+    let orientation = info.orientation.clone().unwrap_or_default();
+    let announced = match &*hello {
+        Some(hello) => html! {
+            <p>
+                { format!(
+                    "Firmware {}, protocol {}, sensors: ",
+                    hello.firmware.as_deref().unwrap_or("unknown"),
+                    hello.protocol
+                ) }
+                {
+                    hello.sensors.iter().map(|s| {
+                        let mut text = s.kind.clone();
+                        if let Some(unit) = &s.unit {
+                            text.push_str(&format!(" [{unit}]"));
+                        }
+                        if !s.sample_rates.is_empty() {
+                            let rates = s.sample_rates.iter().map(|r| r.to_string()).collect::<Vec<_>>();
+                            text.push_str(&format!(" @ {} Hz", rates.join("/")));
+                        }
+                        text
+                    }).collect::<Vec<_>>().join(", ")
+                }
+            </p>
+        },
+        None => html! {},
+    };
+
+    html! {
+        <details class="device-editor">
+            <summary>{ "Device" }</summary>
+            { announced }
+            <table>
+                <tr>
+                    <td>{ "Name" }</td>
+                    <td><input value={info.name.clone().unwrap_or_default()}
+                        onchange={field(|i, v| i.name = optional(v))} /></td>
+                </tr>
+                <tr>
+                    <td>{ "Description" }</td>
+                    <td><input value={info.description.clone().unwrap_or_default()}
+                        onchange={field(|i, v| i.description = optional(v))} /></td>
+                </tr>
+                <tr>
+                    <td>{ "Location" }</td>
+                    <td><input value={info.location.clone().unwrap_or_default()}
+                        onchange={field(|i, v| i.location = optional(v))} /></td>
+                </tr>
+                <tr>
+                    <td>{ "Tags" }</td>
+                    <td><input value={info.tags.join(", ")} placeholder="comma separated"
+                        onchange={field(|i, v| i.tags = v.split(',').filter_map(|t| optional(t.to_string())).collect())} /></td>
+                </tr>
+                <tr>
+                    <td>{ "Sensor model" }</td>
+                    <td><input value={info.model.clone().unwrap_or_default()}
+                        onchange={field(|i, v| i.model = optional(v))} /></td>
+                </tr>
+                <tr>
+                    <td>{ "Orientation (roll, pitch, yaw in °)" }</td>
+                    <td>
+                        <input style="width: 7ch;" value={orientation.roll.to_string()}
+                            onchange={field(|i, v| i.orientation.get_or_insert_with(Default::default).roll = v.parse().unwrap_or_default())} />
+                        <input style="width: 7ch;" value={orientation.pitch.to_string()}
+                            onchange={field(|i, v| i.orientation.get_or_insert_with(Default::default).pitch = v.parse().unwrap_or_default())} />
+                        <input style="width: 7ch;" value={orientation.yaw.to_string()}
+                            onchange={field(|i, v| i.orientation.get_or_insert_with(Default::default).yaw = v.parse().unwrap_or_default())} />
+                    </td>
+                </tr>
+            </table>
+            <button onclick={save_onclick}>{ "Save" }</button>
+            <span>{ (*status).clone() }</span>
+        </details>
+    }
+}
@@ -1,5 +1,7 @@
 use gloo::net::http;
+use polars::prelude::*;
 use serde::de::DeserializeOwned;
+use std::io::Cursor;
 
 #[derive(Debug, Copy, Clone)]
 pub struct FetchError;
@@ -13,14 +15,43 @@ where
     }
 }
 
+// A value requested from the server, asked for as `ACCEPT` and read from the
+// response by `decode`
 pub trait Fetch: Sized {
-    async fn fetch(url: &str) -> Result<Self, FetchError>;
+    const ACCEPT: &'static str = "application/json";
+
+    async fn decode(response: http::Response) -> Result<Self, FetchError>;
+
+    async fn fetch(url: &str) -> Result<Self, FetchError> {
+        let response = http::Request::get(url)
+            .header("Accept", Self::ACCEPT)
+            .send()
+            .await?;
+        if !response.ok() {
+            return Err(FetchError);
+        }
+        Self::decode(response).await
+    }
 }
 
 impl<D: DeserializeOwned> Fetch for D {
-    async fn fetch(url: &str) -> Result<Self, FetchError> {
-        let response = http::Request::get(url).send().await?;
+    async fn decode(response: http::Response) -> Result<Self, FetchError> {
         let parsed = response.json().await?;
         Ok(parsed)
     }
 }
+
+// A frame requested as Arrow IPC stream, which is much smaller and faster to
+// decode than its JSON form. DataFrame itself is deserializable, so it already
+// fetches as JSON through the impl above.
+pub struct Frame(pub DataFrame);
+
+impl Fetch for Frame {
+    const ACCEPT: &'static str = "application/vnd.apache.arrow.stream";
+
+    async fn decode(response: http::Response) -> Result<Self, FetchError> {
+        let bytes = response.binary().await?;
+        let frame = IpcStreamReader::new(Cursor::new(bytes)).finish()?;
+        Ok(Self(frame))
+    }
+}
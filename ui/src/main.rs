@@ -1,17 +1,26 @@
+mod command;
+mod device;
 mod fetch;
+mod replay;
+mod spectrum;
 
 use charming::{
     component::{Axis, Title},
     element::AxisType,
-    series::Line,
+    series::{Line, Scatter},
     Chart, WasmRenderer,
 };
-use fetch::Fetch;
+use command::CommandPanel;
+use device::{Device, DeviceEditor};
+use fetch::{Fetch, Frame};
 use gloo::net::http;
 use polars::prelude::*;
+use replay::ReplayPanel;
+use serde::Deserialize;
+use spectrum::SpectrumView;
 use uuid::Uuid;
 use wasm_bindgen::JsCast;
-use web_sys::HtmlInputElement;
+use web_sys::{HtmlInputElement, HtmlTextAreaElement};
 use yew::prelude::*;
 use yew_hooks::prelude::*;
 
@@ -49,6 +58,17 @@ fn dataframe_table(DataFrameTableProps { frame }: &DataFrameTableProps) -> Html
     }
 }
 
+// Size of a plot in CSS pixels
+const PLOT_WIDTH: u32 = 400;
+const PLOT_HEIGHT: u32 = 300;
+
+// Samples per channel to ask the server for: one per device pixel across the
+// width the plots are rendered with
+fn plot_points() -> u32 {
+    let ratio = gloo::utils::window().device_pixel_ratio().max(1.0);
+    (PLOT_WIDTH as f64 * ratio).round() as u32
+}
+
 #[derive(Debug, Clone, PartialEq)]
 struct PlotData {
     pub name: String,
@@ -99,14 +119,68 @@ impl PlotData {
     }
 }
 
+// A channel as declared by the device, see `/sensor/<id>/schema`
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+struct Channel {
+    name: String,
+    unit: Option<String>,
+}
+
+// Channels to plot: the columns of the frame, with units from the schema
+fn plotted_channels(frame: &DataFrame, schema: &[Channel]) -> Vec<Channel> {
+    frame
+        .get_columns()
+        .iter()
+        .filter(|s| {
+            !matches!(s.name(), "time" | "quality")
+                && !s.name().ends_with("_filtered")
+                && (s.dtype().is_numeric() || *s.dtype() == DataType::Boolean)
+        })
+        .map(|s| Channel {
+            name: s.name().to_string(),
+            unit: schema
+                .iter()
+                .find(|c| c.name == s.name())
+                .and_then(|c| c.unit.clone()),
+        })
+        .collect()
+}
+
+// "acc_x" becomes "Acc X"
+fn channel_title(name: &str) -> String {
+    name.split('_')
+        .filter(|word| !word.is_empty())
+        .map(|word| {
+            let mut chars = word.chars();
+            chars
+                .next()
+                .map(|c| c.to_uppercase().chain(chars).collect::<String>())
+                .unwrap_or_default()
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
 #[derive(Debug, Properties, PartialEq)]
 struct PlotProps {
     #[prop_or_default]
     data: Option<PlotData>,
+    // Filtered version of the same channel, drawn on top of the raw data
+    #[prop_or_default]
+    filtered: Option<PlotData>,
+    // Times of events to mark on the plotted curve
+    #[prop_or_default]
+    markers: Vec<f64>,
 }
 
 #[function_component(Plot)]
-fn plot(PlotProps { data }: &PlotProps) -> Html {
+fn plot(
+    PlotProps {
+        data,
+        filtered,
+        markers,
+    }: &PlotProps,
+) -> Html {
     let zipped_data = data
         .clone()
         .map(|d| {
@@ -127,17 +201,45 @@ fn plot(PlotProps { data }: &PlotProps) -> Html {
         .and_then(|d| d.xs.iter().copied().max_by(|a, b| a.total_cmp(b)))
         .unwrap_or(1.0);
 
+    // Place each marker on the sample closest to it in time
+    let marker_data = data
+        .as_ref()
+        .map(|d| {
+            markers
+                .iter()
+                .filter(|t| (x_min..=x_max).contains(*t))
+                .filter_map(|t| {
+                    d.xs.iter()
+                        .zip(d.ys.iter())
+                        .min_by(|(a, _), (b, _)| (*a - t).abs().total_cmp(&(*b - t).abs()))
+                        .map(|(_, y)| vec![*t, *y])
+                })
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+
+    let filtered_data = filtered.clone().map(|d| {
+        d.xs.iter()
+            .zip(d.ys.iter())
+            .map(|(x, y)| vec![*x, *y])
+            .collect::<Vec<_>>()
+    });
+
     let id = Uuid::new_v4();
 
     let plot_name = data.clone().map(|d| d.name).unwrap_or(String::new());
 
     yew::platform::spawn_local(async move {
-        let chart = Chart::new()
+        let mut chart = Chart::new()
             .title(Title::new().text(plot_name))
             .x_axis(Axis::new().type_(AxisType::Value).min(x_min).max(x_max))
             .y_axis(Axis::new().type_(AxisType::Value))
-            .series(Line::new().data(zipped_data));
-        WasmRenderer::new(400, 300)
+            .series(Line::new().name("Raw").data(zipped_data))
+            .series(Scatter::new().name("Events").data(marker_data));
+        if let Some(filtered_data) = filtered_data {
+            chart = chart.series(Line::new().name("Filtered").data(filtered_data));
+        }
+        WasmRenderer::new(PLOT_WIDTH, PLOT_HEIGHT)
             .render(&id.to_string(), &chart)
             .unwrap();
     });
@@ -147,13 +249,127 @@ fn plot(PlotProps { data }: &PlotProps) -> Html {
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+struct SensorEvent {
+    time: f64,
+}
+
+#[derive(Debug, Properties, PartialEq)]
+struct FilterEditorProps {
+    device_id: String,
+}
+
+#[function_component(FilterEditor)]
+fn filter_editor(FilterEditorProps { device_id }: &FilterEditorProps) -> Html {
+    let chain = use_state(String::new);
+    let status = use_state(String::new);
+
+    {
+        let chain = chain.clone();
+        use_effect_with(device_id.clone(), move |device_id| {
+            let device_id = device_id.clone();
+            yew::platform::spawn_local(async move {
+                let text = match http::Request::get(&format!("/sensor/{device_id}/filters"))
+                    .send()
+                    .await
+                {
+                    Ok(response) if response.ok() => response.text().await.unwrap_or_default(),
+                    _ => String::new(),
+                };
+                chain.set(text);
+            });
+        });
+    }
+
+    let chain_oninput = {
+        let chain = chain.clone();
+        Callback::from(move |e: InputEvent| {
+            if let Some(input) = e
+                .target()
+                .and_then(|t| t.dyn_into::<HtmlTextAreaElement>().ok())
+            {
+                chain.set(input.value());
+            }
+        })
+    };
+
+    let save_onclick = {
+        let chain = chain.clone();
+        let status = status.clone();
+        let device_id = device_id.clone();
+        Callback::from(move |_| {
+            let chain = chain.clone();
+            let status = status.clone();
+            let device_id = device_id.clone();
+            yew::platform::spawn_local(async move {
+                let result = http::Request::put(&format!("/sensor/{device_id}/filters"))
+                    .header("Content-Type", "application/json")
+                    .body((*chain).clone());
+                let ok = match result {
+                    Ok(request) => request.send().await.is_ok_and(|r| r.ok()),
+                    Err(_) => false,
+                };
+                status.set(String::from(if ok {
+                    "Saved"
+                } else {
+                    "Invalid filter chain"
+                }));
+            });
+        })
+    };
+
+    let clear_onclick = {
+        let chain = chain.clone();
+        let status = status.clone();
+        let device_id = device_id.clone();
+        Callback::from(move |_| {
+            let chain = chain.clone();
+            let status = status.clone();
+            let device_id = device_id.clone();
+            yew::platform::spawn_local(async move {
+                _ = http::Request::delete(&format!("/sensor/{device_id}/filters"))
+                    .send()
+                    .await;
+                chain.set(String::new());
+                status.set(String::from("Cleared"));
+            });
+        })
+    };
+
+    html! {
+        <details class="filter-editor">
+            <summary>{ "Filters" }</summary>
+            <textarea
+                rows="6"
+                cols="80"
+                value={(*chain).clone()}
+                oninput={chain_oninput}
+                placeholder={r#"{ "filters": [{ "type": "low_pass", "cutoff": 5.0 }], "stage": "query" }"#}
+            />
+            <div>
+                <button onclick={save_onclick}>{ "Save" }</button>
+                <button onclick={clear_onclick}>{ "Clear" }</button>
+                <span>{ (*status).clone() }</span>
+            </div>
+        </details>
+    }
+}
+
 #[derive(Debug, Properties, PartialEq)]
 struct DataViewProps {
     device_id: UseStateHandle<String>,
+    // Show a stored recording of that name instead of a connected device
+    #[prop_or_default]
+    recording: bool,
 }
 
 #[function_component(DataView)]
-fn data_view(DataViewProps { device_id }: &DataViewProps) -> Html {
+fn data_view(
+    DataViewProps {
+        device_id,
+        recording,
+    }: &DataViewProps,
+) -> Html {
     if (*device_id.clone()).is_empty() {
         return html! {
             <></>
@@ -161,28 +377,79 @@ fn data_view(DataViewProps { device_id }: &DataViewProps) -> Html {
     }
 
     let data = use_state(DataFrame::empty);
+    let events = use_state(Vec::<SensorEvent>::new);
     let data_duration = use_state(|| String::from("1m"));
     let sampling_interval = use_state(|| String::from("500ms"));
+    let show_filtered = use_state(|| false);
+    let schema = use_state(Vec::<Channel>::new);
+
+    {
+        let schema = schema.clone();
+        let recording = *recording;
+        use_effect_with((**device_id).clone(), move |device_id| {
+            let device_id = device_id.clone();
+            yew::platform::spawn_local(async move {
+                let received = if recording {
+                    Vec::new()
+                } else {
+                    Vec::<Channel>::fetch(&format!("/sensor/{device_id}/schema"))
+                        .await
+                        .unwrap_or_default()
+                };
+                schema.set(received);
+            });
+        });
+    }
 
     {
         let data = data.clone();
+        let events = events.clone();
+        let show_filtered = show_filtered.clone();
         let sampling_interval = sampling_interval.clone();
         let data_duration = data_duration.clone();
         let device_id = device_id.clone();
+        let recording = *recording;
         use_interval(
             move || {
                 let data = data.clone();
+                let events = events.clone();
+                let show_filtered = show_filtered.clone();
                 let sampling_interval = sampling_interval.clone();
                 let data_duration = data_duration.clone();
                 let device_id = device_id.clone();
                 yew::platform::spawn_local(async move {
-                    if let Ok(new_data) = DataFrame::fetch(&format!(
-                        "/sensor/{}/data?interval={}&duration={}",
-                        *device_id, *sampling_interval, *data_duration
+                    let url = if recording {
+                        format!(
+                            "/recordings/{}/data?interval={}&duration={}&max_points={}",
+                            *device_id,
+                            *sampling_interval,
+                            *data_duration,
+                            plot_points()
+                        )
+                    } else {
+                        format!(
+                            "/sensor/{}/data?interval={}&duration={}&filtered={}&max_points={}",
+                            *device_id,
+                            *sampling_interval,
+                            *data_duration,
+                            *show_filtered,
+                            plot_points()
+                        )
+                    };
+                    if let Ok(Frame(new_data)) = Frame::fetch(&url).await {
+                        data.set(new_data);
+                    }
+                    if recording {
+                        events.set(Vec::new());
+                        return;
+                    }
+                    if let Ok(new_events) = Vec::<SensorEvent>::fetch(&format!(
+                        "/sensor/{}/events?duration={}",
+                        *device_id, *data_duration
                     ))
                     .await
                     {
-                        data.set(new_data);
+                        events.set(new_events);
                     }
                 });
             },
@@ -226,45 +493,63 @@ fn data_view(DataViewProps { device_id }: &DataViewProps) -> Html {
         })
     };
 
-    let acc_x = PlotData::over_time(&data, "time", "acc_x", "Acc X");
-    let acc_y = PlotData::over_time(&data, "time", "acc_y", "Acc Y");
-    let acc_z = PlotData::over_time(&data, "time", "acc_z", "Acc Z");
-    let mag_x = PlotData::over_time(&data, "time", "mag_x", "Mag X");
-    let mag_y = PlotData::over_time(&data, "time", "mag_y", "Mag Y");
-    let mag_z = PlotData::over_time(&data, "time", "mag_z", "Mag Z");
-    let gyro_x = PlotData::over_time(&data, "time", "gyro_x", "Gyro X");
-    let gyro_y = PlotData::over_time(&data, "time", "gyro_y", "Gyro Y");
-    let gyro_z = PlotData::over_time(&data, "time", "gyro_z", "Gyro Z");
+    let show_filtered_onchange = {
+        let show_filtered = show_filtered.clone();
+        Callback::from(move |e: Event| {
+            if let Some(input) = e
+                .target()
+                .and_then(|t| t.dyn_into::<HtmlInputElement>().ok())
+            {
+                show_filtered.set(input.checked());
+            }
+        })
+    };
+
+    let markers = events.iter().map(|e| e.time).collect::<Vec<_>>();
+    let channels = plotted_channels(&data, &schema);
+    let plot = |channel: &Channel| {
+        let mut title = channel_title(&channel.name);
+        if let Some(unit) = &channel.unit {
+            title.push_str(&format!(" [{unit}]"));
+        }
+        let filtered = PlotData::over_time(
+            &data,
+            "time",
+            &format!("{}_filtered", channel.name),
+            &format!("{title} filtered"),
+        );
+        html! {
+            <td><Plot data={PlotData::over_time(&data, "time", &channel.name, &title)} {filtered} markers={markers.clone()}/></td>
+        }
+    };
 
     html! {
         <>
-            <h2>{ format!("Device: {}", **device_id) }</h2>
+            <h2>{ format!("{}: {}", if *recording { "Recording" } else { "Device" }, **device_id) }</h2>
             <div class="data-view-settings">
                 <span>{ "Duration:" }</span>
                 <input style="width: 7ch;" onchange={data_duration_onchange} placeholder={(*data_duration).to_string()}/>
                 <span>{ "Sampling interval:" }</span>
                 <input style="width: 7ch;" onchange={sampling_interval_onchange} placeholder={(*sampling_interval).to_string()}/>
-                <button onclick={reset_button_onclick}>{ "Reset Data" }</button>
+                if !*recording {
+                    <span>{ "Filtered:" }</span>
+                    <input type="checkbox" onchange={show_filtered_onchange} checked={*show_filtered}/>
+                    <button onclick={reset_button_onclick}>{ "Reset Data" }</button>
+                }
             </div>
             <table>
-                <tr>
-                    <td><Plot data={acc_x}/></td>
-                    <td><Plot data={acc_y}/></td>
-                    <td><Plot data={acc_z}/></td>
-                </tr>
-                <tr>
-                    <td><Plot data={mag_x}/></td>
-                    <td><Plot data={mag_y}/></td>
-                    <td><Plot data={mag_z}/></td>
-
-                </tr>
-                <tr>
-                    <td><Plot data={gyro_x}/></td>
-                    <td><Plot data={gyro_y}/></td>
-                    <td><Plot data={gyro_z}/></td>
-
-                </tr>
+                {
+                    for channels.chunks(3).map(|row| html! {
+                        <tr>{ for row.iter().map(&plot) }</tr>
+                    })
+                }
             </table>
+            if !*recording {
+                <DeviceEditor device_id={(**device_id).clone()} />
+                <FilterEditor device_id={(**device_id).clone()} />
+                <CommandPanel device_id={(**device_id).clone()} />
+                <SpectrumView device_id={(**device_id).clone()} duration={(*data_duration).clone()} />
+            }
             { "Raw data:" }
             <DataFrameTable frame={(*data).clone()} />
         </>
@@ -280,15 +565,16 @@ struct ConnectedDevicesListProps {
 fn connected_devices_list(
     ConnectedDevicesListProps { selected_id }: &ConnectedDevicesListProps,
 ) -> Html {
-    let ids = use_state(Vec::<String>::new);
+    let devices = use_state(Vec::<Device>::new);
+    let filter = use_state(String::new);
     {
-        let ids = ids.clone();
+        let devices = devices.clone();
         use_interval(
             move || {
-                let ids = ids.clone();
+                let devices = devices.clone();
                 yew::platform::spawn_local(async move {
-                    if let Ok(received_ids) = Vec::<String>::fetch("/sensor/connections").await {
-                        ids.set(received_ids);
+                    if let Ok(received) = Vec::<Device>::fetch("/devices").await {
+                        devices.set(received);
                     }
                 });
             },
@@ -296,19 +582,108 @@ fn connected_devices_list(
         );
     }
 
+    let filter_oninput = {
+        let filter = filter.clone();
+        Callback::from(move |e: InputEvent| {
+            if let Some(input) = e
+                .target()
+                .and_then(|t| t.dyn_into::<HtmlInputElement>().ok())
+            {
+                filter.set(input.value().to_lowercase());
+            }
+        })
+    };
+
+    // Devices matching the filter by id, name or tag, grouped by tag
+    let mut groups = std::collections::BTreeMap::<String, Vec<&Device>>::new();
+    for device in devices.iter().filter(|d| d.matches(&filter)) {
+        if device.info.tags.is_empty() {
+            groups.entry(String::new()).or_default().push(device);
+        }
+        for tag in &device.info.tags {
+            groups.entry(tag.clone()).or_default().push(device);
+        }
+    }
+
     html! {
         <div class="device-list">
-            <h2>{ "Connected Devices" }</h2>
-            <table>
+            <h2>{ "Devices" }</h2>
+            <input placeholder="Filter by name or tag" oninput={filter_oninput} />
             {
-                for (*ids).iter().map(|id| {
-                    let selected_id = selected_id.clone();
-                    let id_clone = id.clone();
-                    html! {
-                        <tr><td onclick={Callback::from(move |_| selected_id.set(id_clone.clone()))}> {id} </td></tr>
-                    }
+                for groups.iter().map(|(tag, devices)| html! {
+                    <>
+                        <h3>{ if tag.is_empty() { "Untagged" } else { tag.as_str() } }</h3>
+                        <table>
+                        {
+                            for devices.iter().map(|device| {
+                                let selected_id = selected_id.clone();
+                                let id = device.id.clone();
+                                let class = classes!(
+                                    if device.online { "online" } else { "offline" },
+                                    (*selected_id == device.id).then_some("selected"),
+                                );
+                                html! {
+                                    <tr {class} onclick={Callback::from(move |_| selected_id.set(id.clone()))}>
+                                        <td>{ if device.online { "\u{25cf}" } else { "\u{25cb}" } }</td>
+                                        <td title={device.id.clone()}>{ device.label() }</td>
+                                    </tr>
+                                }
+                            })
+                        }
+                        </table>
+                    </>
                 })
             }
+        </div>
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+struct Alert {
+    rule_name: String,
+    device: String,
+    message: String,
+    time: String,
+}
+
+#[function_component(AlertList)]
+fn alert_list() -> Html {
+    let alerts = use_state(Vec::<Alert>::new);
+    {
+        let alerts = alerts.clone();
+        use_interval(
+            move || {
+                let alerts = alerts.clone();
+                yew::platform::spawn_local(async move {
+                    if let Ok(received) = Vec::<Alert>::fetch("/alerts/history?limit=20").await {
+                        alerts.set(received);
+                    }
+                });
+            },
+            1000,
+        );
+    }
+
+    html! {
+        <div class="alert-list">
+            <h2>{ "Alerts" }</h2>
+            <table>
+                <tr>
+                    <th>{ "Time" }</th>
+                    <th>{ "Device" }</th>
+                    <th>{ "Rule" }</th>
+                    <th>{ "Message" }</th>
+                </tr>
+                {
+                    for (*alerts).iter().map(|alert| html! {
+                        <tr>
+                            <td>{ &alert.time }</td>
+                            <td>{ &alert.device }</td>
+                            <td>{ &alert.rule_name }</td>
+                            <td>{ &alert.message }</td>
+                        </tr>
+                    })
+                }
             </table>
         </div>
     }
@@ -317,12 +692,26 @@ fn connected_devices_list(
 #[function_component(App)]
 fn app() -> Html {
     let selected_id = use_state(String::new);
+    let selected_recording = use_state(String::new);
+    {
+        // Selecting a device leaves the recording view
+        let selected_recording = selected_recording.clone();
+        use_effect_with((*selected_id).clone(), move |_| {
+            selected_recording.set(String::new());
+        });
+    }
 
     html! {
         <>
             <ConnectedDevicesList selected_id={selected_id.clone()} />
             <div class="main">
-                <DataView device_id={selected_id.clone()}/>
+                if selected_recording.is_empty() {
+                    <DataView device_id={selected_id.clone()}/>
+                } else {
+                    <DataView device_id={selected_recording.clone()} recording=true />
+                }
+                <ReplayPanel device_id={(*selected_id).clone()} selected_recording={selected_recording.clone()} />
+                <AlertList />
             </div>
         </>
     }
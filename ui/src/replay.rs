@@ -0,0 +1,252 @@
+use crate::fetch::Fetch;
+use gloo::net::http;
+use serde::Deserialize;
+use wasm_bindgen::JsCast;
+use web_sys::{FormData, HtmlInputElement};
+use yew::prelude::*;
+use yew_hooks::prelude::*;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+struct ReplayStatus {
+    device: String,
+    source: String,
+    speed: f64,
+    paused: bool,
+    finished: bool,
+    position: f64,
+    duration: f64,
+}
+
+fn input_value(e: &Event) -> Option<HtmlInputElement> {
+    e.target()
+        .and_then(|t| t.dyn_into::<HtmlInputElement>().ok())
+}
+
+// Fire a request and ignore the response, the panel picks up changes on its next poll
+fn send(request: http::RequestBuilder) {
+    yew::platform::spawn_local(async move {
+        _ = request.send().await;
+    });
+}
+
+#[derive(Debug, Properties, PartialEq)]
+pub struct ReplayPanelProps {
+    // Currently selected device, offered for saving as a recording
+    pub device_id: String,
+    // Recording shown in the data view
+    pub selected_recording: UseStateHandle<String>,
+}
+
+#[function_component(ReplayPanel)]
+pub fn replay_panel(
+    ReplayPanelProps {
+        device_id,
+        selected_recording,
+    }: &ReplayPanelProps,
+) -> Html {
+    let recordings = use_state(Vec::<String>::new);
+    let replays = use_state(Vec::<ReplayStatus>::new);
+    let recording_name = use_state(String::new);
+    let import_status = use_state(String::new);
+    {
+        let recordings = recordings.clone();
+        let replays = replays.clone();
+        use_interval(
+            move || {
+                let recordings = recordings.clone();
+                let replays = replays.clone();
+                yew::platform::spawn_local(async move {
+                    if let Ok(received) = Vec::<String>::fetch("/recordings").await {
+                        recordings.set(received);
+                    }
+                    if let Ok(received) = Vec::<ReplayStatus>::fetch("/replays").await {
+                        replays.set(received);
+                    }
+                });
+            },
+            1000,
+        );
+    }
+
+    let recording_name_onchange = {
+        let recording_name = recording_name.clone();
+        Callback::from(move |e: Event| {
+            if let Some(input) = input_value(&e) {
+                recording_name.set(input.value());
+            }
+        })
+    };
+
+    let save_onclick = {
+        let recording_name = recording_name.clone();
+        let device_id = device_id.clone();
+        Callback::from(move |_| {
+            if recording_name.is_empty() || device_id.is_empty() {
+                return;
+            }
+            send(http::Request::post(&format!(
+                "/recordings/{}?device={device_id}",
+                *recording_name
+            )));
+        })
+    };
+
+    let upload_onchange = Callback::from(|e: Event| {
+        let Some(file) = input_value(&e)
+            .and_then(|input| input.files())
+            .and_then(|files| files.get(0))
+        else {
+            return;
+        };
+        let device = format!("upload-{}", file.name().replace('.', "-"));
+        let request = http::Request::post(&format!("/replays/upload?device={device}"))
+            .header("Content-Type", "application/octet-stream")
+            .body(file);
+        if let Ok(request) = request {
+            yew::platform::spawn_local(async move {
+                _ = request.send().await;
+            });
+        }
+    });
+
+    // Import a CSV or Parquet file as a recording named after the file
+    let import_onchange = {
+        let import_status = import_status.clone();
+        Callback::from(move |e: Event| {
+            let Some(file) = input_value(&e)
+                .and_then(|input| input.files())
+                .and_then(|files| files.get(0))
+            else {
+                return;
+            };
+            let Ok(form) = FormData::new() else {
+                return;
+            };
+            let name = file
+                .name()
+                .rsplit_once('.')
+                .map_or(file.name(), |(name, _)| name.to_string());
+            _ = form.append_with_str("name", &name);
+            _ = form.append_with_blob_and_filename("file", &file, &file.name());
+
+            let import_status = import_status.clone();
+            let Ok(request) = http::Request::post("/recordings/import").body(form) else {
+                return;
+            };
+            yew::platform::spawn_local(async move {
+                let status = match request.send().await {
+                    Ok(response) if response.ok() => format!("Imported {name}"),
+                    Ok(response) => response.text().await.unwrap_or_default(),
+                    Err(_) => String::from("Import failed"),
+                };
+                import_status.set(status);
+            });
+        })
+    };
+
+    html! {
+        <details class="replay-panel">
+            <summary>{ "Recordings and replay" }</summary>
+            <div>
+                <span>{ "Save selected device as:" }</span>
+                <input style="width: 20ch;" onchange={recording_name_onchange} />
+                <button onclick={save_onclick}>{ "Save recording" }</button>
+                <span>{ "Replay file:" }</span>
+                <input type="file" accept=".arrow,.ipc,.parquet,.csv" onchange={upload_onchange} />
+            </div>
+            <div>
+                <span>{ "Import CSV/Parquet:" }</span>
+                <input type="file" accept=".csv,.parquet" onchange={import_onchange} />
+                <span>{ (*import_status).clone() }</span>
+            </div>
+            <table>
+                {
+                    for (*recordings).iter().map(|name| {
+                        let start = {
+                            let name = name.clone();
+                            Callback::from(move |_| {
+                                let body = format!(r#"{{"recording":"{name}"}}"#);
+                                if let Ok(request) = http::Request::post("/replays")
+                                    .header("Content-Type", "application/json")
+                                    .body(body)
+                                {
+                                    yew::platform::spawn_local(async move {
+                                        _ = request.send().await;
+                                    });
+                                }
+                            })
+                        };
+                        let view = {
+                            let name = name.clone();
+                            let selected_recording = selected_recording.clone();
+                            Callback::from(move |_| selected_recording.set(name.clone()))
+                        };
+                        let remove = {
+                            let name = name.clone();
+                            Callback::from(move |_| send(http::Request::delete(&format!("/recordings/{name}"))))
+                        };
+                        html! {
+                            <tr>
+                                <td>{ name }</td>
+                                <td><button onclick={view}>{ "View" }</button></td>
+                                <td><button onclick={start}>{ "Replay" }</button></td>
+                                <td><button onclick={remove}>{ "Delete" }</button></td>
+                            </tr>
+                        }
+                    })
+                }
+            </table>
+            <table>
+                {
+                    for (*replays).iter().map(|replay| {
+                        let device = replay.device.clone();
+                        let toggle = {
+                            let url = format!("/replays/{device}/control?paused={}", !replay.paused);
+                            Callback::from(move |_| send(http::Request::post(&url)))
+                        };
+                        let speed = {
+                            let device = device.clone();
+                            Callback::from(move |e: Event| {
+                                if let Some(speed) = input_value(&e).and_then(|i| i.value().parse::<f64>().ok()) {
+                                    send(http::Request::post(&format!("/replays/{device}/control?speed={speed}")));
+                                }
+                            })
+                        };
+                        let seek = {
+                            let device = device.clone();
+                            Callback::from(move |e: Event| {
+                                if let Some(seek) = input_value(&e).and_then(|i| i.value().parse::<f64>().ok()) {
+                                    send(http::Request::post(&format!("/replays/{device}/control?seek={seek}")));
+                                }
+                            })
+                        };
+                        let stop = {
+                            let device = device.clone();
+                            Callback::from(move |_| send(http::Request::delete(&format!("/replays/{device}"))))
+                        };
+                        html! {
+                            <tr>
+                                <td>{ &replay.device }</td>
+                                <td>{ &replay.source }</td>
+                                <td>
+                                    <button onclick={toggle} disabled={replay.finished}>
+                                        { if replay.paused { "Resume" } else { "Pause" } }
+                                    </button>
+                                </td>
+                                <td>
+                                    <input style="width: 5ch;" onchange={speed} placeholder={format!("{}x", replay.speed)} />
+                                </td>
+                                <td>
+                                    <input type="range" min="0" max={replay.duration.to_string()} step="0.1"
+                                        value={replay.position.to_string()} onchange={seek} />
+                                </td>
+                                <td>{ format!("{:.1} / {:.1} s", replay.position, replay.duration) }</td>
+                                <td><button onclick={stop}>{ "Stop" }</button></td>
+                            </tr>
+                        }
+                    })
+                }
+            </table>
+        </details>
+    }
+}
@@ -0,0 +1,233 @@
+use crate::fetch::Fetch;
+
+use charming::{
+    component::{Axis, Legend, Title, VisualMap},
+    element::AxisType,
+    series::{Heatmap, Line},
+    Chart, WasmRenderer,
+};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use uuid::Uuid;
+use wasm_bindgen::JsCast;
+use web_sys::HtmlInputElement;
+use yew::prelude::*;
+use yew_hooks::prelude::*;
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+struct Spectrum {
+    frequencies: Vec<f64>,
+    channels: BTreeMap<String, Vec<f64>>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+struct Spectrogram {
+    times: Vec<f64>,
+    frequencies: Vec<f64>,
+    channels: BTreeMap<String, Vec<Vec<f64>>>,
+}
+
+// Power in dB, clamped so that empty bins don't break the color scale
+fn decibel(power: f64) -> f64 {
+    10.0 * power.max(1.0e-12).log10()
+}
+
+#[derive(Debug, Properties, PartialEq)]
+struct SpectrumPlotProps {
+    spectrum: Spectrum,
+}
+
+#[function_component(SpectrumPlot)]
+fn spectrum_plot(SpectrumPlotProps { spectrum }: &SpectrumPlotProps) -> Html {
+    let id = Uuid::new_v4();
+
+    let series = spectrum
+        .channels
+        .iter()
+        .map(|(name, psd)| {
+            let data = spectrum
+                .frequencies
+                .iter()
+                .zip(psd)
+                .map(|(f, p)| vec![*f, decibel(*p)])
+                .collect::<Vec<_>>();
+            Line::new()
+                .name(name.as_str())
+                .show_symbol(false)
+                .data(data)
+        })
+        .collect::<Vec<_>>();
+
+    yew::platform::spawn_local(async move {
+        let mut chart = Chart::new()
+            .title(Title::new().text("Power spectral density [dB]"))
+            .legend(Legend::new())
+            .x_axis(Axis::new().type_(AxisType::Value).name("Hz"))
+            .y_axis(Axis::new().type_(AxisType::Value));
+        for line in series {
+            chart = chart.series(line);
+        }
+        WasmRenderer::new(1200, 400)
+            .render(&id.to_string(), &chart)
+            .unwrap();
+    });
+
+    html! {
+        <div id={id.to_string()}></div>
+    }
+}
+
+#[derive(Debug, Properties, PartialEq)]
+struct WaterfallPlotProps {
+    channel: String,
+    spectrogram: Spectrogram,
+}
+
+#[function_component(WaterfallPlot)]
+fn waterfall_plot(
+    WaterfallPlotProps {
+        channel,
+        spectrogram,
+    }: &WaterfallPlotProps,
+) -> Html {
+    let id = Uuid::new_v4();
+
+    let rows = spectrogram
+        .channels
+        .get(channel)
+        .cloned()
+        .unwrap_or_default();
+    let data = rows
+        .iter()
+        .enumerate()
+        .flat_map(|(t, row)| {
+            row.iter()
+                .enumerate()
+                .map(move |(f, p)| vec![t as f64, f as f64, decibel(*p)])
+        })
+        .collect::<Vec<_>>();
+    let min = data
+        .iter()
+        .map(|d| d[2])
+        .min_by(|a, b| a.total_cmp(b))
+        .unwrap_or(0.0);
+    let max = data
+        .iter()
+        .map(|d| d[2])
+        .max_by(|a, b| a.total_cmp(b))
+        .unwrap_or(1.0);
+
+    let times = spectrogram
+        .times
+        .iter()
+        .map(|t| format!("{t:.1}"))
+        .collect::<Vec<_>>();
+    let frequencies = spectrogram
+        .frequencies
+        .iter()
+        .map(|f| format!("{f:.1}"))
+        .collect::<Vec<_>>();
+    let title = format!("Spectrogram {channel} [dB]");
+
+    yew::platform::spawn_local(async move {
+        let chart = Chart::new()
+            .title(Title::new().text(title))
+            .x_axis(Axis::new().type_(AxisType::Category).name("s").data(times))
+            .y_axis(
+                Axis::new()
+                    .type_(AxisType::Category)
+                    .name("Hz")
+                    .data(frequencies),
+            )
+            .visual_map(VisualMap::new().min(min).max(max).calculable(true))
+            .series(Heatmap::new().data(data));
+        WasmRenderer::new(1200, 400)
+            .render(&id.to_string(), &chart)
+            .unwrap();
+    });
+
+    html! {
+        <div id={id.to_string()}></div>
+    }
+}
+
+#[derive(Debug, Properties, PartialEq)]
+pub struct SpectrumViewProps {
+    pub device_id: String,
+    pub duration: String,
+}
+
+#[function_component(SpectrumView)]
+pub fn spectrum_view(
+    SpectrumViewProps {
+        device_id,
+        duration,
+    }: &SpectrumViewProps,
+) -> Html {
+    let spectrum = use_state(Spectrum::default);
+    let spectrogram = use_state(Spectrogram::default);
+    let window = use_state(|| String::from("hann"));
+    let size = use_state(|| String::from("256"));
+    let channel = use_state(|| String::from("acc_z"));
+
+    {
+        let spectrum = spectrum.clone();
+        let spectrogram = spectrogram.clone();
+        let window = window.clone();
+        let size = size.clone();
+        let channel = channel.clone();
+        let device_id = device_id.clone();
+        let duration = duration.clone();
+        use_interval(
+            move || {
+                let spectrum = spectrum.clone();
+                let spectrogram = spectrogram.clone();
+                let url = format!(
+                    "/sensor/{}/spectrum?duration={}&window={}&size={}",
+                    device_id, duration, *window, *size
+                );
+                let channel = channel.clone();
+                yew::platform::spawn_local(async move {
+                    if let Ok(new_spectrum) = Spectrum::fetch(&format!("{url}&mode=psd")).await {
+                        spectrum.set(new_spectrum);
+                    }
+                    if let Ok(new_spectrogram) =
+                        Spectrogram::fetch(&format!("{url}&mode=spectrogram&channels={}", *channel))
+                            .await
+                    {
+                        spectrogram.set(new_spectrogram);
+                    }
+                });
+            },
+            2000,
+        );
+    }
+
+    let onchange = |state: &UseStateHandle<String>| {
+        let state = state.clone();
+        Callback::from(move |e: Event| {
+            if let Some(input) = e
+                .target()
+                .and_then(|t| t.dyn_into::<HtmlInputElement>().ok())
+            {
+                state.set(input.value());
+            }
+        })
+    };
+
+    html! {
+        <>
+            <h3>{ "Spectrum" }</h3>
+            <div class="data-view-settings">
+                <span>{ "Window:" }</span>
+                <input style="width: 12ch;" onchange={onchange(&window)} placeholder={(*window).clone()}/>
+                <span>{ "Size:" }</span>
+                <input style="width: 7ch;" onchange={onchange(&size)} placeholder={(*size).clone()}/>
+                <span>{ "Spectrogram channel:" }</span>
+                <input style="width: 10ch;" onchange={onchange(&channel)} placeholder={(*channel).clone()}/>
+            </div>
+            <SpectrumPlot spectrum={(*spectrum).clone()} />
+            <WaterfallPlot channel={(*channel).clone()} spectrogram={(*spectrogram).clone()} />
+        </>
+    }
+}